@@ -0,0 +1,506 @@
+use std::{ffi::OsString, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+// Name under which mount(8) runs us for fstab entries of type "nextcloud"
+pub const MOUNT_HELPER_NAME: &str = "mount.nextcloud";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "nextcloud-fuse",
+    version,
+    about = "Mount Nextcloud files with FUSE"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Mount a Nextcloud account")]
+    Mount(Box<MountArgs>),
+    #[command(about = "Store the password of an account")]
+    Login(LoginArgs),
+    #[command(about = "Forget the stored password of an account")]
+    Logout(LogoutArgs),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Store {
+    // Secret Service of the desktop session
+    Keyring,
+    // The 0600 credentials file
+    File,
+}
+
+// What a mount does while the server cannot be reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflineMode {
+    // Fail like any other error
+    Off,
+    // Serve cached metadata and contents
+    ReadOnly,
+    // Also queue modifications until the server is back
+    ReadWrite,
+}
+
+// Which version stays when a file changed on the server while we were writing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    // Upload ours as a conflicted copy next to theirs
+    KeepBoth,
+    // Drop ours
+    ServerWins,
+    // Overwrite theirs
+    LocalWins,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct LoginArgs {
+    #[arg(help = "Server URL, e.g. https://alice@cloud.example.com")]
+    pub url: String,
+    #[arg(short, long, help = "User name, defaults to the one in the URL")]
+    pub user: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "keyring",
+        help = "Where to keep the password"
+    )]
+    pub store: Store,
+    #[arg(long, help = "Credentials file for --store file")]
+    pub credentials_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct LogoutArgs {
+    #[arg(help = "Server URL, e.g. https://alice@cloud.example.com")]
+    pub url: String,
+    #[arg(short, long, help = "User name, defaults to the one in the URL")]
+    pub user: Option<String>,
+    #[arg(long, help = "Credentials file to remove the password from")]
+    pub credentials_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct MountArgs {
+    #[arg(
+        required_unless_present = "all",
+        help = "Server URL, e.g. https://alice@cloud.example.com, or a profile name"
+    )]
+    pub target: Option<String>,
+    #[arg(help = "Directory to mount on, defaults to the profile's mountpoint")]
+    pub mountpoint: Option<PathBuf>,
+    #[arg(
+        long,
+        conflicts_with = "target",
+        help = "Mount every configured profile"
+    )]
+    pub all: bool,
+    #[arg(long, help = "Configuration file with the profiles")]
+    pub config: Option<PathBuf>,
+    #[arg(short, long, help = "User name, defaults to the one in the URL")]
+    pub user: Option<String>,
+    #[arg(long, help = "Folder of the account to mount")]
+    pub remote_path: Option<String>,
+    #[arg(long, help = "Path of the WebDAV API, remote.php/dav by default")]
+    pub dav_path: Option<String>,
+    // Flags are None unless given, so =false can override a profile
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        help = "Refuse all modifications"
+    )]
+    pub read_only: Option<bool>,
+    #[arg(short, long, help = "Stay in the foreground")]
+    pub foreground: bool,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        help = "Let other users access the mount"
+    )]
+    pub allow_other: Option<bool>,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        help = "Show the trash bin in /.trash"
+    )]
+    pub trash: Option<bool>,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        help = "Show older versions of files in /.versions/<path>/, moving one over its file restores it"
+    )]
+    pub versions: Option<bool>,
+    #[arg(
+        long,
+        help = "File of patterns to never show or upload, in the format of sync-exclude.lst"
+    )]
+    pub exclude_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        help = "Never show or upload files matching this, e.g. '*.swp', can be repeated"
+    )]
+    pub exclude: Vec<String>,
+    #[arg(
+        long,
+        value_name = "REMOTE_PATH",
+        help = "Only show this folder of the account, can be repeated"
+    )]
+    pub allow: Vec<String>,
+    #[arg(
+        long,
+        value_name = "REMOTE_PATH",
+        help = "Never show this folder of the account, can be repeated"
+    )]
+    pub deny: Vec<String>,
+    #[arg(long, help = "Owner of all files, defaults to the current user")]
+    pub uid: Option<u32>,
+    #[arg(long, help = "Group of all files, defaults to the current group")]
+    pub gid: Option<u32>,
+    #[arg(long, value_parser = parse_umask, help = "Permission bits to clear, in octal")]
+    pub umask: Option<u32>,
+    #[arg(long, help = "Directory for cached file contents")]
+    pub cache_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How long file attributes are cached"
+    )]
+    pub attr_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How long missing files are remembered"
+    )]
+    pub negative_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How often to look for changes on the server, 0 to never"
+    )]
+    pub poll_interval: Option<u64>,
+    #[arg(
+        long,
+        value_name = "KIB",
+        help = "Size of the blocks file contents are fetched in"
+    )]
+    pub block_size: Option<u64>,
+    #[arg(long, value_name = "MIB", help = "Limit for cached file contents")]
+    pub cache_size: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        help = "What works while the server is unreachable, read-only by default"
+    )]
+    pub offline: Option<OfflineMode>,
+    #[arg(
+        long,
+        value_enum,
+        help = "Which version of a file changed on both sides wins, keep-both by default"
+    )]
+    pub conflicts: Option<ConflictPolicy>,
+    #[arg(long, help = "Shell command printing the password")]
+    pub password_command: Option<String>,
+    #[arg(long, help = "Credentials file to look the password up in")]
+    pub credentials_file: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value = "warn",
+        help = "One of off, error, warn, info, debug, trace. In the background messages go to \
+                nextcloud-fuse.log in the data dir"
+    )]
+    pub log_level: log::LevelFilter,
+    #[arg(
+        short = 'o',
+        value_name = "OPTIONS",
+        help = "Comma separated mount options as in fstab, e.g. ro,uid=1000,user=alice"
+    )]
+    pub options: Vec<String>,
+}
+
+fn parse_umask(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|umask| *umask <= 0o777)
+        .ok_or_else(|| format!("invalid umask {}", value))
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: Option<&str>) -> Result<T, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("option {} needs a numeric value", key))
+}
+
+// Flags are on without a value, otherwise the value says
+fn parse_flag(key: &str, value: Option<&str>) -> Result<bool, String> {
+    match value {
+        None | Some("true" | "yes" | "on" | "1") => Ok(true),
+        Some("false" | "no" | "off" | "0") => Ok(false),
+        Some(value) => Err(format!("invalid value {} for option {}", value, key)),
+    }
+}
+
+fn required(key: &str, value: Option<&str>) -> Result<String, String> {
+    value
+        .map(|value| value.to_string())
+        .ok_or_else(|| format!("option {} needs a value", key))
+}
+
+// Generic options mount(8) passes along that do not concern us
+fn is_ignored_option(key: &str) -> bool {
+    matches!(
+        key,
+        "defaults"
+            | "auto"
+            | "noauto"
+            | "user"
+            | "users"
+            | "nouser"
+            | "owner"
+            | "nofail"
+            | "_netdev"
+            | "exec"
+            | "noexec"
+            | "suid"
+            | "nosuid"
+            | "dev"
+            | "nodev"
+            | "async"
+            | "sync"
+            | "atime"
+            | "noatime"
+            | "relatime"
+    ) || key.starts_with("x-")
+        || key.starts_with("comment=")
+}
+
+impl MountArgs {
+    // Fold the -o options into the other fields, later options win
+    pub fn apply_options(&mut self) -> Result<(), String> {
+        let options = std::mem::take(&mut self.options);
+
+        for option in options.iter().flat_map(|options| options.split(',')) {
+            let option = option.trim();
+            if option.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+
+            match key {
+                "ro" => self.read_only = Some(true),
+                "rw" => self.read_only = Some(false),
+                "foreground" => self.foreground = parse_flag(key, value)?,
+                "allow_other" => self.allow_other = Some(parse_flag(key, value)?),
+                "trash" => self.trash = Some(parse_flag(key, value)?),
+                "versions" => self.versions = Some(parse_flag(key, value)?),
+                "exclude_file" => self.exclude_file = Some(PathBuf::from(required(key, value)?)),
+                "exclude" => self.exclude.push(required(key, value)?),
+                "allow" => self.allow.push(required(key, value)?),
+                "deny" => self.deny.push(required(key, value)?),
+                // Without a value "user" is the fstab flag allowing users to mount
+                "user" if value.is_some() => self.user = Some(required(key, value)?),
+                "remote_path" => self.remote_path = Some(required(key, value)?),
+                "dav_path" => self.dav_path = Some(required(key, value)?),
+                "config" => self.config = Some(PathBuf::from(required(key, value)?)),
+                "uid" => self.uid = Some(parse_number(key, value)?),
+                "gid" => self.gid = Some(parse_number(key, value)?),
+                "umask" => self.umask = Some(parse_umask(&required(key, value)?)?),
+                "cache_dir" => self.cache_dir = Some(PathBuf::from(required(key, value)?)),
+                "attr_timeout" => self.attr_timeout = Some(parse_number(key, value)?),
+                "negative_timeout" => self.negative_timeout = Some(parse_number(key, value)?),
+                "poll_interval" => self.poll_interval = Some(parse_number(key, value)?),
+                "block_size" => self.block_size = Some(parse_number(key, value)?),
+                "cache_size" => self.cache_size = Some(parse_number(key, value)?),
+                "offline" => {
+                    self.offline = Some(
+                        OfflineMode::from_str(&required(key, value)?, true)
+                            .map_err(|_| format!("invalid offline mode {}", value.unwrap_or("")))?,
+                    )
+                }
+                "conflicts" => {
+                    self.conflicts = Some(
+                        ConflictPolicy::from_str(&required(key, value)?, true).map_err(|_| {
+                            format!("invalid conflict policy {}", value.unwrap_or(""))
+                        })?,
+                    )
+                }
+                "password_command" => self.password_command = Some(required(key, value)?),
+                "credentials_file" => {
+                    self.credentials_file = Some(PathBuf::from(required(key, value)?))
+                }
+                "log_level" => {
+                    self.log_level = required(key, value)?
+                        .parse()
+                        .map_err(|_| format!("invalid log level {}", value.unwrap_or("")))?
+                }
+                _ if is_ignored_option(option) || is_ignored_option(key) => (),
+                _ => return Err(format!("unknown mount option {}", option)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Server origin and the user name given in the URL, if any
+pub fn split_url(url: &str) -> Result<(String, Option<String>), String> {
+    let mut url = url::Url::parse(url).map_err(|error| format!("invalid URL: {}", error))?;
+    let user = match url.username() {
+        "" => None,
+        user => Some(
+            percent_encoding::percent_decode_str(user)
+                .decode_utf8_lossy()
+                .to_string(),
+        ),
+    };
+
+    // Only the user name belongs in the URL, never send it along with requests
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.set_query(None);
+    url.set_fragment(None);
+
+    Ok((url.as_str().trim_end_matches('/').to_string(), user))
+}
+
+// mount(8) runs helpers as
+// `mount.nextcloud <url> <mountpoint> [-sfnv] [-o options] [-t type]`, rewrite that into
+// the mount subcommand
+fn helper_args(args: Vec<OsString>) -> Vec<OsString> {
+    let mut rewritten = vec![OsString::from("nextcloud-fuse"), OsString::from("mount")];
+
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            // -s (sloppy), -n (no mtab), -v (verbose) and -f (fake) have no equivalent here
+            Some("-s" | "-n" | "-v" | "-f" | "-sn" | "-ns") => (),
+            // The type is what got us run in the first place
+            Some("-t") => {
+                args.next();
+            }
+            Some(arg) if arg.starts_with("-t") => (),
+            _ => rewritten.push(arg),
+        }
+    }
+    rewritten
+}
+
+pub fn parse(args: Vec<OsString>) -> Cli {
+    let program = args
+        .first()
+        .map(PathBuf::from)
+        .and_then(|program| program.file_name().map(|name| name.to_owned()));
+
+    if program.as_deref() == Some(MOUNT_HELPER_NAME.as_ref()) {
+        Cli::parse_from(helper_args(args))
+    } else {
+        Cli::parse_from(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount_args(args: &[&str]) -> MountArgs {
+        let args = args.iter().map(OsString::from).collect();
+        match parse(args).command {
+            Command::Mount(mut mount) => {
+                mount.apply_options().unwrap();
+                *mount
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_cli() {
+        let args = mount_args(&[
+            "nextcloud-fuse",
+            "mount",
+            "https://cloud.example.com",
+            "/mnt/cloud",
+            "--user",
+            "alice",
+            "--umask",
+            "027",
+            "--read-only",
+        ]);
+        assert_eq!(args.user.as_deref(), Some("alice"));
+        assert_eq!(args.umask, Some(0o027));
+        assert_eq!(args.read_only, Some(true));
+        assert!(!args.foreground);
+        assert_eq!(args.dav_path, None);
+
+        let args = mount_args(&[
+            "/sbin/mount.nextcloud",
+            "https://alice@cloud.example.com/nextcloud/",
+            "/mnt/cloud",
+            "-n",
+            "-o",
+            "rw,noauto,user,_netdev,x-systemd.automount,uid=1000,gid=100,remote_path=Photos,attr_timeout=300,poll_interval=0,trash,versions,deny=Photos/Private,exclude=*.swp,offline=read-write,conflicts=server-wins",
+            "-t",
+            "nextcloud",
+        ]);
+        assert_eq!(args.mountpoint, Some(PathBuf::from("/mnt/cloud")));
+        assert_eq!(args.uid, Some(1000));
+        assert_eq!(args.gid, Some(100));
+        assert_eq!(args.user, None);
+        assert_eq!(args.remote_path.as_deref(), Some("Photos"));
+        assert_eq!(args.attr_timeout, Some(300));
+        assert_eq!(args.poll_interval, Some(0));
+        assert_eq!(args.read_only, Some(false));
+        assert_eq!(args.trash, Some(true));
+        assert_eq!(args.versions, Some(true));
+        assert_eq!(args.allow_other, None);
+        assert_eq!(args.deny, ["Photos/Private"]);
+        assert_eq!(args.exclude, ["*.swp"]);
+        assert_eq!(args.offline, Some(OfflineMode::ReadWrite));
+        assert_eq!(args.conflicts, Some(ConflictPolicy::ServerWins));
+        assert_eq!(
+            split_url(args.target.as_deref().unwrap()).unwrap(),
+            (
+                "https://cloud.example.com/nextcloud".to_string(),
+                Some("alice".to_string())
+            )
+        );
+
+        let mut args = mount_args(&["nextcloud-fuse", "mount", "https://example.com", "/mnt"]);
+        args.options = vec!["ro,log_level=debug,user=bob".to_string(), "rw".to_string()];
+        args.apply_options().unwrap();
+        assert_eq!(args.read_only, Some(false));
+        assert_eq!(args.log_level, log::LevelFilter::Debug);
+        assert_eq!(args.user.as_deref(), Some("bob"));
+
+        args.options = vec!["trash=false,versions=no,allow_other=1".to_string()];
+        args.apply_options().unwrap();
+        assert_eq!(args.trash, Some(false));
+        assert_eq!(args.versions, Some(false));
+        assert_eq!(args.allow_other, Some(true));
+        args.options = vec!["trash=maybe".to_string()];
+        assert!(args.apply_options().is_err());
+
+        args.options = vec!["bogus".to_string()];
+        assert!(args.apply_options().is_err());
+        args.options = vec!["umask=999".to_string()];
+        assert!(args.apply_options().is_err());
+    }
+}
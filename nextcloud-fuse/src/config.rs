@@ -0,0 +1,329 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::cli::{ConflictPolicy, MountArgs, OfflineMode};
+
+pub const DEFAULT_DAV_PATH: &str = "remote.php/dav";
+
+// One account and where to mount it, e.g.
+//
+//   [profiles.team]
+//   url = "https://alice@cloud.example.com"
+//   mountpoint = "~/Team"
+//   remote_path = "Shared/Team"
+//   password_command = "pass show cloud.example.com"
+//
+// Everything but url can also be given on the command line, which wins over the file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: String,
+    pub user: Option<String>,
+    pub dav_path: Option<String>,
+    pub mountpoint: Option<PathBuf>,
+    // Folder of the account shown as the root of the mount
+    pub remote_path: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub allow_other: bool,
+    // Show the trash bin in /.trash
+    #[serde(default)]
+    pub trash: bool,
+    // Show older versions of files in /.versions
+    #[serde(default)]
+    pub versions: bool,
+    // Patterns in the syntax of the desktop client's sync-exclude.lst, from a file and
+    // given here, and remote paths to show exclusively or never
+    pub exclude_file: Option<PathBuf>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub umask: Option<u32>,
+    pub cache_dir: Option<PathBuf>,
+    // Seconds metadata is cached for, and missing paths are remembered for
+    pub attr_timeout: Option<u64>,
+    pub negative_timeout: Option<u64>,
+    // Seconds between checks for changes made elsewhere, 0 to not check
+    pub poll_interval: Option<u64>,
+    // Content cache blocks in KiB and the limit of the content cache in MiB
+    pub block_size: Option<u64>,
+    pub cache_size: Option<u64>,
+    pub offline: Option<OfflineMode>,
+    pub conflicts: Option<ConflictPolicy>,
+    // Credential sources besides the environment and the keyring
+    pub password_command: Option<String>,
+    pub credentials_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+// $XDG_CONFIG_HOME/nextcloud-fuse, or ~/.config/nextcloud-fuse
+pub fn config_dir() -> PathBuf {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    config.join("nextcloud-fuse")
+}
+
+pub fn cache_dir() -> PathBuf {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    cache.join("nextcloud-fuse")
+}
+
+pub fn data_dir() -> PathBuf {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    data.join("nextcloud-fuse")
+}
+
+pub fn default_config_file() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Profile {
+    pub fn dav_path(&self) -> &str {
+        self.dav_path.as_deref().unwrap_or(DEFAULT_DAV_PATH)
+    }
+
+    pub fn remote_path(&self) -> &str {
+        self.remote_path.as_deref().unwrap_or("")
+    }
+
+    // Command line options override the profile
+    fn merge(mut self, args: &MountArgs) -> Profile {
+        fn or<T: Clone>(arg: &Option<T>, value: Option<T>) -> Option<T> {
+            arg.clone().or(value)
+        }
+
+        self.user = or(&args.user, self.user);
+        self.dav_path = or(&args.dav_path, self.dav_path);
+        self.mountpoint = or(&args.mountpoint, self.mountpoint);
+        self.remote_path = or(&args.remote_path, self.remote_path);
+        self.read_only = args.read_only.unwrap_or(self.read_only);
+        self.allow_other = args.allow_other.unwrap_or(self.allow_other);
+        self.trash = args.trash.unwrap_or(self.trash);
+        self.versions = args.versions.unwrap_or(self.versions);
+        self.exclude_file = or(&args.exclude_file, self.exclude_file);
+        self.exclude.extend(args.exclude.iter().cloned());
+        self.allow.extend(args.allow.iter().cloned());
+        self.deny.extend(args.deny.iter().cloned());
+        self.uid = or(&args.uid, self.uid);
+        self.gid = or(&args.gid, self.gid);
+        self.umask = or(&args.umask, self.umask);
+        self.cache_dir = or(&args.cache_dir, self.cache_dir);
+        self.attr_timeout = or(&args.attr_timeout, self.attr_timeout);
+        self.negative_timeout = or(&args.negative_timeout, self.negative_timeout);
+        self.poll_interval = or(&args.poll_interval, self.poll_interval);
+        self.block_size = or(&args.block_size, self.block_size);
+        self.cache_size = or(&args.cache_size, self.cache_size);
+        self.offline = or(&args.offline, self.offline);
+        self.conflicts = or(&args.conflicts, self.conflicts);
+        self.password_command = or(&args.password_command, self.password_command);
+        self.credentials_file = or(&args.credentials_file, self.credentials_file);
+        self
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config: Config = toml::from_str(text).map_err(|error| error.to_string())?;
+
+        for profile in config.profiles.values_mut() {
+            profile.mountpoint = profile.mountpoint.as_deref().map(expand_home);
+            profile.cache_dir = profile.cache_dir.as_deref().map(expand_home);
+            profile.credentials_file = profile.credentials_file.as_deref().map(expand_home);
+            profile.exclude_file = profile.exclude_file.as_deref().map(expand_home);
+        }
+
+        Ok(config)
+    }
+
+    // A missing file is an empty configuration, unless it was asked for explicitly
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let default_path = default_config_file();
+        let file = path.unwrap_or(&default_path);
+
+        match std::fs::read_to_string(file) {
+            Ok(text) => {
+                Config::parse(&text).map_err(|error| format!("{}: {}", file.display(), error))
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound && path.is_none() => {
+                Ok(Config::default())
+            }
+            Err(error) => Err(format!("{}: {}", file.display(), error)),
+        }
+    }
+
+    // The named profiles a mount command refers to: all of them, one by name, or an ad hoc
+    // one for a URL
+    pub fn resolve(&self, args: &MountArgs) -> Result<Vec<(String, Profile)>, String> {
+        if args.all {
+            if self.profiles.is_empty() {
+                return Err("no profiles configured".to_string());
+            }
+            return Ok(self
+                .profiles
+                .iter()
+                .map(|(name, profile)| (name.clone(), profile.clone().merge(args)))
+                .collect());
+        }
+
+        let target = args
+            .target
+            .as_deref()
+            .ok_or("give a server URL, a profile name or --all")?;
+
+        let profile = if target.contains("://") {
+            Profile {
+                url: target.to_string(),
+                ..Profile::default()
+            }
+        } else {
+            self.profiles
+                .get(target)
+                .cloned()
+                .ok_or_else(|| format!("no profile named {}", target))?
+        };
+
+        Ok(vec![(target.to_string(), profile.merge(args))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{parse, Command};
+    use std::ffi::OsString;
+
+    const CONFIG: &str = r#"
+        [profiles.personal]
+        url = "https://alice@cloud.example.com"
+        mountpoint = "/home/alice/Nextcloud"
+        offline = "read-write"
+
+        [profiles.customer]
+        url = "https://files.customer.example/nextcloud"
+        user = "alice.contractor"
+        dav_path = "remote.php/dav"
+        mountpoint = "/mnt/customer"
+        remote_path = "Projects/2024"
+        deny = ["Projects/2024/Archive"]
+        exclude = ["*.swp"]
+        read_only = true
+        umask = 0o077
+        password_command = "pass show customer"
+    "#;
+
+    fn mount_args(args: &[&str]) -> MountArgs {
+        let args = args.iter().map(OsString::from).collect();
+        match parse(args).command {
+            Command::Mount(mount) => *mount,
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_resolve_profiles() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.profiles.len(), 2);
+
+        let profiles = config
+            .resolve(&mount_args(&["nextcloud-fuse", "mount", "customer"]))
+            .unwrap();
+        let (name, customer) = &profiles[0];
+        assert_eq!(name, "customer");
+        assert_eq!(customer.user.as_deref(), Some("alice.contractor"));
+        assert_eq!(customer.umask, Some(0o077));
+        assert_eq!(customer.remote_path(), "Projects/2024");
+        assert!(customer.read_only);
+        assert_eq!(customer.deny, ["Projects/2024/Archive"]);
+
+        // The command line can also turn flags of the profile off
+        for args in [
+            &["nextcloud-fuse", "mount", "customer", "-o", "rw"][..],
+            &["nextcloud-fuse", "mount", "customer", "--read-only=false"],
+        ] {
+            let mut args = mount_args(args);
+            args.apply_options().unwrap();
+            let profiles = config.resolve(&args).unwrap();
+            assert!(!profiles[0].1.read_only);
+        }
+
+        let profiles = config
+            .resolve(&mount_args(&[
+                "nextcloud-fuse",
+                "mount",
+                "personal",
+                "/tmp/cloud",
+                "--remote-path",
+                "Photos",
+                "--exclude",
+                "*.tmp",
+            ]))
+            .unwrap();
+        let (_, personal) = &profiles[0];
+        assert_eq!(personal.mountpoint, Some(PathBuf::from("/tmp/cloud")));
+        assert_eq!(personal.remote_path(), "Photos");
+        assert_eq!(personal.dav_path(), DEFAULT_DAV_PATH);
+        assert!(!personal.read_only);
+        assert_eq!(personal.offline, Some(OfflineMode::ReadWrite));
+        assert_eq!(personal.exclude, ["*.tmp"]);
+
+        let profiles = config
+            .resolve(&mount_args(&[
+                "nextcloud-fuse",
+                "mount",
+                "--all",
+                "--read-only",
+            ]))
+            .unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert!(profiles.iter().all(|(_, profile)| profile.read_only));
+
+        let profiles = config
+            .resolve(&mount_args(&[
+                "nextcloud-fuse",
+                "mount",
+                "https://example.com",
+                "/mnt",
+            ]))
+            .unwrap();
+        assert_eq!(profiles[0].1.url, "https://example.com");
+
+        assert!(config
+            .resolve(&mount_args(&["nextcloud-fuse", "mount", "missing"]))
+            .is_err());
+        assert!(Config::parse("[profiles.x]\nurl = \"https://a\"\nbogus = 1").is_err());
+    }
+}
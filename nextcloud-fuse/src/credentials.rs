@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use secret_service::{EncryptionType, SecretService};
+
+use crate::config::config_dir;
+
+// Environment variable with the password of a server given on the command line, profiles
+// use it with their name appended
+pub const PASSWORD_VARIABLE: &str = "NEXTCLOUD_PASSWORD";
+// Application attribute of our keyring items
+const SERVICE: &str = "nextcloud-fuse";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub origin: String,
+    pub user: String,
+}
+
+impl Account {
+    fn attributes(&self) -> HashMap<&str, &str> {
+        HashMap::from([
+            ("application", SERVICE),
+            ("server", self.origin.as_str()),
+            ("user", self.user.as_str()),
+        ])
+    }
+}
+
+#[derive(Debug)]
+pub enum CredentialError {
+    Keyring(secret_service::Error),
+    Io(std::io::Error),
+    // The credentials file can be read by other users
+    InsecureFile(PathBuf),
+    CommandFailed(String),
+    // With the environment variable that was looked at
+    NotFound(String),
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::Keyring(error) => write!(f, "keyring: {}", error),
+            CredentialError::Io(error) => write!(f, "{}", error),
+            CredentialError::InsecureFile(path) => write!(
+                f,
+                "{} is accessible by other users, run chmod 600 on it",
+                path.display()
+            ),
+            CredentialError::CommandFailed(message) => {
+                write!(f, "password command failed: {}", message)
+            }
+            CredentialError::NotFound(variable) => write!(
+                f,
+                "no password found, run the login command or set {}",
+                variable
+            ),
+        }
+    }
+}
+
+pub fn default_credentials_file() -> PathBuf {
+    config_dir().join("credentials")
+}
+
+// Where passwords are kept besides the credentials file
+pub trait Keyring {
+    async fn store(&self, account: &Account, password: &str) -> Result<(), CredentialError>;
+    async fn lookup(&self, account: &Account) -> Result<Option<String>, CredentialError>;
+    // Returns whether anything was removed
+    async fn delete(&self, account: &Account) -> Result<bool, CredentialError>;
+}
+
+// The Secret Service of the desktop session
+pub struct SecretServiceKeyring;
+
+async fn connect() -> Result<SecretService<'static>, CredentialError> {
+    SecretService::connect(EncryptionType::Dh)
+        .await
+        .map_err(CredentialError::Keyring)
+}
+
+impl Keyring for SecretServiceKeyring {
+    async fn store(&self, account: &Account, password: &str) -> Result<(), CredentialError> {
+        let service = connect().await?;
+        let collection = service
+            .get_default_collection()
+            .await
+            .map_err(CredentialError::Keyring)?;
+        collection
+            .unlock()
+            .await
+            .map_err(CredentialError::Keyring)?;
+
+        collection
+            .create_item(
+                &format!("Nextcloud {}@{}", account.user, account.origin),
+                account.attributes(),
+                password.as_bytes(),
+                true,
+                "text/plain",
+            )
+            .await
+            .map_err(CredentialError::Keyring)?;
+
+        Ok(())
+    }
+
+    async fn lookup(&self, account: &Account) -> Result<Option<String>, CredentialError> {
+        let service = connect().await?;
+        let items = service
+            .search_items(account.attributes())
+            .await
+            .map_err(CredentialError::Keyring)?;
+
+        let item = match items.unlocked.into_iter().chain(items.locked).next() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.unlock().await.map_err(CredentialError::Keyring)?;
+        let secret = item.get_secret().await.map_err(CredentialError::Keyring)?;
+
+        Ok(Some(String::from_utf8_lossy(&secret).to_string()))
+    }
+
+    async fn delete(&self, account: &Account) -> Result<bool, CredentialError> {
+        let service = connect().await?;
+        let items = service
+            .search_items(account.attributes())
+            .await
+            .map_err(CredentialError::Keyring)?;
+
+        let mut deleted = false;
+        for item in items.unlocked.into_iter().chain(items.locked) {
+            item.delete().await.map_err(CredentialError::Keyring)?;
+            deleted = true;
+        }
+
+        Ok(deleted)
+    }
+}
+
+// The credentials file holds one "<origin>\t<user>\t<password>" line per account and has
+// to be private to its owner
+fn read_credentials_file(path: &Path) -> Result<Vec<(Account, String)>, CredentialError> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(CredentialError::Io(error)),
+    };
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(CredentialError::InsecureFile(path.to_path_buf()));
+    }
+
+    let content = fs::read_to_string(path).map_err(CredentialError::Io)?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let origin = fields.next()?.to_string();
+            let user = fields.next()?.to_string();
+            let password = fields.next()?.to_string();
+            Some((Account { origin, user }, password))
+        })
+        .collect())
+}
+
+fn write_credentials_file(
+    path: &Path,
+    entries: &[(Account, String)],
+) -> Result<(), CredentialError> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent).map_err(CredentialError::Io)?;
+
+    // Written next to the file and renamed over it, so a crash leaves either the old or the
+    // new passwords
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = parent.join(format!(".{}.{}", name, std::process::id()));
+    let _ = fs::remove_file(&temporary);
+    let write = || {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        for (account, password) in entries {
+            writeln!(file, "{}\t{}\t{}", account.origin, account.user, password)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    };
+
+    write().map_err(|error| {
+        let _ = fs::remove_file(&temporary);
+        CredentialError::Io(error)
+    })
+}
+
+pub fn file_lookup(path: &Path, account: &Account) -> Result<Option<String>, CredentialError> {
+    Ok(read_credentials_file(path)?
+        .into_iter()
+        .find(|(entry, _)| entry == account)
+        .map(|(_, password)| password))
+}
+
+pub fn file_store(path: &Path, account: &Account, password: &str) -> Result<(), CredentialError> {
+    let mut entries = read_credentials_file(path)?;
+    entries.retain(|(entry, _)| entry != account);
+    entries.push((account.clone(), password.to_string()));
+
+    write_credentials_file(path, &entries)
+}
+
+pub fn file_delete(path: &Path, account: &Account) -> Result<bool, CredentialError> {
+    let mut entries = read_credentials_file(path)?;
+    let count = entries.len();
+    entries.retain(|(entry, _)| entry != account);
+
+    if entries.len() == count {
+        return Ok(false);
+    }
+    write_credentials_file(path, &entries)?;
+    Ok(true)
+}
+
+// Run a command like "pass show nextcloud" through the shell, its first line of output
+// is the password
+pub fn command_password(command: &str) -> Result<String, CredentialError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(CredentialError::Io)?;
+
+    if !output.status.success() {
+        return Err(CredentialError::CommandFailed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.to_string())
+        .filter(|line| !line.is_empty())
+        .ok_or_else(|| CredentialError::CommandFailed("no output".to_string()))
+}
+
+// Environment variable with the password of the mount called name. Only a server given on
+// the command line gets the plain one, so with several profiles no password is ever sent to
+// another profile's server.
+pub fn password_variable(name: &str) -> String {
+    if name.contains("://") {
+        return PASSWORD_VARIABLE.to_string();
+    }
+    let suffix: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}_{}", PASSWORD_VARIABLE, suffix)
+}
+
+// Find the password of account: password command, keyring, credentials file, then the
+// environment variable
+pub async fn find_password(
+    keyring: &impl Keyring,
+    account: &Account,
+    password_command: Option<&str>,
+    credentials_file: &Path,
+    variable: &str,
+) -> Result<String, CredentialError> {
+    if let Some(command) = password_command {
+        return command_password(command);
+    }
+
+    // Without a desktop session there is no keyring, that is not an error
+    match keyring.lookup(account).await {
+        Ok(Some(password)) => return Ok(password),
+        Ok(None) => (),
+        Err(error) => log::debug!("{}", error),
+    }
+
+    if let Some(password) = file_lookup(credentials_file, account)? {
+        return Ok(password);
+    }
+    std::env::var(variable).map_err(|_| CredentialError::NotFound(variable.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // Stands in for the Secret Service, which needs a desktop session
+    #[derive(Default)]
+    struct MemoryKeyring(Mutex<Vec<(Account, String)>>);
+
+    impl Keyring for MemoryKeyring {
+        async fn store(&self, account: &Account, password: &str) -> Result<(), CredentialError> {
+            let mut entries = self.0.lock().unwrap();
+            entries.retain(|(entry, _)| entry != account);
+            entries.push((account.clone(), password.to_string()));
+            Ok(())
+        }
+
+        async fn lookup(&self, account: &Account) -> Result<Option<String>, CredentialError> {
+            let entries = self.0.lock().unwrap();
+            Ok(entries
+                .iter()
+                .find(|(entry, _)| entry == account)
+                .map(|(_, password)| password.clone()))
+        }
+
+        async fn delete(&self, account: &Account) -> Result<bool, CredentialError> {
+            let mut entries = self.0.lock().unwrap();
+            let count = entries.len();
+            entries.retain(|(entry, _)| entry != account);
+            Ok(entries.len() != count)
+        }
+    }
+
+    async fn check_keyring(keyring: &impl Keyring) {
+        let account = account(&format!("test-{}", std::process::id()));
+
+        keyring.store(&account, "secret").await.unwrap();
+        assert_eq!(
+            keyring.lookup(&account).await.unwrap().as_deref(),
+            Some("secret")
+        );
+        assert!(keyring.delete(&account).await.unwrap());
+        assert_eq!(keyring.lookup(&account).await.unwrap(), None);
+    }
+
+    fn account(user: &str) -> Account {
+        Account {
+            origin: "https://cloud.example.com".to_string(),
+            user: user.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let path = std::env::temp_dir()
+            .join(format!("nextcloud-fuse-test-{}", std::process::id()))
+            .join("credentials");
+
+        file_store(&path, &account("alice"), "first secret").unwrap();
+        file_store(&path, &account("bob"), "bob\tpassword").unwrap();
+        file_store(&path, &account("alice"), "second secret").unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            file_lookup(&path, &account("alice")).unwrap().as_deref(),
+            Some("second secret")
+        );
+        assert_eq!(
+            file_lookup(&path, &account("bob")).unwrap().as_deref(),
+            Some("bob\tpassword")
+        );
+
+        // Rewrites leave nothing else behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        assert!(file_delete(&path, &account("alice")).unwrap());
+        assert!(!file_delete(&path, &account("alice")).unwrap());
+        assert_eq!(file_lookup(&path, &account("alice")).unwrap(), None);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            file_lookup(&path, &account("bob")),
+            Err(CredentialError::InsecureFile(_))
+        ));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_command_password() {
+        assert_eq!(
+            command_password("printf 'hunter2\\nignored'").unwrap(),
+            "hunter2"
+        );
+        assert!(command_password("exit 1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_find_password() {
+        let path = std::env::temp_dir()
+            .join(format!("nextcloud-fuse-find-{}", std::process::id()))
+            .join("credentials");
+        let keyring = MemoryKeyring::default();
+        check_keyring(&keyring).await;
+
+        assert_eq!(
+            password_variable("https://cloud.example.com"),
+            "NEXTCLOUD_PASSWORD"
+        );
+        assert_eq!(
+            password_variable("work-cloud"),
+            "NEXTCLOUD_PASSWORD_WORK_CLOUD"
+        );
+        let variable = password_variable(&format!("test {}", std::process::id()));
+        let find = |account| {
+            let (keyring, path, variable) = (&keyring, &path, &variable);
+            async move { find_password(keyring, &account, None, path, variable).await }
+        };
+
+        // The environment comes last
+        std::env::set_var(&variable, "from environment");
+        assert_eq!(find(account("alice")).await.unwrap(), "from environment");
+        file_store(&path, &account("alice"), "from file").unwrap();
+        assert_eq!(find(account("alice")).await.unwrap(), "from file");
+        keyring
+            .store(&account("alice"), "from keyring")
+            .await
+            .unwrap();
+        assert_eq!(find(account("alice")).await.unwrap(), "from keyring");
+        std::env::remove_var(&variable);
+        assert!(matches!(
+            find(account("bob")).await,
+            Err(CredentialError::NotFound(name)) if name == variable
+        ));
+
+        let password = find_password(
+            &keyring,
+            &account("alice"),
+            Some("echo from command"),
+            &path,
+            &variable,
+        )
+        .await
+        .unwrap();
+        assert_eq!(password, "from command");
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    // Needs a Secret Service on the session bus, e.g. a throwaway one started with
+    // `dbus-run-session -- sh -c 'echo | gnome-keyring-daemon --unlock; cargo test -- --ignored'`
+    #[tokio::test]
+    #[ignore]
+    async fn test_keyring() {
+        check_keyring(&SecretServiceKeyring).await;
+    }
+}
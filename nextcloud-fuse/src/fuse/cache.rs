@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use nextcloud::{DavItem, File, Folder};
+
+use super::database::Database;
+
+// Metadata of files and folders from recent PROPFINDs, so that stating every entry of a
+// listed folder does not take a round trip each. Paths are relative to the user's files.
+pub struct MetadataCache {
+    ttl: Duration,
+    // How long a path is remembered not to exist
+    negative_ttl: Duration,
+    state: Mutex<State>,
+    // Everything but negative entries is written through to it
+    database: Option<Arc<Database>>,
+}
+
+pub enum Lookup {
+    Found(Box<DavItem>),
+    Missing,
+    // Not cached or expired
+    Unknown,
+}
+
+struct Entry {
+    item: Option<DavItem>,
+    fetched: Instant,
+    // Changed locally while offline, kept until the journal has been replayed
+    local: bool,
+}
+
+// Contents of a listed folder. Nextcloud changes the etag of a folder whenever anything
+// below it changes, so an unchanged etag revalidates all of them at once.
+struct Listing {
+    etag: Option<String>,
+    children: Vec<String>,
+    fetched: Instant,
+    local: bool,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    listings: HashMap<String, Listing>,
+    // Last known path of each file id
+    paths: HashMap<u64, String>,
+}
+
+// Folder containing path, None for the root
+pub fn parent(path: &str) -> Option<&str> {
+    match path {
+        "" => None,
+        path => Some(path.rsplit_once('/').map_or("", |(parent, _)| parent)),
+    }
+}
+
+fn child(folder: &str, name: &str) -> String {
+    match folder {
+        "" => name.to_string(),
+        folder => format!("{}/{}", folder, name),
+    }
+}
+
+fn is_below(path: &str, folder: &str) -> bool {
+    folder.is_empty()
+        || path
+            .strip_prefix(folder)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+// The same item at another path
+fn relocate(item: DavItem, path: &str) -> DavItem {
+    let name = path.rsplit('/').next().unwrap_or_default().to_string();
+    match item {
+        DavItem::Folder(folder) => DavItem::Folder(Folder {
+            name,
+            path: path.to_string(),
+            ..folder
+        }),
+        DavItem::File(file) => DavItem::File(File {
+            name,
+            path: path.to_string(),
+            ..file
+        }),
+    }
+}
+
+impl State {
+    fn insert(&mut self, path: &str, item: Option<DavItem>, fetched: Instant) {
+        // Local changes win over the server until they reached it
+        if self.entries.get(path).is_some_and(|entry| entry.local) {
+            return;
+        }
+        self.set(
+            path,
+            Entry {
+                item,
+                fetched,
+                local: false,
+            },
+        );
+    }
+
+    fn set(&mut self, path: &str, entry: Entry) {
+        if let Some(id) = entry.item.as_ref().and_then(|item| item.file_id()) {
+            // Renamed behind our back, the old path is gone
+            if let Some(old) = self.paths.insert(id, path.to_string()) {
+                if old != path {
+                    self.entries.remove(&old);
+                    let listing = parent(&old).and_then(|parent| self.listings.get_mut(parent));
+                    if let Some(listing) = listing {
+                        listing.children.retain(|child| *child != old);
+                    }
+                }
+            }
+        }
+        self.entries.insert(path.to_string(), entry);
+    }
+
+    fn insert_listing(
+        &mut self,
+        path: &str,
+        etag: Option<String>,
+        contents: &[DavItem],
+        fetched: Instant,
+    ) {
+        if self.listings.get(path).is_some_and(|listing| listing.local) {
+            return;
+        }
+        let children: Vec<String> = contents
+            .iter()
+            .map(|item| child(path, item.name()))
+            .collect();
+        // Entries that disappeared from the folder
+        if let Some(listing) = self.listings.remove(path) {
+            for old in listing.children {
+                if !children.contains(&old) {
+                    self.entries.remove(&old);
+                }
+            }
+        }
+
+        for (child, item) in children.iter().zip(contents) {
+            self.insert(child, Some(item.clone()), fetched);
+        }
+        self.listings.insert(
+            path.to_string(),
+            Listing {
+                etag,
+                children,
+                fetched,
+                local: false,
+            },
+        );
+    }
+
+    fn remove_tree(&mut self, path: &str) {
+        self.entries
+            .retain(|key, _| key != path && !is_below(key, path));
+        self.listings
+            .retain(|key, _| key != path && !is_below(key, path));
+    }
+
+    // Add path to or drop it from the listing of its parent
+    fn link(&mut self, path: &str, present: bool) {
+        let listing = parent(path).and_then(|parent| self.listings.get_mut(parent));
+        if let Some(listing) = listing {
+            listing.children.retain(|child| child != path);
+            if present {
+                listing.children.push(path.to_string());
+            }
+            listing.local = true;
+        }
+    }
+
+    fn set_missing(&mut self, path: &str, fetched: Instant) {
+        self.entries.insert(
+            path.to_string(),
+            Entry {
+                item: None,
+                fetched,
+                local: true,
+            },
+        );
+        self.link(path, false);
+    }
+}
+
+impl MetadataCache {
+    // Starts out with what the database remembers from earlier mounts, which has to be
+    // reconciled with the server before it is trusted
+    pub fn new(ttl: Duration, negative_ttl: Duration, database: Option<Arc<Database>>) -> Self {
+        let mut state = State::default();
+        let listings = database.as_ref().map(|database| database.listings());
+
+        match listings {
+            Some(Ok(listings)) => {
+                let now = Instant::now();
+                for listing in listings {
+                    state.insert_listing(&listing.path, listing.etag, &listing.contents, now);
+                }
+            }
+            Some(Err(error)) => log::warn!("cannot load cached metadata: {}", error),
+            None => (),
+        }
+
+        Self {
+            ttl,
+            negative_ttl,
+            state: Mutex::new(state),
+            database,
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Lookup {
+        self.lookup(path, false)
+    }
+
+    // Whatever is known about path however old it is, for when the server cannot be asked
+    pub fn get_stale(&self, path: &str) -> Lookup {
+        self.lookup(path, true)
+    }
+
+    fn lookup(&self, path: &str, stale: bool) -> Lookup {
+        let state = self.state.lock().unwrap();
+
+        if let Some(entry) = state.entries.get(path) {
+            let ttl = match entry.item {
+                Some(_) => self.ttl,
+                None => self.negative_ttl,
+            };
+            if stale || entry.local || entry.fetched.elapsed() < ttl {
+                return match &entry.item {
+                    Some(item) => Lookup::Found(Box::new(item.clone())),
+                    None => Lookup::Missing,
+                };
+            }
+        }
+
+        // Anything a fresh listing of the parent does not have does not exist
+        let listing = parent(path).and_then(|parent| state.listings.get(parent));
+        match listing {
+            Some(listing)
+                if (stale || listing.local || listing.fetched.elapsed() < self.negative_ttl)
+                    && !listing.children.iter().any(|child| child == path) =>
+            {
+                Lookup::Missing
+            }
+            _ => Lookup::Unknown,
+        }
+    }
+
+    // Contents of a folder if its listing has not expired
+    pub fn children(&self, path: &str) -> Option<Vec<DavItem>> {
+        self.listing(path, false)
+    }
+
+    pub fn stale_children(&self, path: &str) -> Option<Vec<DavItem>> {
+        self.listing(path, true)
+    }
+
+    fn listing(&self, path: &str, stale: bool) -> Option<Vec<DavItem>> {
+        let state = self.state.lock().unwrap();
+        let listing = state.listings.get(path)?;
+        if !stale && !listing.local && listing.fetched.elapsed() >= self.ttl {
+            return None;
+        }
+
+        listing
+            .children
+            .iter()
+            .map(|child| state.entries.get(child)?.item.clone())
+            .collect()
+    }
+
+    // Etag of a folder when it was last listed, even if that listing expired
+    pub fn listing_etag(&self, path: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.listings.get(path)?.etag.clone()
+    }
+
+    // Folders in a listed folder that were listed themselves, with the etags they had then
+    pub fn listed_subfolders(&self, path: &str) -> Vec<(String, Option<String>)> {
+        let state = self.state.lock().unwrap();
+        let children = match state.listings.get(path) {
+            Some(listing) => &listing.children,
+            None => return Vec::new(),
+        };
+
+        children
+            .iter()
+            .filter_map(|child| {
+                let listing = state.listings.get(child)?;
+                Some((child.clone(), listing.etag.clone()))
+            })
+            .collect()
+    }
+
+    // The folder still has the etag it was listed with, everything in it is current
+    pub fn revalidate(&self, path: &str, folder: DavItem) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let children = match state.listings.get_mut(path) {
+            Some(listing) => {
+                listing.fetched = now;
+                listing.children.clone()
+            }
+            None => return,
+        };
+        for child in children {
+            if let Some(entry) = state.entries.get_mut(&child) {
+                entry.fetched = now;
+            }
+        }
+        if let Some(database) = &self.database {
+            database.save_item(path, &folder);
+        }
+        state.insert(path, Some(folder), now);
+    }
+
+    // Remember the result of a stat, None if path does not exist
+    pub fn insert(&self, path: &str, item: Option<DavItem>) {
+        if let Some(database) = &self.database {
+            match &item {
+                Some(item) => database.save_item(path, item),
+                None => database.remove(path),
+            }
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .insert(path, item, Instant::now());
+    }
+
+    pub fn insert_listing(&self, path: &str, folder: Option<DavItem>, contents: &[DavItem]) {
+        if let Some(database) = &self.database {
+            database.save_listing(path, folder.as_ref(), contents);
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let etag = folder
+            .as_ref()
+            .and_then(|folder| folder.etag().map(str::to_string));
+        state.insert_listing(path, etag, contents, now);
+        if let Some(folder) = folder {
+            state.insert(path, Some(folder), now);
+        }
+    }
+
+    // Forget path, everything below it and the listing of its parent, after it was changed
+    pub fn invalidate(&self, path: &str) {
+        if let Some(database) = &self.database {
+            database.remove(path);
+        }
+        let mut state = self.state.lock().unwrap();
+
+        state.remove_tree(path);
+        if let Some(parent) = parent(path) {
+            state.listings.remove(parent);
+            state.entries.remove(parent);
+        }
+    }
+
+    // Changes queued while offline are shown right away and win over the server until
+    // the journal has been replayed. Only the journal is durable, the database is left
+    // alone.
+    pub fn put_local(&self, path: &str, item: DavItem) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        if let DavItem::Folder(_) = item {
+            state
+                .listings
+                .entry(path.to_string())
+                .or_insert_with(|| Listing {
+                    etag: None,
+                    children: Vec::new(),
+                    fetched: now,
+                    local: true,
+                });
+        }
+        state.set(
+            path,
+            Entry {
+                item: Some(item),
+                fetched: now,
+                local: true,
+            },
+        );
+        state.link(path, true);
+    }
+
+    pub fn remove_local(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.remove_tree(path);
+        state.set_missing(path, Instant::now());
+    }
+
+    pub fn rename_local(&self, from: &str, to: &str) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let moved = |key: &str| format!("{}{}", to, &key[from.len()..]);
+        let is_moved = |key: &String| key == from || is_below(key, from);
+
+        state.remove_tree(to);
+        // Listings first, moving the entries would otherwise drop them from the old ones
+        let listings: Vec<String> = state
+            .listings
+            .keys()
+            .filter(|key| is_moved(key))
+            .cloned()
+            .collect();
+        for key in listings {
+            let listing = state.listings.remove(&key).unwrap();
+            let listing = Listing {
+                children: listing.children.iter().map(|child| moved(child)).collect(),
+                local: true,
+                ..listing
+            };
+            state.listings.insert(moved(&key), listing);
+        }
+
+        let entries: Vec<String> = state
+            .entries
+            .keys()
+            .filter(|key| is_moved(key))
+            .cloned()
+            .collect();
+        for key in entries {
+            let entry = state.entries.remove(&key).unwrap();
+            let path = moved(&key);
+            let entry = Entry {
+                item: entry.item.map(|item| relocate(item, &path)),
+                fetched: now,
+                local: true,
+            };
+            state.set(&path, entry);
+        }
+
+        state.set_missing(from, now);
+        state.link(to, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nextcloud::{File, Folder};
+
+    fn file(path: &str, id: u64) -> DavItem {
+        DavItem::File(File {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            file_id: Some(id),
+            size: 1,
+            etag: None,
+            last_modified: None,
+            permissions: None,
+            lock: None,
+        })
+    }
+
+    fn folder(path: &str, etag: &str) -> DavItem {
+        DavItem::Folder(Folder {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            file_id: None,
+            etag: Some(etag.to_string()),
+            last_modified: None,
+            permissions: None,
+            lock: None,
+        })
+    }
+
+    #[test]
+    fn test_metadata_cache() {
+        let cache = MetadataCache::new(Duration::from_secs(60), Duration::from_secs(60), None);
+        cache.insert_listing(
+            "Docs",
+            Some(folder("Docs", "1")),
+            &[file("Docs/a.txt", 1), file("Docs/b.txt", 2)],
+        );
+
+        assert!(matches!(cache.get("Docs/a.txt"), Lookup::Found(_)));
+        assert!(matches!(cache.get("Docs/missing"), Lookup::Missing));
+        assert!(matches!(cache.get("Other/a.txt"), Lookup::Unknown));
+        assert_eq!(cache.children("Docs").unwrap().len(), 2);
+        assert_eq!(cache.listing_etag("Docs").as_deref(), Some("1"));
+
+        // b.txt was renamed on the server
+        cache.insert("Docs/c.txt", Some(file("Docs/c.txt", 2)));
+        assert!(matches!(cache.get("Docs/b.txt"), Lookup::Missing));
+
+        // Offline changes stay until they are replayed
+        cache.rename_local("Docs", "Old");
+        cache.put_local("Old/d.txt", file("Old/d.txt", 3));
+        cache.insert_listing("Old", Some(folder("Old", "2")), &[]);
+        assert!(matches!(cache.get("Docs"), Lookup::Missing));
+        assert_eq!(cache.children("Old").unwrap().len(), 2);
+        cache.remove_local("Old/a.txt");
+        assert!(matches!(cache.get("Old/a.txt"), Lookup::Missing));
+        match cache.get("Old/c.txt") {
+            Lookup::Found(item) => assert_eq!(item.path(), "Old/c.txt"),
+            _ => panic!("Old/c.txt is not cached"),
+        }
+
+        cache.invalidate("Old/c.txt");
+        assert!(matches!(cache.get("Old/c.txt"), Lookup::Unknown));
+        assert!(cache.children("Old").is_none());
+
+        let cache = MetadataCache::new(Duration::ZERO, Duration::ZERO, None);
+        cache.insert_listing("", None, &[file("a.txt", 1)]);
+        assert!(matches!(cache.get("a.txt"), Lookup::Unknown));
+        assert!(cache.children("").is_none());
+        assert!(matches!(cache.get_stale("a.txt"), Lookup::Found(_)));
+        assert!(matches!(cache.get_stale("b.txt"), Lookup::Missing));
+        assert_eq!(cache.stale_children("").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    ffi::CStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Name of this machine, shown in the names of conflicted copies
+pub fn hostname() -> String {
+    let mut buffer = [0 as libc::c_char; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len() - 1) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+
+    unsafe { CStr::from_ptr(buffer.as_ptr()) }
+        .to_string_lossy()
+        .to_string()
+}
+
+// Local date and time as in the desktop client's conflicted copies, e.g. 2024-05-17 093012
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs()) as libc::time_t;
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&seconds, &mut local) };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}{:02}{:02}",
+        local.tm_year + 1900,
+        local.tm_mon + 1,
+        local.tm_mday,
+        local.tm_hour,
+        local.tm_min,
+        local.tm_sec
+    )
+}
+
+// Where our version of path goes when both versions are kept, named like the desktop
+// client does: "report (conflicted copy laptop 2024-05-17 093012).odt"
+pub fn conflict_path(path: &str, host: &str, time: SystemTime) -> String {
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((folder, name)) => (Some(folder), name),
+        None => (None, path),
+    };
+    // A leading dot starts a hidden name, not an extension
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 => name.split_at(index),
+        _ => (name, ""),
+    };
+
+    let name = format!(
+        "{} (conflicted copy {} {}){}",
+        stem,
+        host,
+        timestamp(time),
+        extension
+    );
+    match folder {
+        Some(folder) => format!("{}/{}", folder, name),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflict_path() {
+        let time = SystemTime::now();
+        let stamp = timestamp(time);
+        assert_eq!(stamp.len(), "2024-05-17 093012".len());
+
+        assert_eq!(
+            conflict_path("Docs/report.odt", "laptop", time),
+            format!("Docs/report (conflicted copy laptop {}).odt", stamp)
+        );
+        assert_eq!(
+            conflict_path(".bashrc", "laptop", time),
+            format!(".bashrc (conflicted copy laptop {})", stamp)
+        );
+        assert_eq!(
+            conflict_path("a.b/Makefile", "laptop", time),
+            format!("a.b/Makefile (conflicted copy laptop {})", stamp)
+        );
+    }
+}
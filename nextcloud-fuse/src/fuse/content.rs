@@ -0,0 +1,405 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::database::{ContentEntry, Database};
+
+// File contents fetched with range requests, kept on disk in sparse files of which only
+// the fetched blocks are filled in. A file is only used as long as its etag is unchanged.
+pub struct ContentCache {
+    dir: PathBuf,
+    block_size: u64,
+    // Upper limit for the blocks of all files together, the least recently read files
+    // are dropped beyond it
+    max_size: u64,
+    state: Mutex<State>,
+    // Index of the cached files and their blocks, to keep them across mounts
+    database: Option<Arc<Database>>,
+}
+
+pub struct CachedFile {
+    // Path of the file on the server
+    key: String,
+    path: PathBuf,
+    file: File,
+    etag: String,
+    size: u64,
+    block_size: u64,
+    blocks: Mutex<HashSet<u64>>,
+    database: Option<Arc<Database>>,
+    // Dropped from the cache, the data goes once the last reader is done
+    removed: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    // Keyed by the path of the file on the server
+    files: HashMap<String, Entry>,
+    next_id: u64,
+    clock: u64,
+}
+
+struct Entry {
+    file: Arc<CachedFile>,
+    last_used: u64,
+}
+
+impl CachedFile {
+    // Blocks overlapping the range that still have to be fetched
+    pub fn missing(&self, offset: u64, size: u64) -> Vec<u64> {
+        let end = offset.saturating_add(size).min(self.size);
+        if offset >= end {
+            return Vec::new();
+        }
+
+        let blocks = self.blocks.lock().unwrap();
+        (offset / self.block_size..=(end - 1) / self.block_size)
+            .filter(|block| !blocks.contains(block))
+            .collect()
+    }
+
+    // Byte range of a block, the last one may be short
+    pub fn block_range(&self, block: u64) -> (u64, u64) {
+        let start = block * self.block_size;
+        (start, self.block_size.min(self.size.saturating_sub(start)))
+    }
+
+    pub fn store(&self, block: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, block * self.block_size)?;
+        // A block the index knows of has to survive a crash
+        if let Some(database) = &self.database {
+            self.file.sync_data()?;
+            database.save_block(&self.key, block);
+        }
+        self.blocks.lock().unwrap().insert(block);
+        Ok(())
+    }
+
+    pub fn read_at(&self, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(size).min(self.size);
+        let mut buffer = vec![0; end.saturating_sub(offset) as usize];
+        self.file.read_exact_at(&mut buffer, offset)?;
+        Ok(buffer)
+    }
+
+    fn cached_size(&self) -> u64 {
+        self.blocks.lock().unwrap().len() as u64 * self.block_size
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        if self.removed.load(Ordering::Relaxed) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl State {
+    fn remove(&mut self, path: &str, database: Option<&Arc<Database>>) -> Option<Entry> {
+        let entry = self.files.remove(path)?;
+        entry.file.removed.store(true, Ordering::Relaxed);
+        if let Some(database) = database {
+            database.remove_content(path);
+        }
+        Some(entry)
+    }
+}
+
+impl ContentCache {
+    // Without a database whatever an earlier run left in dir is discarded, with one the
+    // files it lists are picked up again
+    pub fn new(
+        dir: &Path,
+        block_size: u64,
+        max_size: u64,
+        database: Option<Arc<Database>>,
+    ) -> io::Result<Self> {
+        if database.is_none() {
+            match std::fs::remove_dir_all(dir) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => (),
+            }
+        }
+        std::fs::create_dir_all(dir)?;
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            block_size,
+            max_size,
+            state: Mutex::new(State::default()),
+            database,
+        };
+        if let Some(database) = &cache.database {
+            match database.content() {
+                Ok(entries) => cache.load(database, entries)?,
+                Err(error) => log::warn!("cannot load the content cache index: {}", error),
+            }
+        }
+
+        Ok(cache)
+    }
+
+    fn load(&self, database: &Arc<Database>, entries: Vec<ContentEntry>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        for entry in entries {
+            let path = self.dir.join(&entry.file_name);
+            let file = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) if entry.block_size == self.block_size => file,
+                // Gone, or cached with another block size
+                _ => {
+                    database.remove_content(&entry.path);
+                    continue;
+                }
+            };
+
+            state.next_id = state.next_id.max(entry.file_name.parse().unwrap_or(0));
+            state.clock = state.clock.max(entry.last_used);
+            let cached = CachedFile {
+                key: entry.path.clone(),
+                path,
+                file,
+                etag: entry.etag,
+                size: entry.size,
+                block_size: self.block_size,
+                blocks: Mutex::new(entry.blocks.into_iter().collect()),
+                database: Some(database.clone()),
+                removed: AtomicBool::new(false),
+            };
+            state.files.insert(
+                entry.path,
+                Entry {
+                    file: Arc::new(cached),
+                    last_used: entry.last_used,
+                },
+            );
+        }
+
+        // Files the index does not know of, e.g. after a crash
+        for file in std::fs::read_dir(&self.dir)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().to_string();
+            if !state
+                .files
+                .values()
+                .any(|entry| entry.file.path.ends_with(&name))
+            {
+                let _ = std::fs::remove_file(file.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    // The cached copy of a file at path with the given etag, a copy of an older version
+    // is thrown away
+    pub fn open(&self, path: &str, etag: &str, size: u64) -> io::Result<Arc<CachedFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = state.clock;
+
+        if let Some(entry) = state.files.get_mut(path) {
+            if entry.file.etag == etag && entry.file.size == size {
+                entry.last_used = now;
+                return Ok(entry.file.clone());
+            }
+        }
+        state.remove(path, self.database.as_ref());
+
+        state.next_id += 1;
+        let file_name = state.next_id.to_string();
+        let disk_path = self.dir.join(&file_name);
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&disk_path)
+        };
+        // Never write into a file that was put there by something else
+        let file = match open() {
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                std::fs::remove_file(&disk_path)?;
+                open()?
+            }
+            result => result?,
+        };
+        file.set_len(size)?;
+
+        if let Some(database) = &self.database {
+            database.save_content(&ContentEntry {
+                path: path.to_string(),
+                etag: etag.to_string(),
+                size,
+                block_size: self.block_size,
+                file_name,
+                last_used: now,
+                blocks: Vec::new(),
+            });
+        }
+        let file = Arc::new(CachedFile {
+            key: path.to_string(),
+            path: disk_path,
+            file,
+            etag: etag.to_string(),
+            size,
+            block_size: self.block_size,
+            blocks: Mutex::new(HashSet::new()),
+            database: self.database.clone(),
+            removed: AtomicBool::new(false),
+        });
+        state.files.insert(
+            path.to_string(),
+            Entry {
+                file: file.clone(),
+                last_used: now,
+            },
+        );
+
+        Ok(file)
+    }
+
+    pub fn remove(&self, path: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .remove(path, self.database.as_ref());
+    }
+
+    // Drop the least recently used files until the cache fits its limit. Files still being
+    // read from are only deleted once the last reader is done.
+    pub fn evict(&self) {
+        let mut state = self.state.lock().unwrap();
+        let mut total: u64 = state
+            .files
+            .values()
+            .map(|entry| entry.file.cached_size())
+            .sum();
+
+        while total > self.max_size {
+            let oldest = state
+                .files
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            let entry = match oldest.and_then(|path| state.remove(&path, self.database.as_ref())) {
+                Some(entry) => entry,
+                None => break,
+            };
+            total -= entry.file.cached_size();
+        }
+    }
+}
+
+// Without a database nothing outlives the mount, with one the order of use is kept for
+// eviction in the next mount
+impl Drop for ContentCache {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+
+        match &self.database {
+            Some(database) => {
+                let used: Vec<(String, u64)> = state
+                    .files
+                    .iter()
+                    .map(|(path, entry)| (path.clone(), entry.last_used))
+                    .collect();
+                database.touch_content(&used);
+            }
+            None => {
+                for entry in state.files.values() {
+                    entry.file.removed.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_cache() {
+        let dir =
+            std::env::temp_dir().join(format!("nextcloud-fuse-content-{}", std::process::id()));
+        let cache = ContentCache::new(&dir, 4, 8, None).unwrap();
+
+        let file = cache.open("a.txt", "1", 10).unwrap();
+        assert_eq!(file.missing(3, 4), [0, 1]);
+        assert_eq!(file.block_range(2), (8, 2));
+        file.store(0, b"0123").unwrap();
+        file.store(1, b"4567").unwrap();
+        assert_eq!(file.missing(0, 100), [2]);
+        assert_eq!(file.read_at(2, 4).unwrap(), b"2345");
+        assert_eq!(file.read_at(8, 4).unwrap().len(), 2);
+
+        // Same version, same blocks
+        assert!(cache
+            .open("a.txt", "1", 10)
+            .unwrap()
+            .missing(0, 8)
+            .is_empty());
+        // Changed on the server
+        assert_eq!(cache.open("a.txt", "2", 10).unwrap().missing(0, 8), [0, 1]);
+
+        let a = cache.open("a.txt", "2", 10).unwrap();
+        a.store(0, b"abcd").unwrap();
+        let b = cache.open("b.txt", "1", 8).unwrap();
+        b.store(0, b"0123").unwrap();
+        b.store(1, b"4567").unwrap();
+        cache.evict();
+        assert_eq!(cache.open("a.txt", "2", 10).unwrap().missing(0, 4), [0]);
+
+        drop((a, b, file, cache));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_content_cache() {
+        let dir =
+            std::env::temp_dir().join(format!("nextcloud-fuse-persistent-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = Arc::new(Database::open(&dir.join("cache.sqlite")).unwrap());
+        let content = dir.join("content");
+
+        let cache = ContentCache::new(&content, 4, 100, Some(database.clone())).unwrap();
+        cache
+            .open("a.txt", "1", 6)
+            .unwrap()
+            .store(1, b"45")
+            .unwrap();
+        drop(cache);
+        std::fs::write(content.join("stray"), b"x").unwrap();
+
+        let cache = ContentCache::new(&content, 4, 100, Some(database.clone())).unwrap();
+        let file = cache.open("a.txt", "1", 6).unwrap();
+        assert_eq!(file.missing(0, 6), [0]);
+        assert_eq!(file.read_at(4, 2).unwrap(), b"45");
+        assert!(!content.join("stray").exists());
+
+        // A link where the next file goes is replaced, not written through
+        let target = dir.join("target");
+        std::fs::write(&target, b"keep").unwrap();
+        let next = cache.state.lock().unwrap().next_id + 1;
+        std::os::unix::fs::symlink(&target, content.join(next.to_string())).unwrap();
+        let b = cache.open("b.txt", "1", 6).unwrap();
+        b.store(0, b"0123").unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"keep");
+        drop(b);
+
+        drop((file, cache, database));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
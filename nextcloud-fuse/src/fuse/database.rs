@@ -0,0 +1,589 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use nextcloud::{DavItem, File, Folder};
+use rusqlite::{params, Connection};
+
+use super::journal::Operation;
+
+// Bump when the schema changes, older databases are then dropped and rebuilt
+const SCHEMA_VERSION: i64 = 3;
+
+const SCHEMA: &str = "
+    CREATE TABLE items (
+        path TEXT PRIMARY KEY,
+        parent TEXT NOT NULL,
+        folder INTEGER NOT NULL,
+        file_id INTEGER,
+        etag TEXT,
+        size INTEGER NOT NULL,
+        mtime INTEGER,
+        permissions TEXT
+    );
+    CREATE INDEX items_parent ON items (parent);
+    -- Folders whose contents are all in items
+    CREATE TABLE listings (
+        path TEXT PRIMARY KEY,
+        etag TEXT
+    );
+    CREATE TABLE content (
+        path TEXT PRIMARY KEY,
+        etag TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        block_size INTEGER NOT NULL,
+        file_name TEXT NOT NULL,
+        last_used INTEGER NOT NULL
+    );
+    CREATE TABLE blocks (
+        path TEXT NOT NULL REFERENCES content (path) ON DELETE CASCADE,
+        block INTEGER NOT NULL,
+        PRIMARY KEY (path, block)
+    );
+    -- Modifications made while offline, replayed in order of id
+    CREATE TABLE journal (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        operation TEXT NOT NULL,
+        path TEXT NOT NULL,
+        target TEXT,
+        overwrite INTEGER,
+        mtime INTEGER,
+        data TEXT,
+        -- Etag the file has to have on the server
+        etag TEXT,
+        -- Set when replaying failed for another reason than being offline
+        error TEXT
+    );
+";
+
+// The metadata and content cache index of a mount, kept in SQLite so that a remount
+// starts warm. Every change is its own transaction, a crash loses at most the change
+// being written. Failing cache writes only cost cache hits, so they are logged and
+// ignored. The journal of offline modifications is not a cache, its errors are reported.
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+pub struct Listing {
+    pub path: String,
+    pub etag: Option<String>,
+    pub contents: Vec<DavItem>,
+}
+
+pub struct JournalEntry {
+    pub id: i64,
+    pub operation: Operation,
+    pub error: Option<String>,
+}
+
+pub struct ContentEntry {
+    pub path: String,
+    pub etag: String,
+    pub size: u64,
+    pub block_size: u64,
+    pub file_name: String,
+    pub last_used: u64,
+    pub blocks: Vec<u64>,
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+// LIKE pattern matching everything below path
+fn below(path: &str) -> String {
+    let escaped = path
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    match path {
+        "" => "%".to_string(),
+        _ => format!("{}/%", escaped),
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn from_unix_seconds(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+fn log_error<T>(result: rusqlite::Result<T>) {
+    if let Err(error) = result {
+        log::warn!("cache database: {}", error);
+    }
+}
+
+fn item_from_row(row: &rusqlite::Row) -> rusqlite::Result<DavItem> {
+    let path: String = row.get("path")?;
+    let name = path.rsplit('/').next().unwrap_or_default().to_string();
+    let file_id: Option<i64> = row.get("file_id")?;
+    let file_id = file_id.map(|id| id as u64);
+    let etag = row.get("etag")?;
+    let last_modified = row.get::<_, Option<i64>>("mtime")?.map(from_unix_seconds);
+    let permissions = row.get("permissions")?;
+
+    Ok(if row.get("folder")? {
+        DavItem::Folder(Folder {
+            name,
+            path,
+            file_id,
+            etag,
+            last_modified,
+            permissions,
+            lock: None,
+        })
+    } else {
+        DavItem::File(File {
+            name,
+            path,
+            file_id,
+            size: row.get::<_, i64>("size")? as u64,
+            etag,
+            last_modified,
+            permissions,
+            lock: None,
+        })
+    })
+}
+
+fn save_item(connection: &Connection, path: &str, item: &DavItem) -> rusqlite::Result<usize> {
+    let size = match item {
+        DavItem::File(file) => file.size,
+        DavItem::Folder(_) => 0,
+    };
+
+    connection.execute(
+        "INSERT OR REPLACE INTO items
+            (path, parent, folder, file_id, etag, size, mtime, permissions)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            path,
+            parent(path),
+            matches!(item, DavItem::Folder(_)),
+            item.file_id().map(|id| id as i64),
+            item.etag(),
+            size as i64,
+            item.last_modified().map(unix_seconds),
+            item.permissions(),
+        ],
+    )
+}
+
+fn remove_tree(connection: &Connection, path: &str) -> rusqlite::Result<()> {
+    for table in ["items", "listings"] {
+        connection.execute(
+            &format!(
+                "DELETE FROM {} WHERE path = ?1 OR path LIKE ?2 ESCAPE '\\'",
+                table
+            ),
+            params![path, below(path)],
+        )?;
+    }
+    Ok(())
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Database> {
+        if let Some(parent) = path.parent() {
+            // Opening reports it if this fails
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            connection.execute_batch(
+                "DROP TABLE IF EXISTS journal;
+                DROP TABLE IF EXISTS blocks;
+                DROP TABLE IF EXISTS content;
+                DROP TABLE IF EXISTS listings;
+                DROP TABLE IF EXISTS items;",
+            )?;
+            connection.execute_batch(SCHEMA)?;
+            connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Listed folders with their contents
+    pub fn listings(&self) -> rusqlite::Result<Vec<Listing>> {
+        let connection = self.connection.lock().unwrap();
+        let mut folders = connection.prepare("SELECT path, etag FROM listings")?;
+        let mut contents = connection.prepare("SELECT * FROM items WHERE parent = ?1")?;
+
+        let listings = folders
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .map(|row| {
+                let (path, etag) = row?;
+                let contents = contents
+                    .query_map([&path], item_from_row)?
+                    // The root is its own parent
+                    .filter(|item| !matches!(item, Ok(item) if item.path() == path))
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(Listing {
+                    path,
+                    etag,
+                    contents,
+                })
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(listings)
+    }
+
+    #[cfg(test)]
+    pub fn item(&self, path: &str) -> rusqlite::Result<Option<DavItem>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT * FROM items WHERE path = ?1")?;
+        let mut items = statement.query_map([path], item_from_row)?;
+        items.next().transpose()
+    }
+
+    pub fn save_item(&self, path: &str, item: &DavItem) {
+        let connection = self.connection.lock().unwrap();
+        log_error(save_item(&connection, path, item));
+    }
+
+    // Replace what is known about the contents of a folder
+    pub fn save_listing(&self, path: &str, folder: Option<&DavItem>, contents: &[DavItem]) {
+        let mut connection = self.connection.lock().unwrap();
+
+        log_error((|| {
+            let transaction = connection.transaction()?;
+            let old: Vec<String> = transaction
+                .prepare("SELECT path FROM items WHERE parent = ?1 AND path != ?1")?
+                .query_map([path], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            for old in old {
+                if !contents.iter().any(|item| item.path() == old) {
+                    remove_tree(&transaction, &old)?;
+                }
+            }
+            for item in contents {
+                save_item(&transaction, item.path(), item)?;
+            }
+            if let Some(folder) = folder {
+                save_item(&transaction, path, folder)?;
+            }
+            transaction.execute(
+                "INSERT OR REPLACE INTO listings (path, etag) VALUES (?1, ?2)",
+                params![path, folder.and_then(|folder| folder.etag())],
+            )?;
+            transaction.commit()
+        })());
+    }
+
+    // Forget path, everything below it and the listing of its parent
+    pub fn remove(&self, path: &str) {
+        let mut connection = self.connection.lock().unwrap();
+
+        log_error((|| {
+            let transaction = connection.transaction()?;
+            remove_tree(&transaction, path)?;
+            if !path.is_empty() {
+                transaction.execute("DELETE FROM listings WHERE path = ?1", [parent(path)])?;
+            }
+            transaction.commit()
+        })());
+    }
+
+    pub fn content(&self) -> rusqlite::Result<Vec<ContentEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut files = connection
+            .prepare("SELECT path, etag, size, block_size, file_name, last_used FROM content")?;
+        let mut blocks = connection.prepare("SELECT block FROM blocks WHERE path = ?1")?;
+
+        let entries = files
+            .query_map([], |row| {
+                Ok(ContentEntry {
+                    path: row.get(0)?,
+                    etag: row.get(1)?,
+                    size: row.get::<_, i64>(2)? as u64,
+                    block_size: row.get::<_, i64>(3)? as u64,
+                    file_name: row.get(4)?,
+                    last_used: row.get::<_, i64>(5)? as u64,
+                    blocks: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.blocks = blocks
+                    .query_map([&entry.path], |row| Ok(row.get::<_, i64>(0)? as u64))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(entry)
+            })
+            .collect()
+    }
+
+    // A new cached file, replacing any older one of the same path along with its blocks
+    pub fn save_content(&self, entry: &ContentEntry) {
+        let mut connection = self.connection.lock().unwrap();
+
+        log_error((|| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM content WHERE path = ?1", [&entry.path])?;
+            transaction.execute(
+                "INSERT INTO content (path, etag, size, block_size, file_name, last_used)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    entry.path,
+                    entry.etag,
+                    entry.size as i64,
+                    entry.block_size as i64,
+                    entry.file_name,
+                    entry.last_used as i64,
+                ],
+            )?;
+            transaction.commit()
+        })());
+    }
+
+    // Only call once the block's data is on disk
+    pub fn save_block(&self, path: &str, block: u64) {
+        let connection = self.connection.lock().unwrap();
+        log_error(connection.execute(
+            "INSERT OR IGNORE INTO blocks (path, block) VALUES (?1, ?2)",
+            params![path, block as i64],
+        ));
+    }
+
+    // Record the order in which cached files were last used
+    pub fn touch_content(&self, used: &[(String, u64)]) {
+        let mut connection = self.connection.lock().unwrap();
+
+        log_error((|| {
+            let transaction = connection.transaction()?;
+            for (path, last_used) in used {
+                transaction.execute(
+                    "UPDATE content SET last_used = ?2 WHERE path = ?1",
+                    params![path, *last_used as i64],
+                )?;
+            }
+            transaction.commit()
+        })());
+    }
+
+    pub fn remove_content(&self, path: &str) {
+        let connection = self.connection.lock().unwrap();
+        log_error(connection.execute("DELETE FROM content WHERE path = ?1", [path]));
+    }
+
+    pub fn queue(&self, operation: &Operation) -> rusqlite::Result<i64> {
+        let connection = self.connection.lock().unwrap();
+        let (kind, path, target, overwrite, mtime, data, etag) = match operation {
+            Operation::Upload {
+                path,
+                data,
+                mtime,
+                etag,
+            } => (
+                "upload",
+                path,
+                None,
+                None,
+                mtime.map(unix_seconds),
+                Some(data.to_string_lossy()),
+                etag.as_ref(),
+            ),
+            Operation::Create { path, data } => (
+                "create",
+                path,
+                None,
+                None,
+                None,
+                Some(data.to_string_lossy()),
+                None,
+            ),
+            Operation::Mkdir { path } => ("mkdir", path, None, None, None, None, None),
+            Operation::Delete { path } => ("delete", path, None, None, None, None, None),
+            Operation::Rename {
+                from,
+                to,
+                overwrite,
+                etag,
+            } => (
+                "rename",
+                from,
+                Some(to),
+                Some(*overwrite),
+                None,
+                None,
+                etag.as_ref(),
+            ),
+        };
+
+        connection.execute(
+            "INSERT INTO journal (operation, path, target, overwrite, mtime, data, etag)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![kind, path, target, overwrite, mtime, data, etag],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    // Everything in the journal, oldest first
+    pub fn journal(&self) -> rusqlite::Result<Vec<JournalEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, operation, path, target, overwrite, mtime, data, error, etag
+                FROM journal ORDER BY id",
+        )?;
+
+        let entries = statement
+            .query_map([], |row| {
+                let kind: String = row.get(1)?;
+                let path: String = row.get(2)?;
+                let operation = match kind.as_str() {
+                    "upload" => Operation::Upload {
+                        path,
+                        data: PathBuf::from(row.get::<_, Option<String>>(6)?.unwrap_or_default()),
+                        mtime: row.get::<_, Option<i64>>(5)?.map(from_unix_seconds),
+                        etag: row.get(8)?,
+                    },
+                    "create" => Operation::Create {
+                        path,
+                        data: PathBuf::from(row.get::<_, Option<String>>(6)?.unwrap_or_default()),
+                    },
+                    "mkdir" => Operation::Mkdir { path },
+                    "delete" => Operation::Delete { path },
+                    _ => Operation::Rename {
+                        from: path,
+                        to: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                        overwrite: row.get::<_, Option<bool>>(4)?.unwrap_or(false),
+                        etag: row.get(8)?,
+                    },
+                };
+
+                Ok(JournalEntry {
+                    id: row.get(0)?,
+                    operation,
+                    error: row.get(7)?,
+                })
+            })?
+            .collect();
+        entries
+    }
+
+    pub fn finish(&self, id: i64) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM journal WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    pub fn fail(&self, id: i64, error: &str) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE journal SET error = ?2 WHERE id = ?1",
+            params![id, error],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> DavItem {
+        DavItem::File(File {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            file_id: Some(7),
+            size: 3,
+            etag: Some("e1".to_string()),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            permissions: Some("RGDNVW".to_string()),
+            lock: None,
+        })
+    }
+
+    #[test]
+    fn test_database() {
+        let path =
+            std::env::temp_dir().join(format!("nextcloud-fuse-db-{}.sqlite", std::process::id()));
+        let database = Database::open(&path).unwrap();
+
+        database.save_listing("", None, &[file("a_b.txt"), file("Docs")]);
+        database.save_listing("Docs", None, &[file("Docs/x.txt")]);
+        drop(database);
+
+        let database = Database::open(&path).unwrap();
+        let listings = database.listings().unwrap();
+        assert_eq!(listings.len(), 2);
+        assert_eq!(listings[0].path, "");
+        assert_eq!(listings[0].contents.len(), 2);
+        let item = database.item("Docs/x.txt").unwrap().unwrap();
+        assert_eq!(item.etag(), Some("e1"));
+        assert_eq!(item.file_id(), Some(7));
+        assert_eq!(item.permissions(), Some("RGDNVW"));
+        assert_eq!(item.last_modified(), file("x").last_modified());
+
+        // "_" must not match as a wildcard
+        database.remove("a");
+        assert!(database.item("a_b.txt").unwrap().is_some());
+        database.remove("Docs");
+        assert!(database.item("Docs/x.txt").unwrap().is_none());
+        assert_eq!(database.listings().unwrap().len(), 0);
+
+        database.save_content(&ContentEntry {
+            path: "a_b.txt".to_string(),
+            etag: "e1".to_string(),
+            size: 3,
+            block_size: 4,
+            file_name: "1".to_string(),
+            last_used: 1,
+            blocks: Vec::new(),
+        });
+        database.save_block("a_b.txt", 0);
+        assert_eq!(database.content().unwrap()[0].blocks, [0]);
+        database.remove_content("a_b.txt");
+        assert!(database.content().unwrap().is_empty());
+
+        let mkdir = database
+            .queue(&Operation::Mkdir {
+                path: "New".to_string(),
+            })
+            .unwrap();
+        database
+            .queue(&Operation::Rename {
+                from: "a_b.txt".to_string(),
+                to: "New/a_b.txt".to_string(),
+                overwrite: true,
+                etag: Some("1".to_string()),
+            })
+            .unwrap();
+        database.finish(mkdir).unwrap();
+        let journal = database.journal().unwrap();
+        assert_eq!(journal.len(), 1);
+        assert!(matches!(
+            &journal[0].operation,
+            Operation::Rename { to, overwrite: true, etag: Some(etag), .. }
+                if to == "New/a_b.txt" && etag == "1"
+        ));
+        database.fail(journal[0].id, "conflict").unwrap();
+        assert_eq!(
+            database.journal().unwrap()[0].error.as_deref(),
+            Some("conflict")
+        );
+
+        drop(database);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
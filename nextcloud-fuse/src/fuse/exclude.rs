@@ -0,0 +1,196 @@
+// Entries hidden from the mount and never uploaded, from patterns in the syntax of the
+// desktop client's sync-exclude.lst and from lists of remote paths, e.g.
+//
+//   # Comments start with a hash
+//   *.swp
+//   ~$*.docx
+//   .~lock.*#
+//   node_modules/
+//   /build/
+//
+// A pattern matches the names of files and folders anywhere. With a slash inside it matches
+// the path from the root of the mount instead. A trailing slash only matches folders and
+// a leading ] (deletable by the desktop client) makes no difference here. * and ? never
+// match a slash, [a-z] and [!a-z] match one character of a class.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+    // Paths relative to the user's files. Only what is below an allowed folder is shown
+    // when there are any, never what is below a denied one.
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    glob: Vec<char>,
+    folders_only: bool,
+    whole_path: bool,
+}
+
+fn parse_pattern(line: &str) -> Option<Pattern> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let line = line.strip_prefix(']').unwrap_or(line);
+    let folders_only = line.ends_with('/');
+    let line = line.trim_end_matches('/');
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(Pattern {
+        glob: line.trim_start_matches('/').chars().collect(),
+        folders_only,
+        whole_path: line.contains('/'),
+    })
+}
+
+// Index of the ] closing a class, a ] right after the [ or the ! is part of it
+fn class_end(pattern: &[char]) -> Option<usize> {
+    let start = match pattern.first() {
+        Some('!' | '^') => 2,
+        _ => 1,
+    };
+    Some(start + pattern.get(start..)?.iter().position(|&c| c == ']')?)
+}
+
+fn in_class(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('!' | '^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    matched != negated
+}
+
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| matches(rest, &text[i..])),
+        Some(('?', rest)) => match text.split_first() {
+            Some((&c, text)) => c != '/' && matches(rest, text),
+            None => false,
+        },
+        Some(('[', rest)) => match (class_end(rest), text.split_first()) {
+            (Some(end), Some((&c, text))) => {
+                c != '/' && in_class(&rest[..end], c) && matches(&rest[end + 1..], text)
+            }
+            (Some(_), None) => false,
+            // Not a class after all
+            (None, _) => text.first() == Some(&'[') && matches(rest, &text[1..]),
+        },
+        Some(('\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && matches(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+    }
+}
+
+fn is_below(path: &str, folder: &str) -> bool {
+    folder.is_empty() || path == folder || path.starts_with(&format!("{}/", folder))
+}
+
+impl Excludes {
+    pub fn new(patterns: &str, allow: &[String], deny: &[String]) -> Excludes {
+        let paths = |paths: &[String]| {
+            paths
+                .iter()
+                .map(|path| path.trim_matches('/').to_string())
+                .collect()
+        };
+
+        Excludes {
+            patterns: patterns.lines().filter_map(parse_pattern).collect(),
+            allow: paths(allow),
+            deny: paths(deny),
+        }
+    }
+
+    // path is relative to the user's files, relative to the root of the mount
+    pub fn excludes(&self, path: &str, relative: &str, folder: bool) -> bool {
+        let path = path.trim_matches('/');
+        let relative = relative.trim_matches('/');
+        if relative.is_empty() {
+            return false;
+        }
+
+        if self.deny.iter().any(|denied| is_below(path, denied)) {
+            return true;
+        }
+        // The folders above an allowed one are needed to get there
+        if !self.allow.is_empty()
+            && !self
+                .allow
+                .iter()
+                .any(|allowed| is_below(path, allowed) || is_below(allowed, path))
+        {
+            return true;
+        }
+
+        let name: Vec<char> = relative
+            .rsplit('/')
+            .next()
+            .unwrap_or(relative)
+            .chars()
+            .collect();
+        let relative: Vec<char> = relative.chars().collect();
+        self.patterns.iter().any(|pattern| {
+            let text = if pattern.whole_path { &relative } else { &name };
+            (folder || !pattern.folders_only) && matches(&pattern.glob, text)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excludes() {
+        let excludes = Excludes::new(
+            "# editors\n*.swp\n~$*.docx\n]*~\n.~lock.*#\n\nnode_modules/\n/build/\n[Tt]humbs.db\n",
+            &[],
+            &[],
+        );
+        let excluded = |path: &str, folder: bool| excludes.excludes(path, path, folder);
+
+        assert!(excluded("Docs/.notes.txt.swp", false));
+        assert!(excluded("~$report.docx", false));
+        assert!(excluded("notes.txt~", false));
+        assert!(excluded("Docs/.~lock.report.odt#", false));
+        assert!(excluded("app/node_modules", true));
+        assert!(excluded("build", true));
+        assert!(excluded("thumbs.db", false));
+        assert!(!excluded("report.docx", false));
+        assert!(!excluded("node_modules", false));
+        assert!(!excluded("app/build", true));
+        assert!(!excluded("# editors", false));
+
+        let excludes = Excludes::new(
+            "",
+            &["Projects/2024".to_string()],
+            &["/Projects/2024/Archive/".to_string()],
+        );
+        assert!(!excludes.excludes("Projects", "Projects", true));
+        assert!(!excludes.excludes("Projects/2024/a.txt", "Projects/2024/a.txt", false));
+        assert!(excludes.excludes("Projects/2023", "Projects/2023", true));
+        assert!(excludes.excludes("Photos", "Photos", true));
+        assert!(excludes.excludes("Projects/2024/Archive/old", "Archive/old", true));
+        assert!(!excludes.excludes("Projects/2024", "", true));
+    }
+}
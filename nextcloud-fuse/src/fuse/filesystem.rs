@@ -1,70 +1,2689 @@
-use std::io::Empty;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use bytes::Bytes;
 use fuse3::{
     path::{
-        reply::{DirectoryEntry, ReplyDirectory, ReplyInit},
+        reply::{
+            DirectoryEntry, DirectoryEntryPlus, FileAttr, ReplyAttr, ReplyCreated, ReplyData,
+            ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs,
+            ReplyWrite, ReplyXAttr,
+        },
         PathFilesystem,
     },
     raw::Request,
-    Errno,
+    Errno, FileType, Result, SetAttr, Timestamp,
+};
+use futures_util::stream::{self, Iter};
+use nextcloud::{
+    DavError, DavItem, File, Folder, Nextcloud, Permissions, Quota, QuotaValue, TrashItem, Version,
+};
+
+use super::{
+    cache::{parent, Lookup, MetadataCache},
+    content::ContentCache,
+    database::Database,
+    exclude::Excludes,
+    journal::{Journal, Operation, Outcome},
+    staging::{self, OpenFile, StagedFile},
+    trash::{self, Trash},
+    versions::{self, Versions},
+    watcher::Change,
+    xattr,
 };
-use nextcloud::Nextcloud;
+use crate::cli::{ConflictPolicy, OfflineMode};
+
+// How long the kernel may cache attributes and lookups
+const TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u32 = 4096;
+const MAX_NAME_LENGTH: u32 = 255;
+// Size of the ranges fetched at once to fill in a staged file
+const DOWNLOAD_SIZE: u64 = 8 * 1024 * 1024;
+// How long the quota of the mount root is reused by statfs and the space checks
+const QUOTA_TTL: Duration = Duration::from_secs(30);
+// Free space reported when the quota is unlimited or the server cannot tell, 1 PiB
+const UNKNOWN_FREE: u64 = 1 << 50;
+
+#[derive(Debug, Clone)]
+pub struct FilesystemOptions {
+    // Folder of the account shown as the root of the mount, "" for all files
+    pub root: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub umask: u32,
+    pub read_only: bool,
+    // Where files opened for writing are staged until they are uploaded
+    pub staging_dir: PathBuf,
+    // How long metadata is served from memory before it is revalidated
+    pub attr_timeout: Duration,
+    pub negative_timeout: Duration,
+    // Where fetched blocks of file contents are kept, in blocks of block_size bytes up to
+    // cache_size bytes in total
+    pub content_dir: PathBuf,
+    pub block_size: u64,
+    pub cache_size: u64,
+    // Keeps both caches across mounts
+    pub database: Option<PathBuf>,
+    // What still works while the server cannot be reached. Writing offline needs the
+    // database, modifications are queued in it along with copies of uploads in journal_dir.
+    pub offline: OfflineMode,
+    pub journal_dir: PathBuf,
+    // Which version stays when a file was changed on the server while we wrote to it
+    pub conflicts: ConflictPolicy,
+    // Shows the trash bin in /.trash
+    pub trash: bool,
+    // Shows older versions of files in /.versions
+    pub versions: bool,
+    // Files and folders hidden from the mount, which cannot be created either
+    pub excludes: Excludes,
+    // How often the server is asked for changes made elsewhere, zero to never
+    pub poll_interval: Duration,
+}
 
-struct NextcloudFilesystem {
+// A file of the trash bin or the versions folder, those are only ever read
+#[derive(Debug, Clone)]
+enum VirtualFile {
+    // By its path relative to the trash
+    Trashed(String),
+    // By the file id of its file and the server's name for it
+    Version(u64, String),
+}
+
+// What a path below /.versions is
+enum VersionPath {
+    // A folder of the mirrored tree, by its path
+    Folder(String),
+    // A file, shown as a folder of its versions
+    File(String, u64),
+    // One of the versions of a file
+    Version(String, u64, Box<Version>),
+}
+
+pub struct NextcloudFilesystem {
     client: Nextcloud,
+    options: FilesystemOptions,
+    cache: Arc<MetadataCache>,
+    // None if the content cache directory could not be set up
+    content: Option<ContentCache>,
+    // Only for mounts writable while offline
+    journal: Option<Arc<Journal>>,
+    handles: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<OpenFile>>>>,
+    next_handle: AtomicU64,
+    // Last quota of the mount root and when it was fetched
+    quota: Mutex<Option<(Instant, Quota)>>,
+    trash: Option<Trash>,
+    versions: Option<Versions>,
+    // Files opened in the trash bin and the versions folder, by handle
+    virtual_handles: Mutex<HashMap<u64, VirtualFile>>,
+    // Where the last sync-collection left off, and whether the server has it at all
+    sync_token: Mutex<Option<String>>,
+    sync_supported: AtomicBool,
 }
 
-impl NextcloudFilesystem {
-    fn new(client: Nextcloud) -> Self {
-        let mut config = include_str!("../../.config").split_whitespace();
-        let username = config.next().unwrap();
-        let password = config.next().unwrap();
-        let base_url = config.next().unwrap();
-        let dav_path = config.next().unwrap();
+// Path of a FUSE path relative to the user's files root
+fn dav_path(root: &str, path: &OsStr) -> Result<String> {
+    let path = path
+        .to_str()
+        .map(|path| path.trim_matches('/'))
+        .ok_or_else(|| Errno::from(libc::EINVAL))?;
+    let root = root.trim_matches('/');
 
-        let provider = client::Nextcloud::new(
-            base_url.to_string(),
-            dav_path.to_string(),
-            username.to_string(),
-            password.to_string(),
-        );
+    Ok(match (root.is_empty(), path.is_empty()) {
+        (true, _) => path.to_string(),
+        (false, true) => root.to_string(),
+        (false, false) => format!("{}/{}", root, path),
+    })
+}
+
+// FUSE path of a path relative to the user's files root, None if it is outside the mount
+fn fuse_path(root: &str, path: &str) -> Option<OsString> {
+    let root = root.trim_matches('/');
+    let rest = match root {
+        "" => path,
+        _ if path == root => "",
+        _ => path.strip_prefix(root)?.strip_prefix('/')?,
+    };
 
-        self { client: provider }
+    Some(OsString::from(format!("/{}", rest.trim_matches('/'))))
+}
+
+// ls wants folders with a trailing slash, except for the root
+fn folder_path(path: &str) -> String {
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", path.trim_end_matches('/'))
     }
 }
 
-impl PathFilesystem for NextcloudFilesystem {
-    async fn init(&self, req: Request) -> Result<ReplyInit> {}
+// Path below the folder with this name in the root, "" for the folder itself
+fn below(path: &OsStr, name: &str) -> Option<String> {
+    let rest = path.to_str()?.trim_start_matches('/').strip_prefix(name)?;
+    if rest.is_empty() {
+        return Some(String::new());
+    }
 
-    fn destroy(&self, req: Request) {}
+    Some(rest.strip_prefix('/')?.trim_matches('/').to_string())
+}
 
-    type DirEntryStream<'a> = Empty<Result<DirectoryEntry, Errno>> where Self: 'a;
-    type DirEntryPlusStream<'a> = Empty<Result<DirectoryEntry, Errno>> where Self:'a;
+fn join(parent: &OsStr, name: &OsStr) -> OsString {
+    Path::new(parent).join(name).into_os_string()
+}
 
-    async fn readdir(
-        &'a self,
-        req: Request,
-        path: &'a OsStr,
-        fh: u64,
-        offset: i64,
-    ) -> Result<ReplyDirectory<Self::DirEntryStream>, Errno> {
-        match self.client.ls(path.to_str().unwrap()).await {
-            Ok(items) => {
-                let entries = items.into_iter().map(|item| {
-                    let kind = match item.is_dir {
-                        true => FileType::Directory,
-                        false => FileType::RegularFile,
-                    };
-
-                    DirEntry {
-                        kind,
-                        name: item.name,
+fn io_errno(error: std::io::Error) -> Errno {
+    error.raw_os_error().unwrap_or(libc::EIO).into()
+}
+
+fn system_time(timestamp: Timestamp) -> SystemTime {
+    UNIX_EPOCH + Duration::new(timestamp.sec.max(0) as u64, timestamp.nsec)
+}
+
+fn is_writable(flags: u32) -> bool {
+    flags as i32 & libc::O_ACCMODE != libc::O_RDONLY
+}
+
+// Refuses what oc:permissions do not allow before the server is asked: EACCES where write
+// access is missing, EPERM for entries that cannot be removed, renamed or moved
+fn require(allowed: bool, error: i32) -> Result<()> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(error.into())
+    }
+}
+
+fn errno(error: DavError) -> Errno {
+    match error {
+        DavError::UnexpectedStatus(status) => match status.as_u16() {
+            401 | 403 => libc::EACCES.into(),
+            404 | 409 => libc::ENOENT.into(),
+            // MKCOL on an existing resource, MOVE without overwriting
+            405 | 412 => libc::EEXIST.into(),
+            507 => libc::ENOSPC.into(),
+            _ => libc::EIO.into(),
+        },
+        DavError::Locked => libc::EBUSY.into(),
+        DavError::BadUrl(_) => libc::EINVAL.into(),
+        DavError::Io(error) => error.raw_os_error().unwrap_or(libc::EIO).into(),
+        _ => libc::EIO.into(),
+    }
+}
+
+impl NextcloudFilesystem {
+    pub fn new(client: Nextcloud, options: FilesystemOptions) -> Self {
+        let database = options.database.as_deref().and_then(|path| {
+            Database::open(path)
+                .map_err(|error| log::warn!("cannot open {}: {}", path.display(), error))
+                .ok()
+                .map(Arc::new)
+        });
+        let content = ContentCache::new(
+            &options.content_dir,
+            options.block_size,
+            options.cache_size,
+            database.clone(),
+        )
+        .map_err(|error| {
+            log::warn!(
+                "not caching file contents in {}: {}",
+                options.content_dir.display(),
+                error
+            )
+        })
+        .ok();
+        let cache = Arc::new(MetadataCache::new(
+            options.attr_timeout,
+            options.negative_timeout,
+            database.clone(),
+        ));
+
+        let journal = match (options.offline, database) {
+            (OfflineMode::ReadWrite, Some(database)) if !options.read_only => Journal::new(
+                client.clone(),
+                options.conflicts,
+                database,
+                &options.journal_dir,
+                cache.clone(),
+            )
+            .map_err(|error| {
+                log::warn!(
+                    "read-only while offline, no journal in {}: {}",
+                    options.journal_dir.display(),
+                    error
+                )
+            })
+            .ok()
+            .map(Arc::new),
+            (OfflineMode::ReadWrite, None) => {
+                log::warn!("read-only while offline, there is no database for the journal");
+                None
+            }
+            _ => None,
+        };
+
+        let trash = options
+            .trash
+            .then(|| Trash::new(client.clone(), options.attr_timeout));
+        let versions = options
+            .versions
+            .then(|| Versions::new(client.clone(), options.attr_timeout));
+
+        Self {
+            client,
+            trash,
+            versions,
+            virtual_handles: Mutex::new(HashMap::new()),
+            cache,
+            content,
+            journal,
+            options,
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            quota: Mutex::new(None),
+            sync_token: Mutex::new(None),
+            sync_supported: AtomicBool::new(true),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.options.poll_interval
+    }
+
+    fn dav_path(&self, path: &OsStr) -> Result<String> {
+        dav_path(&self.options.root, path)
+    }
+
+    // oc:fileid of a file or folder, inode numbers are derived from it. Items in the trash
+    // bin and versions keep theirs, they would clash with the current ones.
+    pub async fn file_id(&self, path: &OsStr) -> Option<u64> {
+        if self.is_virtual(path) {
+            return None;
+        }
+        let path = self.dav_path(path).ok()?;
+        self.item(&path).await.ok()?.file_id()
+    }
+
+    // File ids of the items in the folder at path, from the listing a readdir just put in
+    // the cache
+    pub fn file_ids(&self, path: &OsStr) -> HashMap<OsString, Option<u64>> {
+        let children = self
+            .dav_path(path)
+            .ok()
+            .and_then(|path| self.cache.children(&path))
+            .unwrap_or_default();
+        children
+            .iter()
+            .map(|item| (OsString::from(item.name()), item.file_id()))
+            .collect()
+    }
+
+    fn attr(&self, item: &DavItem) -> FileAttr {
+        let mtime = item.last_modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let permissions = Permissions::of(item);
+        // Read-only shares show up as 0555 and 0444. A folder that cannot be deleted gets
+        // the sticky bit, like /tmp its entries cannot just be removed either.
+        let (kind, mode, size, nlink) = match item {
+            DavItem::Folder(_) => {
+                let mut mode = if permissions.can_create() {
+                    0o777
+                } else {
+                    0o555
+                };
+                if !permissions.delete {
+                    mode |= libc::S_ISVTX;
+                }
+                (FileType::Directory, mode, 0, 2)
+            }
+            DavItem::File(file) => {
+                let mode = if permissions.write { 0o666 } else { 0o444 };
+                (FileType::RegularFile, mode, file.size, 1)
+            }
+        };
+        let mut perm = mode & !self.options.umask;
+        if self.options.read_only {
+            perm &= !0o222;
+        }
+
+        FileAttr {
+            size,
+            blocks: size.div_ceil(512),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            kind,
+            perm: perm as u16,
+            nlink,
+            uid: self.options.uid,
+            gid: self.options.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+        }
+    }
+
+    // Metadata of a file or folder, from memory when it is recent enough
+    async fn item(&self, path: &str) -> Result<DavItem> {
+        match self.cache.get(path) {
+            Lookup::Found(item) => return Ok(*item),
+            Lookup::Missing => return Err(libc::ENOENT.into()),
+            Lookup::Unknown => (),
+        }
+
+        // Revalidating the whole parent folder costs no more than a stat of the item
+        if let Some(parent) = parent(path) {
+            if self.cache.listing_etag(parent).is_some() {
+                self.folder(parent).await?;
+                match self.cache.get(path) {
+                    Lookup::Found(item) => return Ok(*item),
+                    Lookup::Missing => return Err(libc::ENOENT.into()),
+                    Lookup::Unknown => (),
+                }
+            }
+        }
+
+        match self.client.stat(path).await {
+            Ok(item) => {
+                self.cache.insert(path, Some(item.clone()));
+                Ok(item)
+            }
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 404 => {
+                self.cache.insert(path, None);
+                Err(libc::ENOENT.into())
+            }
+            Err(error) if self.serves_offline(&error) => match self.cache.get_stale(path) {
+                Lookup::Found(item) => Ok(*item),
+                Lookup::Missing => Err(libc::ENOENT.into()),
+                Lookup::Unknown => Err(errno(error)),
+            },
+            Err(error) => Err(errno(error)),
+        }
+    }
+
+    fn serves_offline(&self, error: &DavError) -> bool {
+        self.options.offline != OfflineMode::Off && error.is_offline()
+    }
+
+    // Quota of the mount root, the last one is kept while the server cannot be reached
+    async fn quota(&self) -> Result<Quota> {
+        let cached = *self.quota.lock().unwrap();
+        if let Some((fetched, quota)) = cached {
+            if fetched.elapsed() < QUOTA_TTL {
+                return Ok(quota);
+            }
+        }
+
+        match self.client.quota(&self.options.root).await {
+            Ok(quota) => {
+                *self.quota.lock().unwrap() = Some((Instant::now(), quota));
+                Ok(quota)
+            }
+            Err(error) => match cached {
+                Some((_, quota)) if self.serves_offline(&error) => Ok(quota),
+                _ => Err(errno(error)),
+            },
+        }
+    }
+
+    // ENOSPC if uploading size bytes is known to fail. Like the server, the whole upload is
+    // compared with the free space, not just what it adds to a file being replaced.
+    async fn check_space(&self, size: u64) -> Result<()> {
+        // Without a quota the upload itself will tell
+        let available = match self.quota().await {
+            Ok(quota) => quota.available.bytes(),
+            Err(_) => None,
+        };
+
+        match available {
+            Some(available) if size > available => Err(libc::ENOSPC.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Whatever was listed last, if the server cannot be asked
+    fn offline_folder(&self, path: &str, error: DavError) -> Result<Vec<DavItem>> {
+        match self.cache.stale_children(path) {
+            Some(children) if self.serves_offline(&error) => Ok(children),
+            _ => Err(errno(error)),
+        }
+    }
+
+    // Contents of a folder. An expired listing is kept if the folder's etag is unchanged.
+    async fn folder(&self, path: &str) -> Result<Vec<DavItem>> {
+        if let Some(children) = self.cache.children(path) {
+            return Ok(children);
+        }
+
+        if let Some(etag) = self.cache.listing_etag(path) {
+            let folder = match self.client.stat(path).await {
+                Ok(folder) => folder,
+                Err(error) => return self.offline_folder(path, error),
+            };
+            if folder.etag() == Some(etag.as_str()) {
+                self.cache.revalidate(path, folder);
+                if let Some(children) = self.cache.children(path) {
+                    return Ok(children);
+                }
+            }
+        }
+
+        let (folder, children) = match self.client.listing(&folder_path(path)).await {
+            Ok(listing) => listing,
+            Err(error) => return self.offline_folder(path, error),
+        };
+        self.cache.insert_listing(path, folder, &children);
+
+        Ok(children)
+    }
+
+    // Read through the content cache, only blocks that were not read before are fetched.
+    // Files with an upload in the journal are read from its copy.
+    async fn read_remote(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>> {
+        if let Some(data) = self
+            .journal
+            .as_ref()
+            .and_then(|journal| journal.pending_upload(path))
+        {
+            let file = std::fs::File::open(data).map_err(io_errno)?;
+            return staging::read_at(&file, offset, size as usize).map_err(io_errno);
+        }
+
+        let item = self.item(path).await?;
+        let (content, etag, file_size) = match (&self.content, &item) {
+            (
+                Some(content),
+                DavItem::File(File {
+                    etag: Some(etag),
+                    size,
+                    ..
+                }),
+            ) => (content, etag.as_str(), *size),
+            _ => return self.client.read(path, offset, size).await.map_err(errno),
+        };
+        let cached = content.open(path, etag, file_size).map_err(io_errno)?;
+
+        // Runs of consecutive missing blocks are fetched with one range request each
+        let missing = cached.missing(offset, size);
+        for run in missing.chunk_by(|a, b| a + 1 == *b) {
+            let (start, _) = cached.block_range(run[0]);
+            let (last_start, last_length) = cached.block_range(run[run.len() - 1]);
+            let length = last_start + last_length - start;
+
+            let data = self.client.read(path, start, length).await.map_err(errno)?;
+            // The file changed since its metadata was fetched
+            if data.len() as u64 != length {
+                self.cache.invalidate(path);
+                content.remove(path);
+                return self.client.read(path, offset, size).await.map_err(errno);
+            }
+
+            for block in run {
+                let (block_start, block_length) = cached.block_range(*block);
+                let from = (block_start - start) as usize;
+                cached
+                    .store(*block, &data[from..from + block_length as usize])
+                    .map_err(io_errno)?;
+            }
+        }
+        content.evict();
+
+        cached.read_at(offset, size).map_err(io_errno)
+    }
+
+    // Bring the cache up to date with the server, e.g. metadata remembered from an earlier
+    // mount. Folders whose etag is unchanged are current with everything below them, only
+    // the others are listed again. Returns what was found to have changed.
+    async fn refresh(&self) -> Result<Vec<Change>> {
+        let root = self.dav_path(OsStr::new("/"))?;
+        let mut changes = Vec::new();
+        if self.cache.listing_etag(&root).is_none() {
+            return Ok(changes);
+        }
+
+        let mut pending = vec![root];
+        while let Some(path) = pending.pop() {
+            let previous = self.cache.listed_subfolders(&path);
+            let before = self.cache.stale_children(&path).unwrap_or_default();
+            let (folder, contents) = match self.client.listing(&folder_path(&path)).await {
+                Ok(listing) => listing,
+                // Keep everything for offline use, it is refreshed once it expires
+                Err(error) if error.is_offline() => {
+                    log::info!("offline, not refreshing the cache");
+                    return Ok(changes);
+                }
+                Err(error) => {
+                    log::debug!("forgetting {}: {:?}", path, error);
+                    self.cache.invalidate(&path);
+                    continue;
+                }
+            };
+
+            for item in &contents {
+                let change = match before.iter().find(|old| old.path() == item.path()) {
+                    None => Change::Added,
+                    Some(old) if old.etag() != item.etag() => {
+                        self.forget_content(item.path());
+                        Change::Modified
                     }
-                });
+                    Some(_) => continue,
+                };
+                changes.extend(self.change(change, item.path()));
+            }
+            for old in &before {
+                if !contents.iter().any(|item| item.path() == old.path()) {
+                    self.forget_content(old.path());
+                    changes.extend(self.change(Change::Removed, old.path()));
+                }
+            }
+
+            for (subfolder, etag) in previous {
+                let current = contents.iter().find(|item| item.path() == subfolder);
+                if current.and_then(|item| item.etag()) != etag.as_deref() {
+                    pending.push(subfolder);
+                }
+            }
+            self.cache.insert_listing(&path, folder, &contents);
+        }
+
+        Ok(changes)
+    }
+
+    fn change(&self, change: fn(OsString) -> Change, path: &str) -> Option<Change> {
+        fuse_path(&self.options.root, path).map(change)
+    }
+
+    // Changes below the mount root since the last sync-collection. The first one only
+    // tells where to start from.
+    async fn sync_changes(&self, root: &str) -> std::result::Result<Vec<Change>, DavError> {
+        let token = self.sync_token.lock().unwrap().clone();
+        let sync = self.client.sync_collection(root, token.as_deref()).await?;
+        *self.sync_token.lock().unwrap() = Some(sync.token);
+        let mut changes = Vec::new();
+        if token.is_none() {
+            return Ok(changes);
+        }
+
+        for item in sync.changed {
+            let path = item.path().to_string();
+            let change = match self.cache.get_stale(&path) {
+                Lookup::Found(old) if old.etag() == item.etag() => continue,
+                Lookup::Found(_) => {
+                    self.forget_content(&path);
+                    Change::Modified
+                }
+                // The listing of the parent misses it
+                _ => {
+                    self.cache.invalidate(&path);
+                    Change::Added
+                }
+            };
+            self.cache.insert(&path, Some(item));
+            changes.extend(self.change(change, &path));
+        }
+        for path in sync.removed {
+            self.cache.invalidate(&path);
+            self.forget_content(&path);
+            changes.extend(self.change(Change::Removed, &path));
+        }
+
+        Ok(changes)
+    }
 
-                Ok(ReplyDirectory::new(entries))
+    // What changed on the server since the last call, so the kernel can drop it from its
+    // caches. Servers without sync-collection are polled for the etag of the mount root,
+    // which changes with anything below it.
+    pub async fn changes(&self) -> Result<Vec<Change>> {
+        let root = self.dav_path(OsStr::new("/"))?;
+        if self.sync_supported.load(Ordering::Relaxed) {
+            match self.sync_changes(&root).await {
+                Ok(changes) => return Ok(changes),
+                Err(error) if error.is_offline() => return Ok(Vec::new()),
+                Err(error @ (DavError::UnexpectedStatus(_) | DavError::NoContent)) => {
+                    log::info!("no sync-collection, polling the root etag: {:?}", error);
+                    self.sync_supported.store(false, Ordering::Relaxed);
+                }
+                Err(error) => return Err(errno(error)),
             }
-            Err(_) => Err(Errno::ENOENT),
         }
+
+        let etag = match self.cache.listing_etag(&root) {
+            Some(etag) => etag,
+            // Nothing listed yet, so nothing to be out of date
+            None => return Ok(Vec::new()),
+        };
+        match self.client.stat(&root).await {
+            Ok(folder) if folder.etag() == Some(etag.as_str()) => Ok(Vec::new()),
+            Ok(_) => self.refresh().await,
+            Err(error) if error.is_offline() => Ok(Vec::new()),
+            Err(error) => Err(errno(error)),
+        }
+    }
+
+    fn forget_content(&self, path: &str) {
+        if let Some(content) = &self.content {
+            content.remove(path);
+        }
+    }
+
+    async fn stat(&self, path: &OsStr) -> Result<FileAttr> {
+        if let Some((trash, path)) = self.trash_path(path) {
+            return self.trash_stat(trash, &path).await;
+        }
+        if let Some((versions, path)) = self.versions_path(path) {
+            return self.version_stat(versions, &path).await;
+        }
+        let path = self.dav_path(path)?;
+        let item = self.item(&path).await?;
+        if self.excluded(&path, &item) {
+            return Err(libc::ENOENT.into());
+        }
+
+        Ok(self.attr(&item))
+    }
+
+    // Whether the exclude patterns or the allow and deny lists hide a file or folder
+    fn excluded(&self, path: &str, item: &DavItem) -> bool {
+        let folder = matches!(item, DavItem::Folder(_));
+        self.is_excluded(path, folder)
+    }
+
+    fn is_excluded(&self, path: &str, folder: bool) -> bool {
+        let relative = fuse_path(&self.options.root, path).unwrap_or_default();
+        self.options
+            .excludes
+            .excludes(path, &relative.to_string_lossy(), folder)
+    }
+
+    // The trash bin and the path of shown names below /.trash, "" for /.trash itself
+    fn trash_path(&self, path: &OsStr) -> Option<(&Trash, String)> {
+        Some((self.trash.as_ref()?, below(path, trash::NAME)?))
+    }
+
+    // The versions folder and the path below /.versions
+    fn versions_path(&self, path: &OsStr) -> Option<(&Versions, String)> {
+        Some((self.versions.as_ref()?, below(path, versions::NAME)?))
+    }
+
+    // Whether a path is in the trash bin or the versions folder, nothing there can be
+    // written
+    fn is_virtual(&self, path: &OsStr) -> bool {
+        self.trash_path(path).is_some() || self.versions_path(path).is_some()
+    }
+
+    async fn trashed(&self, trash: &Trash, path: &str) -> Result<TrashItem> {
+        match trash.find(path).await {
+            Ok(Some(trashed)) => Ok(trashed),
+            Ok(None) => Err(libc::ENOENT.into()),
+            Err(error) => Err(errno(error)),
+        }
+    }
+
+    // Nothing in the trash bin can be changed, only restored or purged. ctime is when an
+    // item was deleted.
+    fn trash_attr(&self, trashed: Option<&TrashItem>) -> FileAttr {
+        let mut attr = match trashed {
+            Some(trashed) => self.attr(&trashed.item),
+            None => self.attr(&DavItem::Folder(Folder {
+                name: trash::NAME.to_string(),
+                path: trash::NAME.to_string(),
+                file_id: None,
+                etag: None,
+                last_modified: None,
+                permissions: None,
+                lock: None,
+            })),
+        };
+        attr.perm &= 0o555;
+        if let Some(deleted) = trashed.and_then(|trashed| trashed.deleted) {
+            attr.ctime = deleted;
+        }
+        attr
+    }
+
+    async fn trash_stat(&self, trash: &Trash, path: &str) -> Result<FileAttr> {
+        if path.is_empty() {
+            return Ok(self.trash_attr(None));
+        }
+        let trashed = self.trashed(trash, path).await?;
+
+        Ok(self.trash_attr(Some(&trashed)))
+    }
+
+    // A path below /.versions is a folder or a file with its path, or else one of the
+    // versions of its parent
+    async fn version_path(&self, versions: &Versions, path: &str) -> Result<VersionPath> {
+        let dav = self.dav_path(OsStr::new(path))?;
+        let error = match self.item(&dav).await {
+            Ok(item) if self.excluded(&dav, &item) => Errno::from(libc::ENOENT),
+            Ok(DavItem::Folder(_)) => return Ok(VersionPath::Folder(dav)),
+            Ok(DavItem::File(file)) => {
+                let file_id = file.file_id.ok_or_else(|| Errno::from(libc::ENOENT))?;
+                return Ok(VersionPath::File(dav, file_id));
+            }
+            Err(error) => error,
+        };
+
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.dav_path(OsStr::new(parent))?;
+        let file_id = match self.item(&parent).await {
+            Ok(DavItem::File(File {
+                file_id: Some(file_id),
+                ..
+            })) => file_id,
+            _ => return Err(error),
+        };
+        match versions.find(file_id, name).await.map_err(errno)? {
+            Some(version) => Ok(VersionPath::Version(parent, file_id, Box::new(version))),
+            None => Err(error),
+        }
+    }
+
+    // Versions can only be read, files are shown as the folders of their versions
+    fn version_attr(&self, item: &DavItem) -> FileAttr {
+        let mut attr = self.attr(item);
+        attr.perm &= 0o555;
+        if let DavItem::File(_) = item {
+            attr.kind = FileType::Directory;
+            attr.perm |= 0o111;
+            attr.size = 0;
+            attr.blocks = 0;
+            attr.nlink = 2;
+        }
+        attr
+    }
+
+    async fn version_stat(&self, versions: &Versions, path: &str) -> Result<FileAttr> {
+        match self.version_path(versions, path).await? {
+            VersionPath::Folder(path) | VersionPath::File(path, _) => {
+                Ok(self.version_attr(&self.item(&path).await?))
+            }
+            VersionPath::Version(_, _, version) => Ok(self.version_attr(&version.item)),
+        }
+    }
+
+    async fn version_entries(
+        &self,
+        versions: &Versions,
+        path: &str,
+    ) -> Result<Vec<(OsString, FileAttr)>> {
+        match self.version_path(versions, path).await? {
+            VersionPath::Folder(path) => Ok(self
+                .folder(&path)
+                .await?
+                .iter()
+                .filter(|item| !self.excluded(item.path(), item))
+                .map(|item| (OsString::from(item.name()), self.version_attr(item)))
+                .collect()),
+            VersionPath::File(_, file_id) => Ok(versions
+                .list(file_id)
+                .await
+                .map_err(errno)?
+                .iter()
+                .map(|(name, version)| (OsString::from(name), self.version_attr(&version.item)))
+                .collect()),
+            VersionPath::Version(..) => Err(libc::ENOTDIR.into()),
+        }
+    }
+
+    // mv of a version over its file restores it. Anywhere else it fails with EXDEV, so mv
+    // copies it instead.
+    async fn restore_version(&self, versions: &Versions, from: &str, to: &OsStr) -> Result<()> {
+        let (path, file_id, version) = match self.version_path(versions, from).await? {
+            VersionPath::Version(path, file_id, version) => (path, file_id, version),
+            _ => return Err(libc::EXDEV.into()),
+        };
+        if self.dav_path(to)? != path {
+            return Err(libc::EXDEV.into());
+        }
+        require(
+            Permissions::of(&self.item(&path).await?).write,
+            libc::EACCES,
+        )?;
+
+        self.client
+            .restore_version(file_id, version.item.name())
+            .await
+            .map_err(errno)?;
+        versions.forget(file_id);
+        self.forget_content(&path);
+        self.cache.invalidate(&path);
+        Ok(())
+    }
+
+    // rm and rmdir in the trash bin delete for good
+    async fn purge(&self, trash: &Trash, path: &str, folder: bool) -> Result<()> {
+        if path.is_empty() {
+            return Err(libc::EBUSY.into());
+        }
+        let trashed = self.trashed(trash, path).await?;
+        match (&trashed.item, folder) {
+            (DavItem::Folder(_), false) => return Err(libc::EISDIR.into()),
+            (DavItem::File(_), true) => return Err(libc::ENOTDIR.into()),
+            (DavItem::Folder(inner), true) => {
+                if !trash.list(&inner.path).await.map_err(errno)?.is_empty() {
+                    return Err(libc::ENOTEMPTY.into());
+                }
+            }
+            (DavItem::File(_), false) => (),
+        }
+
+        self.client
+            .purge(trashed.item.path())
+            .await
+            .map_err(errno)?;
+        trash.forget();
+        Ok(())
+    }
+
+    // mv out of the trash bin restores. Nextcloud only restores an item where it was
+    // deleted from, it is moved on from there. Whatever cannot be restored like that fails
+    // with EXDEV, so mv copies it out instead.
+    async fn restore(&self, trash: &Trash, from: &str, to: &OsStr, no_replace: bool) -> Result<()> {
+        if from.is_empty() {
+            return Err(libc::EBUSY.into());
+        }
+        let trashed = self.trashed(trash, from).await?;
+        let location = match &trashed.original_location {
+            // Items inside a deleted folder have no location of their own
+            Some(location) if !from.contains('/') => location.trim_matches('/').to_string(),
+            _ => return Err(libc::EXDEV.into()),
+        };
+
+        let to = self.dav_path(to)?;
+        match self.item(&to).await {
+            Ok(_) if no_replace => return Err(libc::EEXIST.into()),
+            // The server would restore next to it under another name
+            Ok(_) => return Err(libc::EXDEV.into()),
+            Err(_) => (),
+        }
+        if location != to && self.item(&location).await.is_ok() {
+            return Err(libc::EXDEV.into());
+        }
+
+        self.client
+            .restore(trashed.item.path())
+            .await
+            .map_err(errno)?;
+        trash.forget();
+        self.cache.invalidate(&location);
+        if location != to {
+            self.client
+                .rename(&location, &to, false, None)
+                .await
+                .map_err(errno)?;
+            self.cache.invalidate(&to);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, fh: u64) -> Result<Arc<tokio::sync::Mutex<OpenFile>>> {
+        self.handles
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .cloned()
+            .ok_or_else(|| libc::EBADF.into())
+    }
+
+    fn add_handle(&self, file: OpenFile) -> u64 {
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles
+            .lock()
+            .unwrap()
+            .insert(fh, Arc::new(tokio::sync::Mutex::new(file)));
+        fh
+    }
+
+    fn add_virtual_handle(&self, file: VirtualFile) -> u64 {
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.virtual_handles.lock().unwrap().insert(fh, file);
+        fh
+    }
+
+    // A staged file for a file with size bytes of content, which is only copied in as it
+    // is needed
+    fn stage(&self, fh: u64, size: u64) -> Result<StagedFile> {
+        StagedFile::create(&self.options.staging_dir, fh, size).map_err(io_errno)
+    }
+
+    // Copy what is missing in offset..offset + size from the file on the server. Had it
+    // changed since it was opened, the upload conflicts and the mix is never put over
+    // their version unless our changes are meant to win anyway.
+    async fn fill(
+        &self,
+        path: &str,
+        staged: &mut StagedFile,
+        offset: u64,
+        size: u64,
+    ) -> Result<()> {
+        for range in staged.missing(offset, size) {
+            let mut start = range.start;
+            while start < range.end {
+                let length = (range.end - start).min(DOWNLOAD_SIZE);
+                let data = self.read_remote(path, start, length).await?;
+                staged.write_at(start, &data).map_err(io_errno)?;
+                // The file got shorter on the server, the rest stays zeros
+                if (data.len() as u64) < length {
+                    staged.forget(start..range.end);
+                    break;
+                }
+                start += length;
+            }
+        }
+        Ok(())
+    }
+
+    // Files open for writing below path get what they still need from the server before
+    // it goes away there
+    async fn fill_open_files(&self, path: &str) -> Result<()> {
+        let handles: Vec<_> = self.handles.lock().unwrap().values().cloned().collect();
+        for handle in handles {
+            let mut file = handle.lock().await;
+            let file = &mut *file;
+            match file.path.strip_prefix(path) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => (),
+                _ => continue,
+            }
+            if let (Some(staged), false) = (&mut file.staged, file.unlinked) {
+                self.fill(&file.path, staged, 0, u64::MAX).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Upload a staged file if it was written to since the last upload
+    async fn upload(&self, file: &mut OpenFile) -> Result<()> {
+        let staged = match (&mut file.staged, file.dirty && !file.unlinked) {
+            (Some(staged), true) => staged,
+            _ => return Ok(()),
+        };
+        self.fill(&file.path, staged, 0, u64::MAX).await?;
+        staged.sync().map_err(io_errno)?;
+
+        let operation = Operation::Upload {
+            path: file.path.clone(),
+            data: staged.path().to_path_buf(),
+            mtime: Some(file.mtime.unwrap_or_else(SystemTime::now)),
+            etag: file.etag.clone(),
+        };
+        let outcome = self
+            .modify(operation, |error| {
+                log::warn!("uploading {} failed: {:?}", file.path, error);
+                errno(error)
+            })
+            .await?;
+        file.dirty = false;
+
+        match outcome {
+            // Later changes go to the same copy
+            Outcome::Uploaded { path, etag } => {
+                file.path = path;
+                file.etag = etag;
+            }
+            Outcome::Discarded => {
+                self.forget_content(&file.path);
+                file.unlinked = true;
+            }
+            Outcome::Done | Outcome::Queued => (),
+        }
+        Ok(())
+    }
+
+    // Send a modification to the server. While it cannot be reached, and afterwards until
+    // everything queued meanwhile has been replayed, modifications go to the journal
+    // instead.
+    async fn modify(
+        &self,
+        operation: Operation,
+        map_error: impl Fn(DavError) -> Errno,
+    ) -> Result<Outcome> {
+        if let Some(journal) = self.journal.as_ref().filter(|journal| !journal.is_empty()) {
+            return self.queue(journal, operation).await;
+        }
+
+        match operation
+            .perform(&self.client, self.options.conflicts)
+            .await
+        {
+            Err(error) if error.is_offline() => match &self.journal {
+                Some(journal) => self.queue(journal, operation).await,
+                None if self.options.offline != OfflineMode::Off => Err(libc::EROFS.into()),
+                None => Err(map_error(error)),
+            },
+            result => {
+                // Whatever changed on the server changed the used space too
+                *self.quota.lock().unwrap() = None;
+                for path in operation.paths() {
+                    self.cache.invalidate(path);
+                }
+                if let Ok(Outcome::Uploaded { path, .. }) = &result {
+                    self.cache.invalidate(path);
+                }
+                result.map_err(map_error)
+            }
+        }
+    }
+
+    async fn queue(&self, journal: &Journal, operation: Operation) -> Result<Outcome> {
+        match journal.queue(operation).await {
+            Ok(()) => Ok(Outcome::Queued),
+            Err(error) => {
+                log::error!("cannot queue a modification: {}", error);
+                Err(io_errno(error))
+            }
+        }
+    }
+
+    // Attributes of an open file, the staged copy is newer than the server's
+    async fn open_file_attr(&self, file: &OpenFile) -> Result<FileAttr> {
+        let item = self.item(&file.path).await?;
+        let mut attr = self.attr(&item);
+
+        if let Some(staged) = &file.staged {
+            attr.size = staged.len().map_err(io_errno)?;
+            attr.blocks = attr.size.div_ceil(512);
+        }
+        if let Some(mtime) = file.mtime {
+            attr.mtime = mtime;
+        }
+        Ok(attr)
+    }
+
+    // Open files are identified by their path, which has to follow renames and removals
+    async fn update_open_files(&self, path: &str, update: impl Fn(&mut OpenFile, &str)) {
+        let handles: Vec<_> = self.handles.lock().unwrap().values().cloned().collect();
+        for handle in handles {
+            let mut file = handle.lock().await;
+            let rest = match file.path.strip_prefix(path) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.to_string(),
+                _ => continue,
+            };
+            update(&mut file, &rest);
+        }
+    }
+
+    async fn list(&self, path: &OsStr) -> Result<Vec<DavItem>> {
+        self.folder(&self.dav_path(path)?).await
+    }
+
+    // Names and attributes of the contents of a folder
+    async fn entries(&self, path: &OsStr) -> Result<Vec<(OsString, FileAttr)>> {
+        if let Some((trash, path)) = self.trash_path(path) {
+            let folder = match path.as_str() {
+                "" => String::new(),
+                path => match self.trashed(trash, path).await?.item {
+                    DavItem::Folder(folder) => folder.path,
+                    DavItem::File(_) => return Err(libc::ENOTDIR.into()),
+                },
+            };
+            let items = trash.list(&folder).await.map_err(errno)?;
+            return Ok(items
+                .iter()
+                .map(|(name, trashed)| (OsString::from(name), self.trash_attr(Some(trashed))))
+                .collect());
+        }
+        if let Some((versions, path)) = self.versions_path(path) {
+            return self.version_entries(versions, &path).await;
+        }
+
+        let mut entries: Vec<(OsString, FileAttr)> = self
+            .list(path)
+            .await?
+            .iter()
+            .filter(|item| !self.excluded(item.path(), item))
+            .map(|item| (OsString::from(item.name()), self.attr(item)))
+            .collect();
+        if Path::new(path) != Path::new("/") {
+            return Ok(entries);
+        }
+        if self.trash.is_some() {
+            entries.retain(|(name, _)| name != trash::NAME);
+            entries.push((OsString::from(trash::NAME), self.trash_attr(None)));
+        }
+        if let Some(versions) = &self.versions {
+            entries.retain(|(name, _)| name != versions::NAME);
+            let attr = self.version_stat(versions, "").await?;
+            entries.push((OsString::from(versions::NAME), attr));
+        }
+        Ok(entries)
+    }
+}
+
+impl PathFilesystem for NextcloudFilesystem {
+    type DirEntryStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntry>>>
+    where
+        Self: 'a;
+    type DirEntryPlusStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntryPlus>>>
+    where
+        Self: 'a;
+
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        // Nothing the kernel could have cached yet
+        if let Err(error) = self.refresh().await {
+            log::warn!("cannot refresh the cache: {:?}", error);
+        }
+        if let Some(journal) = &self.journal {
+            tokio::spawn(journal.clone().run());
+        }
+
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(128 * 1024).unwrap(),
+        })
+    }
+
+    async fn destroy(&self, _req: Request) {}
+
+    async fn lookup(&self, _req: Request, parent: &OsStr, name: &OsStr) -> Result<ReplyEntry> {
+        let attr = self.stat(&join(parent, name)).await?;
+
+        Ok(ReplyEntry { ttl: TTL, attr })
+    }
+
+    async fn getattr(
+        &self,
+        _req: Request,
+        path: Option<&OsStr>,
+        fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        if let Some(handle) = fh.and_then(|fh| self.handle(fh).ok()) {
+            let file = handle.lock().await;
+            if file.staged.is_some() {
+                let attr = self.open_file_attr(&file).await?;
+                return Ok(ReplyAttr { ttl: TTL, attr });
+            }
+        }
+
+        let path = path.ok_or_else(|| Errno::from(libc::ENOENT))?;
+        let attr = self.stat(path).await?;
+
+        Ok(ReplyAttr { ttl: TTL, attr })
+    }
+
+    async fn setattr(
+        &self,
+        _req: Request,
+        path: Option<&OsStr>,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        let changes_content = set_attr.size.is_some() || set_attr.mtime.is_some();
+        if self.options.read_only && changes_content {
+            return Err(libc::EROFS.into());
+        }
+        if let Some(path) = path.filter(|path| self.is_virtual(path)) {
+            if changes_content {
+                return Err(libc::EROFS.into());
+            }
+            let attr = self.stat(path).await?;
+            return Ok(ReplyAttr { ttl: TTL, attr });
+        }
+
+        // Truncating a file that is not open, e.g. truncate(1), goes through a temporary
+        // handle
+        let (handle, temporary) = match fh.and_then(|fh| self.handle(fh).ok()) {
+            Some(handle) => (handle, None),
+            None if set_attr.size.is_some() => {
+                let path = self.dav_path(path.ok_or_else(|| Errno::from(libc::ENOENT))?)?;
+                let item = self.item(&path).await?;
+                require(Permissions::of(&item).write, libc::EACCES)?;
+                let size = match &item {
+                    DavItem::File(file) => file.size,
+                    DavItem::Folder(_) => return Err(libc::EISDIR.into()),
+                };
+                let etag = item.etag().map(str::to_string);
+                let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+                let staged = self.stage(fh, size)?;
+                let fh = self.add_handle(OpenFile {
+                    path,
+                    staged: Some(staged),
+                    dirty: false,
+                    unlinked: false,
+                    etag,
+                    mtime: None,
+                });
+                (self.handle(fh)?, Some(fh))
+            }
+            // Ownership and modes are fixed by the mount options and modification times
+            // of files that are not open cannot be changed without an upload
+            None => {
+                let path = path.ok_or_else(|| Errno::from(libc::ENOENT))?;
+                let attr = self.stat(path).await?;
+                return Ok(ReplyAttr { ttl: TTL, attr });
+            }
+        };
+
+        let mut file = handle.lock().await;
+        if let Some(size) = set_attr.size {
+            let staged = file
+                .staged
+                .as_mut()
+                .ok_or_else(|| Errno::from(libc::EBADF))?;
+            staged.set_len(size).map_err(io_errno)?;
+            file.dirty = true;
+        }
+        if let Some(mtime) = set_attr.mtime {
+            file.mtime = Some(system_time(mtime));
+            file.dirty |= file.staged.is_some();
+        }
+
+        let result = match temporary {
+            Some(_) => self.upload(&mut file).await,
+            None => Ok(()),
+        };
+        let attr = self.open_file_attr(&file).await;
+        drop(file);
+        if let Some(fh) = temporary {
+            self.handles.lock().unwrap().remove(&fh);
+        }
+        result?;
+
+        Ok(ReplyAttr {
+            ttl: TTL,
+            attr: attr?,
+        })
+    }
+
+    async fn open(&self, _req: Request, path: &OsStr, flags: u32) -> Result<ReplyOpen> {
+        let writable = is_writable(flags);
+        if writable && self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        if let Some((trash, path)) = self.trash_path(path) {
+            if writable {
+                return Err(libc::EROFS.into());
+            }
+            if path.is_empty() {
+                return Err(libc::EISDIR.into());
+            }
+            let trashed = self.trashed(trash, &path).await?;
+            if let DavItem::Folder(_) = trashed.item {
+                return Err(libc::EISDIR.into());
+            }
+            let file = VirtualFile::Trashed(trashed.item.path().to_string());
+            return Ok(ReplyOpen {
+                fh: self.add_virtual_handle(file),
+                flags: 0,
+            });
+        }
+        if let Some((versions, path)) = self.versions_path(path) {
+            if writable {
+                return Err(libc::EROFS.into());
+            }
+            let file = match self.version_path(versions, &path).await? {
+                VersionPath::Version(_, file_id, version) => {
+                    VirtualFile::Version(file_id, version.item.name().to_string())
+                }
+                _ => return Err(libc::EISDIR.into()),
+            };
+            return Ok(ReplyOpen {
+                fh: self.add_virtual_handle(file),
+                flags: 0,
+            });
+        }
+
+        let path = self.dav_path(path)?;
+        let item = self.item(&path).await?;
+        if writable {
+            require(Permissions::of(&item).write, libc::EACCES)?;
+        }
+        let (etag, size) = match item {
+            DavItem::Folder(_) => return Err(libc::EISDIR.into()),
+            DavItem::File(file) => (file.etag, file.size),
+        };
+
+        let truncate = flags as i32 & libc::O_TRUNC != 0;
+        let staged = if writable {
+            let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            Some(self.stage(fh, if truncate { 0 } else { size })?)
+        } else {
+            None
+        };
+        let dirty = truncate;
+
+        let fh = self.add_handle(OpenFile {
+            path,
+            staged,
+            dirty,
+            unlinked: false,
+            etag,
+            mtime: None,
+        });
+        Ok(ReplyOpen { fh, flags: 0 })
+    }
+
+    async fn create(
+        &self,
+        _req: Request,
+        parent: &OsStr,
+        name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+    ) -> Result<ReplyCreated> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        if self.is_virtual(parent) {
+            return Err(libc::EROFS.into());
+        }
+
+        let folder = self.item(&self.dav_path(parent)?).await?;
+        require(Permissions::of(&folder).create_file, libc::EACCES)?;
+        // There is no point in creating a file that cannot get any contents
+        self.check_space(1).await?;
+
+        // The empty file is uploaded right away so it can be looked up before it is closed.
+        // It is not put over a file someone else created meanwhile.
+        let path = self.dav_path(&join(parent, name))?;
+        if self.is_excluded(&path, false) {
+            return Err(libc::EPERM.into());
+        }
+        let staged_id = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let staged = self.stage(staged_id, 0)?;
+        let operation = Operation::Create {
+            path: path.clone(),
+            data: staged.path().to_path_buf(),
+        };
+        let etag = match self.modify(operation, errno).await? {
+            Outcome::Uploaded { etag, .. } => etag,
+            _ => None,
+        };
+
+        let file = OpenFile {
+            path,
+            staged: Some(staged),
+            dirty: false,
+            unlinked: false,
+            etag,
+            mtime: None,
+        };
+        let attr = self.open_file_attr(&file).await?;
+        let fh = self.add_handle(file);
+
+        Ok(ReplyCreated {
+            ttl: TTL,
+            attr,
+            generation: 0,
+            fh,
+            flags: 0,
+        })
+    }
+
+    async fn read(
+        &self,
+        _req: Request,
+        path: Option<&OsStr>,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let file = self.virtual_handles.lock().unwrap().get(&fh).cloned();
+        if let Some(file) = file {
+            let data = match file {
+                VirtualFile::Trashed(path) => {
+                    self.client.read_trash(&path, offset, size as u64).await
+                }
+                VirtualFile::Version(file_id, name) => {
+                    self.client
+                        .read_version(file_id, &name, offset, size as u64)
+                        .await
+                }
+            };
+            return Ok(ReplyData {
+                data: Bytes::from(data.map_err(errno)?),
+            });
+        }
+        let handle = self.handle(fh)?;
+        let mut file = handle.lock().await;
+        let file = &mut *file;
+
+        let data = match &mut file.staged {
+            Some(staged) => {
+                self.fill(&file.path, staged, offset, size as u64).await?;
+                staged.read_at(offset, size as usize).map_err(io_errno)?
+            }
+            None => {
+                let path = match path {
+                    Some(path) => self.dav_path(path)?,
+                    None => file.path.clone(),
+                };
+                self.read_remote(&path, offset, size as u64).await?
+            }
+        };
+
+        Ok(ReplyData {
+            data: Bytes::from(data),
+        })
+    }
+
+    async fn write(
+        &self,
+        _req: Request,
+        _path: Option<&OsStr>,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: u32,
+    ) -> Result<ReplyWrite> {
+        let handle = self.handle(fh)?;
+        let mut file = handle.lock().await;
+
+        let staged = file
+            .staged
+            .as_mut()
+            .ok_or_else(|| Errno::from(libc::EBADF))?;
+        let size = staged.len().map_err(io_errno)?;
+        self.check_space(size.max(offset + data.len() as u64))
+            .await?;
+        staged.write_at(offset, data).map_err(io_errno)?;
+        file.dirty = true;
+
+        Ok(ReplyWrite {
+            written: data.len() as u32,
+        })
+    }
+
+    // Called on every close(2) of a descriptor, errors reach the closing process
+    async fn flush(
+        &self,
+        _req: Request,
+        path: Option<&OsStr>,
+        fh: u64,
+        _lock_owner: u64,
+    ) -> Result<()> {
+        if self.virtual_handles.lock().unwrap().contains_key(&fh) {
+            return Ok(());
+        }
+        let handle = self.handle(fh)?;
+        let mut file = handle.lock().await;
+        if let Some(path) = path {
+            file.path = self.dav_path(path)?;
+        }
+
+        self.upload(&mut file).await
+    }
+
+    async fn fsync(
+        &self,
+        req: Request,
+        path: Option<&OsStr>,
+        fh: u64,
+        _datasync: bool,
+    ) -> Result<()> {
+        self.flush(req, path, fh, 0).await
+    }
+
+    async fn release(
+        &self,
+        _req: Request,
+        path: Option<&OsStr>,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        if self.virtual_handles.lock().unwrap().remove(&fh).is_some() {
+            return Ok(());
+        }
+        let handle = match self.handles.lock().unwrap().remove(&fh) {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        let mut file = handle.lock().await;
+        if let Some(path) = path {
+            file.path = self.dav_path(path)?;
+        }
+
+        // Normally flush already uploaded everything
+        self.upload(&mut file).await
+    }
+
+    async fn mkdir(
+        &self,
+        _req: Request,
+        parent: &OsStr,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+    ) -> Result<ReplyEntry> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        if self.is_virtual(parent) {
+            return Err(libc::EROFS.into());
+        }
+
+        let folder = self.item(&self.dav_path(parent)?).await?;
+        require(Permissions::of(&folder).create_folder, libc::EACCES)?;
+
+        let path = join(parent, name);
+        let dav = self.dav_path(&path)?;
+        if self.is_excluded(&dav, true) {
+            return Err(libc::EPERM.into());
+        }
+        self.modify(Operation::Mkdir { path: dav }, errno).await?;
+        let attr = self.stat(&path).await?;
+
+        Ok(ReplyEntry { ttl: TTL, attr })
+    }
+
+    async fn unlink(&self, _req: Request, parent: &OsStr, name: &OsStr) -> Result<()> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        let path = join(parent, name);
+        if self.versions_path(&path).is_some() {
+            return Err(libc::EROFS.into());
+        }
+        if let Some((trash, path)) = self.trash_path(&path) {
+            return self.purge(trash, &path, false).await;
+        }
+
+        let path = self.dav_path(&join(parent, name))?;
+        let item = self.item(&path).await?;
+        if let DavItem::Folder(_) = item {
+            return Err(libc::EISDIR.into());
+        }
+        require(Permissions::of(&item).delete, libc::EPERM)?;
+        self.fill_open_files(&path).await?;
+        self.forget_content(&path);
+        let operation = Operation::Delete { path: path.clone() };
+        self.modify(operation, errno).await?;
+
+        self.update_open_files(&path, |file, rest| {
+            if rest.is_empty() {
+                file.unlinked = true;
+            }
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn rmdir(&self, _req: Request, parent: &OsStr, name: &OsStr) -> Result<()> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        let path = join(parent, name);
+        if self.versions_path(&path).is_some() {
+            return Err(libc::EROFS.into());
+        }
+        if let Some((trash, path)) = self.trash_path(&path) {
+            return self.purge(trash, &path, true).await;
+        }
+
+        if self.dav_path(&path)? == self.dav_path(OsStr::new("/"))? {
+            return Err(libc::EBUSY.into());
+        }
+        let item = self.item(&self.dav_path(&path)?).await?;
+        if let DavItem::File(_) = item {
+            return Err(libc::ENOTDIR.into());
+        }
+        require(Permissions::of(&item).delete, libc::EPERM)?;
+        // DELETE removes a folder with everything in it, rmdir(2) only empty ones
+        if !self.list(&path).await?.is_empty() {
+            return Err(libc::ENOTEMPTY.into());
+        }
+
+        let path = self.dav_path(&path)?;
+        self.modify(Operation::Delete { path }, errno).await?;
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        req: Request,
+        origin_parent: &OsStr,
+        origin_name: &OsStr,
+        parent: &OsStr,
+        name: &OsStr,
+    ) -> Result<()> {
+        self.rename2(req, origin_parent, origin_name, parent, name, 0)
+            .await
+    }
+
+    async fn rename2(
+        &self,
+        _req: Request,
+        origin_parent: &OsStr,
+        origin_name: &OsStr,
+        parent: &OsStr,
+        name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        // There is no way to swap two resources atomically over DAV
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(libc::EINVAL.into());
+        }
+        let no_replace = flags & libc::RENAME_NOREPLACE != 0;
+
+        let from_path = join(origin_parent, origin_name);
+        let to_path = join(parent, name);
+        if self.is_virtual(&to_path) {
+            return Err(libc::EROFS.into());
+        }
+        if let Some((trash, from)) = self.trash_path(&from_path) {
+            return self.restore(trash, &from, &to_path, no_replace).await;
+        }
+        if let Some((versions, from)) = self.versions_path(&from_path) {
+            return self.restore_version(versions, &from, &to_path).await;
+        }
+
+        let from = self.dav_path(&from_path)?;
+        let to = self.dav_path(&to_path)?;
+        if from == to {
+            return Ok(());
+        }
+
+        // MOVE replaces anything, rename(2) only replaces files with files and folders
+        // with empty folders
+        let source = self.item(&from).await?;
+        if self.excluded(&to, &source) {
+            return Err(libc::EPERM.into());
+        }
+        let permissions = Permissions::of(&source);
+        if parent == origin_parent {
+            require(permissions.rename, libc::EPERM)?;
+        } else {
+            require(permissions.r#move, libc::EPERM)?;
+            let folder = Permissions::of(&self.item(&self.dav_path(parent)?).await?);
+            let allowed = match source {
+                DavItem::Folder(_) => folder.create_folder,
+                DavItem::File(_) => folder.create_file,
+            };
+            require(allowed, libc::EACCES)?;
+        }
+        if !no_replace {
+            match (&source, self.item(&to).await) {
+                (DavItem::Folder(_), Ok(DavItem::File(_))) => return Err(libc::ENOTDIR.into()),
+                (DavItem::File(_), Ok(DavItem::Folder(_))) => return Err(libc::EISDIR.into()),
+                (DavItem::Folder(_), Ok(DavItem::Folder(_)))
+                    if !self.list(&to_path).await?.is_empty() =>
+                {
+                    return Err(libc::ENOTEMPTY.into())
+                }
+                _ => (),
+            }
+        }
+
+        if !no_replace {
+            self.fill_open_files(&to).await?;
+        }
+        self.forget_content(&from);
+        self.forget_content(&to);
+        let operation = Operation::Rename {
+            from: from.clone(),
+            to: to.clone(),
+            overwrite: !no_replace,
+            // Folder etags change with anything below them, only files are checked
+            etag: match source {
+                DavItem::File(file) => file.etag,
+                DavItem::Folder(_) => None,
+            },
+        };
+        let outcome = self
+            .modify(operation, |error| match error {
+                // Nextcloud answers moves between storages it cannot do with Bad Gateway
+                DavError::UnexpectedStatus(status) if status.as_u16() == 502 => {
+                    Errno::from(libc::EXDEV)
+                }
+                error => errno(error),
+            })
+            .await?;
+        if let Outcome::Discarded = outcome {
+            return Err(libc::ESTALE.into());
+        }
+
+        self.update_open_files(&to, |file, _| file.unlinked = true)
+            .await;
+        self.update_open_files(&from, |file, rest| {
+            file.path = format!("{}{}", to, rest);
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn opendir(&self, _req: Request, path: &OsStr, _flags: u32) -> Result<ReplyOpen> {
+        match self.stat(path).await?.kind {
+            FileType::Directory => Ok(ReplyOpen { fh: 0, flags: 0 }),
+            _ => Err(libc::ENOTDIR.into()),
+        }
+    }
+
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        path: &'a OsStr,
+        _fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
+        let items = self.entries(path).await?;
+
+        let mut entries = vec![
+            (FileType::Directory, OsString::from(".")),
+            (FileType::Directory, OsString::from("..")),
+        ];
+        entries.extend(items.into_iter().map(|(name, attr)| (attr.kind, name)));
+
+        // Offsets are 1 based, an entry's offset is where the next readdir call continues
+        let entries: Vec<Result<DirectoryEntry>> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(index, (kind, name))| {
+                Ok(DirectoryEntry {
+                    kind,
+                    name,
+                    offset: index as i64 + 1,
+                })
+            })
+            .collect();
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries),
+        })
+    }
+
+    async fn readdirplus<'a>(
+        &'a self,
+        _req: Request,
+        parent: &'a OsStr,
+        _fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'a>>> {
+        let folder = self.stat(parent).await?;
+        let items = self.entries(parent).await?;
+
+        // The parent's attributes are not worth a request, the kernel only uses them for
+        // the inode number
+        let mut entries = vec![
+            (OsString::from("."), folder),
+            (OsString::from(".."), folder),
+        ];
+        entries.extend(items);
+
+        let entries: Vec<Result<DirectoryEntryPlus>> = entries
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .map(|(index, (name, attr))| {
+                Ok(DirectoryEntryPlus {
+                    kind: attr.kind,
+                    name,
+                    offset: index as i64 + 1,
+                    attr,
+                    entry_ttl: TTL,
+                    attr_ttl: TTL,
+                })
+            })
+            .collect();
+
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(entries),
+        })
+    }
+
+    async fn releasedir(&self, _req: Request, _path: &OsStr, _fh: u64, _flags: u32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn getxattr(
+        &self,
+        _req: Request,
+        path: &OsStr,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let name = name.to_str().ok_or_else(|| Errno::from(libc::ENODATA))?;
+        if self.is_virtual(path) {
+            return Err(libc::ENODATA.into());
+        }
+        let path = self.dav_path(path)?;
+
+        let value = if name.starts_with(xattr::PREFIX) {
+            let metadata = self.client.metadata(&path).await.map_err(errno)?;
+            xattr::nextcloud_attributes(&metadata)
+                .into_iter()
+                .find(|(attribute, _)| attribute == name)
+                .map(|(_, value)| value)
+        } else if xattr::is_user(name) {
+            self.client.attribute(&path, name).await.map_err(errno)?
+        } else {
+            // security.*, system.* and trusted.* are asked for a lot, never ask the server
+            None
+        };
+
+        xattr::reply(value.ok_or_else(|| Errno::from(libc::ENODATA))?, size)
+    }
+
+    async fn listxattr(&self, _req: Request, path: &OsStr, size: u32) -> Result<ReplyXAttr> {
+        if self.is_virtual(path) {
+            return xattr::reply(Vec::new(), size);
+        }
+        let path = self.dav_path(path)?;
+        let metadata = self.client.metadata(&path).await.map_err(errno)?;
+        let attributes = self.client.attributes(&path).await.map_err(errno)?;
+
+        let names = xattr::nextcloud_attributes(&metadata)
+            .into_iter()
+            .chain(attributes)
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(xattr::PREFIX) || xattr::is_user(name))
+            .collect::<Vec<String>>();
+
+        xattr::reply(xattr::name_list(names.iter().map(String::as_str)), size)
+    }
+
+    async fn setxattr(
+        &self,
+        _req: Request,
+        path: &OsStr,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+    ) -> Result<()> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        if self.is_virtual(path) {
+            return Err(libc::EROFS.into());
+        }
+        let name = name.to_str().ok_or_else(|| Errno::from(libc::EOPNOTSUPP))?;
+        let path = self.dav_path(path)?;
+
+        if name == xattr::FAVORITE {
+            let favorite = xattr::parse_flag(value)?;
+            return self
+                .client
+                .set_favorite(&path, favorite)
+                .await
+                .map_err(errno);
+        }
+        if name.starts_with(xattr::PREFIX) {
+            return Err(libc::EPERM.into());
+        }
+        if !xattr::is_user(name) {
+            return Err(libc::EOPNOTSUPP.into());
+        }
+
+        // XATTR_CREATE and XATTR_REPLACE depend on whether the attribute is set already
+        let flags = flags as i32;
+        if flags & (libc::XATTR_CREATE | libc::XATTR_REPLACE) != 0 {
+            let exists = self
+                .client
+                .attribute(&path, name)
+                .await
+                .map_err(errno)?
+                .is_some();
+            if exists && flags & libc::XATTR_CREATE != 0 {
+                return Err(libc::EEXIST.into());
+            }
+            if !exists && flags & libc::XATTR_REPLACE != 0 {
+                return Err(libc::ENODATA.into());
+            }
+        }
+
+        self.client
+            .set_attribute(&path, name, Some(value))
+            .await
+            .map_err(errno)
+    }
+
+    async fn removexattr(&self, _req: Request, path: &OsStr, name: &OsStr) -> Result<()> {
+        if self.options.read_only {
+            return Err(libc::EROFS.into());
+        }
+        if self.is_virtual(path) {
+            return Err(libc::EROFS.into());
+        }
+        let name = name.to_str().ok_or_else(|| Errno::from(libc::ENODATA))?;
+        let path = self.dav_path(path)?;
+
+        if name == xattr::FAVORITE {
+            return self.client.set_favorite(&path, false).await.map_err(errno);
+        }
+        if name.starts_with(xattr::PREFIX) {
+            return Err(libc::EPERM.into());
+        }
+        if !xattr::is_user(name) {
+            return Err(libc::ENODATA.into());
+        }
+
+        // Removing a property that is not set succeeds, removexattr(2) has to fail
+        if self
+            .client
+            .attribute(&path, name)
+            .await
+            .map_err(errno)?
+            .is_none()
+        {
+            return Err(libc::ENODATA.into());
+        }
+        self.client
+            .set_attribute(&path, name, None)
+            .await
+            .map_err(errno)
+    }
+
+    async fn statfs(&self, _req: Request, _path: &OsStr) -> Result<ReplyStatFs> {
+        let quota = self.quota().await?;
+
+        // Unlimited and unknown quotas (-3, -2 and -1 while the server is still computing
+        // it) are reported as plenty of free space, so df and file managers don't refuse to
+        // copy anything
+        let free = match quota.available {
+            QuotaValue::Bytes(available) => available,
+            QuotaValue::NotComputed | QuotaValue::Unknown | QuotaValue::Unlimited => UNKNOWN_FREE,
+        } / BLOCK_SIZE as u64;
+        let blocks = quota.used.div_ceil(BLOCK_SIZE as u64) + free;
+
+        Ok(ReplyStatFs {
+            blocks,
+            bfree: free,
+            bavail: free,
+            files: 0,
+            ffree: 0,
+            bsize: BLOCK_SIZE,
+            namelen: MAX_NAME_LENGTH,
+            frsize: BLOCK_SIZE,
+        })
+    }
+}
+
+#[cfg(test)]
+impl FilesystemOptions {
+    // Everything on disk goes to a fresh folder named after the test
+    pub fn test(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("nextcloud-fuse-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        FilesystemOptions {
+            root: String::new(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            umask: 0o022,
+            read_only: false,
+            staging_dir: dir.join("staging"),
+            attr_timeout: Duration::from_secs(60),
+            negative_timeout: Duration::from_secs(5),
+            content_dir: dir.join("content"),
+            block_size: 4096,
+            cache_size: 1024 * 1024,
+            database: Some(dir.join("cache.sqlite")),
+            offline: OfflineMode::ReadOnly,
+            journal_dir: dir.join("journal"),
+            conflicts: ConflictPolicy::KeepBoth,
+            trash: false,
+            versions: false,
+            excludes: Excludes::new("", &[], &[]),
+            poll_interval: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use nextcloud::{
+        mock::{self, Failure, MockServer},
+        CircuitBreakerConfig, LockTimeout, RetryPolicy,
+    };
+
+    use super::*;
+
+    fn os(path: &str) -> &OsStr {
+        OsStr::new(path)
+    }
+
+    async fn names(filesystem: &NextcloudFilesystem, path: &str) -> Vec<OsString> {
+        let reply = filesystem
+            .readdir(Request::default(), os(path), 0, 0)
+            .await
+            .unwrap();
+        reply
+            .entries
+            .map(|entry| entry.unwrap().name)
+            .collect()
+            .await
+    }
+
+    async fn read_file(filesystem: &NextcloudFilesystem, path: &str) -> Result<Vec<u8>> {
+        let req = Request::default();
+        let fh = filesystem
+            .open(req, os(path), libc::O_RDONLY as u32)
+            .await?
+            .fh;
+        let data = filesystem
+            .read(req, Some(os(path)), fh, 0, 1024 * 1024)
+            .await?
+            .data;
+        filesystem
+            .release(req, Some(os(path)), fh, 0, 0, false)
+            .await?;
+        Ok(data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let server = MockServer::start().await;
+        let content: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        server.add_file("Docs/a.txt", &content);
+        let filesystem = NextcloudFilesystem::new(server.client(), FilesystemOptions::test("read"));
+        let req = Request::default();
+
+        let entry = filesystem.lookup(req, os("/"), os("Docs")).await.unwrap();
+        assert_eq!(entry.attr.kind, FileType::Directory);
+        assert_eq!(
+            filesystem
+                .lookup(req, os("/"), os("missing"))
+                .await
+                .unwrap_err(),
+            libc::ENOENT.into()
+        );
+        assert_eq!(names(&filesystem, "/Docs").await, [".", "..", "a.txt"]);
+
+        let attr = filesystem
+            .getattr(req, Some(os("/Docs/a.txt")), None, 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.size, 10000);
+        assert_eq!(
+            read_file(&filesystem, "/Docs/a.txt").await.unwrap(),
+            content
+        );
+
+        // Blocks read once come from the content cache
+        server.clear_requests();
+        assert_eq!(
+            read_file(&filesystem, "/Docs/a.txt").await.unwrap(),
+            content
+        );
+        assert!(!server
+            .requests()
+            .iter()
+            .any(|request| request.method == "GET"));
+    }
+
+    async fn write_file(
+        filesystem: &NextcloudFilesystem,
+        path: &str,
+        flags: i32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let req = Request::default();
+        let fh = filesystem.open(req, os(path), flags as u32).await?.fh;
+        filesystem
+            .write(req, Some(os(path)), fh, offset, data, 0, 0)
+            .await?;
+        let flushed = filesystem.flush(req, Some(os(path)), fh, 0).await;
+        filesystem
+            .release(req, Some(os(path)), fh, 0, 0, false)
+            .await?;
+        flushed
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"hello world");
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("write"));
+        let req = Request::default();
+
+        // Writes stay local until the file is flushed
+        let created = filesystem
+            .create(req, os("/Docs"), os("b.txt"), 0o644, libc::O_WRONLY as u32)
+            .await
+            .unwrap();
+        filesystem
+            .write(
+                req,
+                Some(os("/Docs/b.txt")),
+                created.fh,
+                0,
+                b"new file",
+                0,
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Docs/b.txt").unwrap(), b"");
+        let attr = filesystem
+            .getattr(req, Some(os("/Docs/b.txt")), Some(created.fh), 0)
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.size, 8);
+        filesystem
+            .flush(req, Some(os("/Docs/b.txt")), created.fh, 0)
+            .await
+            .unwrap();
+        filesystem
+            .release(req, Some(os("/Docs/b.txt")), created.fh, 0, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Docs/b.txt").unwrap(), b"new file");
+
+        // A file someone else created meanwhile is not replaced
+        assert_eq!(
+            filesystem
+                .lookup(req, os("/Docs"), os("c.txt"))
+                .await
+                .unwrap_err(),
+            libc::ENOENT.into()
+        );
+        server.add_file("Docs/c.txt", b"theirs");
+        server.clear_requests();
+        assert_eq!(
+            filesystem
+                .create(req, os("/Docs"), os("c.txt"), 0o644, libc::O_WRONLY as u32)
+                .await
+                .unwrap_err(),
+            libc::EEXIST.into()
+        );
+        assert_eq!(server.read_file("Docs/c.txt").unwrap(), b"theirs");
+        let puts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .collect();
+        assert_eq!(puts.len(), 1);
+        assert_eq!(puts[0].header("If-None-Match"), Some("*"));
+
+        // The upload replaces the version that was opened
+        let etag = server.etag("Docs/a.txt").unwrap();
+        server.clear_requests();
+        write_file(&filesystem, "/Docs/a.txt", libc::O_WRONLY, 6, b"there")
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"hello there");
+        let puts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .collect();
+        assert_eq!(puts.len(), 1);
+        assert_eq!(
+            puts[0].header("If-Match"),
+            Some(format!("\"{}\"", etag).as_str())
+        );
+        assert_eq!(
+            read_file(&filesystem, "/Docs/a.txt").await.unwrap(),
+            b"hello there"
+        );
+
+        server.clear_requests();
+        write_file(
+            &filesystem,
+            "/Docs/a.txt",
+            libc::O_WRONLY | libc::O_TRUNC,
+            0,
+            b"bye",
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"bye");
+        assert!(!server
+            .requests()
+            .iter()
+            .any(|request| request.method == "GET"));
+
+        // Opening for writing fetches nothing, reads fetch the blocks they need and the
+        // rest is only filled in for the upload
+        let content: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        server.add_file("Docs/large.bin", &content);
+        let path = "/Docs/large.bin";
+        server.clear_requests();
+        let fh = filesystem
+            .open(req, os(path), libc::O_RDWR as u32)
+            .await
+            .unwrap()
+            .fh;
+        filesystem
+            .write(req, Some(os(path)), fh, 0, b"abc", 0, 0)
+            .await
+            .unwrap();
+        let data = filesystem
+            .read(req, Some(os(path)), fh, 5000, 10)
+            .await
+            .unwrap()
+            .data;
+        assert_eq!(data[..], content[5000..5010]);
+        let ranges: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.header("Range").map(str::to_string))
+            .collect();
+        assert_eq!(ranges, [Some("bytes=4096-8191".to_string())]);
+
+        filesystem.flush(req, Some(os(path)), fh, 0).await.unwrap();
+        filesystem
+            .release(req, Some(os(path)), fh, 0, 0, false)
+            .await
+            .unwrap();
+        let mut expected = content.clone();
+        expected[..3].copy_from_slice(b"abc");
+        assert_eq!(server.read_file("Docs/large.bin").unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"a");
+        server.add_file("Docs/b.txt", b"b");
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("namespace"));
+        let req = Request::default();
+
+        let entry = filesystem
+            .mkdir(req, os("/"), os("New"), 0o755, 0)
+            .await
+            .unwrap();
+        assert_eq!(entry.attr.kind, FileType::Directory);
+        assert!(server.exists("New"));
+        assert_eq!(
+            filesystem
+                .mkdir(req, os("/"), os("New"), 0o755, 0)
+                .await
+                .unwrap_err(),
+            libc::EEXIST.into()
+        );
+
+        filesystem
+            .rename(req, os("/Docs"), os("a.txt"), os("/New"), os("a.txt"))
+            .await
+            .unwrap();
+        assert!(!server.exists("Docs/a.txt"));
+        assert_eq!(server.read_file("New/a.txt").unwrap(), b"a");
+        assert_eq!(names(&filesystem, "/Docs").await, [".", "..", "b.txt"]);
+
+        assert_eq!(
+            filesystem
+                .rename2(
+                    req,
+                    os("/New"),
+                    os("a.txt"),
+                    os("/Docs"),
+                    os("b.txt"),
+                    libc::RENAME_NOREPLACE,
+                )
+                .await
+                .unwrap_err(),
+            libc::EEXIST.into()
+        );
+        assert_eq!(server.read_file("Docs/b.txt").unwrap(), b"b");
+        assert_eq!(
+            filesystem
+                .rename(req, os("/New"), os("a.txt"), os("/"), os("Docs"))
+                .await
+                .unwrap_err(),
+            libc::EISDIR.into()
+        );
+
+        // An open file follows its rename and is uploaded to the new path
+        let fh = filesystem
+            .open(req, os("/Docs/b.txt"), libc::O_WRONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        filesystem
+            .rename(req, os("/Docs"), os("b.txt"), os("/Docs"), os("c.txt"))
+            .await
+            .unwrap();
+        filesystem
+            .write(req, Some(os("/Docs/c.txt")), fh, 1, b"c", 0, 0)
+            .await
+            .unwrap();
+        filesystem
+            .release(req, Some(os("/Docs/c.txt")), fh, 0, 0, true)
+            .await
+            .unwrap();
+        assert!(!server.exists("Docs/b.txt"));
+        assert_eq!(server.read_file("Docs/c.txt").unwrap(), b"bc");
+
+        assert_eq!(
+            filesystem.rmdir(req, os("/"), os("New")).await.unwrap_err(),
+            libc::ENOTEMPTY.into()
+        );
+        assert_eq!(
+            filesystem
+                .unlink(req, os("/"), os("New"))
+                .await
+                .unwrap_err(),
+            libc::EISDIR.into()
+        );
+        filesystem
+            .unlink(req, os("/New"), os("a.txt"))
+            .await
+            .unwrap();
+        assert!(!server.exists("New/a.txt"));
+        assert_eq!(
+            filesystem
+                .lookup(req, os("/New"), os("a.txt"))
+                .await
+                .unwrap_err(),
+            libc::ENOENT.into()
+        );
+        filesystem.rmdir(req, os("/"), os("New")).await.unwrap();
+        assert!(!server.exists("New"));
+        assert_eq!(names(&filesystem, "/").await, [".", "..", "Docs"]);
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"base");
+        server.add_file("Docs/b.txt", b"locked");
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("conflicts"));
+        let req = Request::default();
+
+        // Changed on the server while open here, both versions are kept
+        let fh = filesystem
+            .open(
+                req,
+                os("/Docs/a.txt"),
+                (libc::O_WRONLY | libc::O_TRUNC) as u32,
+            )
+            .await
+            .unwrap()
+            .fh;
+        server.add_file("Docs/a.txt", b"theirs");
+        filesystem
+            .write(req, Some(os("/Docs/a.txt")), fh, 0, b"ours", 0, 0)
+            .await
+            .unwrap();
+        filesystem
+            .flush(req, Some(os("/Docs/a.txt")), fh, 0)
+            .await
+            .unwrap();
+        filesystem
+            .release(req, Some(os("/Docs/a.txt")), fh, 0, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"theirs");
+        let names = names(&filesystem, "/Docs").await;
+        let copy = names
+            .iter()
+            .filter_map(|name| name.to_str())
+            .find(|name| name.starts_with("a (conflicted copy "))
+            .unwrap();
+        assert!(copy.ends_with(").txt"));
+        assert_eq!(
+            server.read_file(&format!("Docs/{}", copy)).unwrap(),
+            b"ours"
+        );
+
+        // Locked by someone else
+        server
+            .client()
+            .lock("Docs/b.txt", "someone else", LockTimeout::Seconds(60))
+            .await
+            .unwrap();
+        assert_eq!(
+            write_file(&filesystem, "/Docs/b.txt", libc::O_WRONLY, 0, b"mine")
+                .await
+                .unwrap_err(),
+            libc::EBUSY.into()
+        );
+        assert_eq!(server.read_file("Docs/b.txt").unwrap(), b"locked");
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let server = MockServer::start().await;
+        server.add_file("a.txt", &[0; 100]);
+        server.set_quota(Some(110));
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("quota"));
+        let req = Request::default();
+
+        let fh = filesystem
+            .create(req, os("/"), os("b.txt"), 0o644, libc::O_WRONLY as u32)
+            .await
+            .unwrap()
+            .fh;
+        // Known not to fit before anything is uploaded
+        assert_eq!(
+            filesystem
+                .write(req, Some(os("/b.txt")), fh, 0, &[1; 20], 0, 0)
+                .await
+                .unwrap_err(),
+            libc::ENOSPC.into()
+        );
+        filesystem
+            .write(req, Some(os("/b.txt")), fh, 0, &[1; 8], 0, 0)
+            .await
+            .unwrap();
+
+        // Refused by the server
+        server.set_quota(Some(100));
+        assert_eq!(
+            filesystem
+                .flush(req, Some(os("/b.txt")), fh, 0)
+                .await
+                .unwrap_err(),
+            libc::ENOSPC.into()
+        );
+        assert_eq!(server.read_file("b.txt").unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let server = MockServer::start().await;
+        server.add_file("Shared/a.txt", b"read only");
+        server.set_permissions("Shared", "SG");
+        server.set_permissions("Shared/a.txt", "SG");
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("permissions"));
+        let req = Request::default();
+
+        let attr = filesystem
+            .lookup(req, os("/Shared"), os("a.txt"))
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.perm & 0o222, 0);
+        let attr = filesystem
+            .lookup(req, os("/"), os("Shared"))
+            .await
+            .unwrap()
+            .attr;
+        assert_eq!(attr.perm & 0o222, 0);
+
+        // Refused here rather than by the server
+        server.clear_requests();
+        assert_eq!(
+            filesystem
+                .open(req, os("/Shared/a.txt"), libc::O_WRONLY as u32)
+                .await
+                .unwrap_err(),
+            libc::EACCES.into()
+        );
+        assert_eq!(
+            filesystem
+                .unlink(req, os("/Shared"), os("a.txt"))
+                .await
+                .unwrap_err(),
+            libc::EPERM.into()
+        );
+        assert_eq!(
+            filesystem
+                .rename(req, os("/Shared"), os("a.txt"), os("/Shared"), os("b.txt"))
+                .await
+                .unwrap_err(),
+            libc::EPERM.into()
+        );
+        assert_eq!(
+            filesystem
+                .create(
+                    req,
+                    os("/Shared"),
+                    os("b.txt"),
+                    0o644,
+                    libc::O_WRONLY as u32
+                )
+                .await
+                .unwrap_err(),
+            libc::EACCES.into()
+        );
+        assert_eq!(
+            filesystem
+                .mkdir(req, os("/Shared"), os("New"), 0o755, 0)
+                .await
+                .unwrap_err(),
+            libc::EACCES.into()
+        );
+        assert!(server.requests().is_empty());
+        assert_eq!(
+            read_file(&filesystem, "/Shared/a.txt").await.unwrap(),
+            b"read only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"old");
+        // The breaker lets requests through again as soon as the server is back
+        let client = server.client().with_retry_policy(
+            RetryPolicy {
+                max_retries: 1,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            },
+            CircuitBreakerConfig {
+                failure_threshold: 1,
+                open_duration: Duration::ZERO,
+            },
+        );
+        let filesystem = NextcloudFilesystem::new(
+            client,
+            FilesystemOptions {
+                offline: OfflineMode::ReadWrite,
+                ..FilesystemOptions::test("offline")
+            },
+        );
+        let req = Request::default();
+        assert_eq!(names(&filesystem, "/Docs").await, [".", "..", "a.txt"]);
+
+        server.fail(Failure::disconnect().times(usize::MAX));
+        write_file(
+            &filesystem,
+            "/Docs/a.txt",
+            libc::O_WRONLY | libc::O_TRUNC,
+            0,
+            b"offline",
+        )
+        .await
+        .unwrap();
+        filesystem
+            .mkdir(req, os("/Docs"), os("New"), 0o755, 0)
+            .await
+            .unwrap();
+
+        // Queued changes are visible locally before they reach the server
+        assert_eq!(
+            read_file(&filesystem, "/Docs/a.txt").await.unwrap(),
+            b"offline"
+        );
+        assert_eq!(
+            names(&filesystem, "/Docs").await,
+            [".", "..", "a.txt", "New"]
+        );
+        let journal = filesystem.journal.as_ref().unwrap();
+        assert!(!journal.is_empty());
+        server.clear_failures();
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"old");
+        assert!(!server.exists("Docs/New"));
+
+        journal.replay().await;
+        assert!(journal.is_empty());
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"offline");
+        assert!(server.exists("Docs/New"));
+
+        // An upload the server refuses during the replay ends up as a conflicted copy, at
+        // the top when the folder refuses that too
+        assert_eq!(
+            read_file(&filesystem, "/Docs/a.txt").await.unwrap(),
+            b"offline"
+        );
+        server.fail(Failure::disconnect().times(usize::MAX));
+        write_file(
+            &filesystem,
+            "/Docs/a.txt",
+            libc::O_WRONLY | libc::O_TRUNC,
+            0,
+            b"refused",
+        )
+        .await
+        .unwrap();
+        server.clear_failures();
+        server.fail(Failure::status(403).on("PUT", "Docs").times(usize::MAX));
+        server.clear_requests();
+        journal.replay().await;
+        assert!(journal.is_empty());
+        assert_eq!(server.read_file("Docs/a.txt").unwrap(), b"offline");
+        let files = format!("/{}/files/{}/", mock::DAV_PATH, mock::USER);
+        let puts: Vec<String> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .filter_map(|request| Some(request.path.strip_prefix(&files)?.to_string()))
+            .collect();
+        assert_eq!(puts.len(), 3);
+        assert!(puts[1].starts_with("Docs/a (conflicted copy "));
+        assert_eq!(puts[2], puts[1].trim_start_matches("Docs/"));
+        assert_eq!(server.read_file(&puts[2]).unwrap(), b"refused");
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(dav_path("", OsStr::new("/")).unwrap(), "");
+        assert_eq!(
+            dav_path("", OsStr::new("/Documents/a b")).unwrap(),
+            "Documents/a b"
+        );
+        assert_eq!(dav_path("/Photos/", OsStr::new("/")).unwrap(), "Photos");
+        assert_eq!(
+            dav_path("Photos", OsStr::new("/2024/a.jpg")).unwrap(),
+            "Photos/2024/a.jpg"
+        );
+        assert_eq!(
+            fuse_path("", "Documents/a b"),
+            Some(OsString::from("/Documents/a b"))
+        );
+        assert_eq!(fuse_path("Photos", "Photos"), Some(OsString::from("/")));
+        assert_eq!(
+            fuse_path("/Photos/", "Photos/2024/a.jpg"),
+            Some(OsString::from("/2024/a.jpg"))
+        );
+        assert_eq!(fuse_path("Photos", "Photos2/a.jpg"), None);
+        assert_eq!(folder_path(""), "");
+        assert_eq!(folder_path("Documents"), "Documents/");
+        assert_eq!(join(OsStr::new("/"), OsStr::new("a")), OsString::from("/a"));
+        assert_eq!(
+            join(OsStr::new("/Documents"), OsStr::new("a")),
+            OsString::from("/Documents/a")
+        );
     }
 }
@@ -0,0 +1,482 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use fuse3::{
+    path::{reply as path_reply, PathFilesystem},
+    raw::{
+        reply::{
+            DirectoryEntry, DirectoryEntryPlus, FileAttr, ReplyAttr, ReplyCreated, ReplyData,
+            ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs,
+            ReplyWrite, ReplyXAttr,
+        },
+        Filesystem, Request,
+    },
+    Errno, Result, SetAttr,
+};
+use futures_util::{
+    stream::{self, Iter},
+    StreamExt,
+};
+
+use super::{
+    filesystem::NextcloudFilesystem,
+    inodes::{InodeTable, ROOT},
+    watcher,
+};
+
+// Serves NextcloudFilesystem through the inode based FUSE API, so inode numbers come from
+// oc:fileid instead of the order in which paths were looked up
+pub struct InodeFilesystem {
+    inner: Arc<NextcloudFilesystem>,
+    inodes: Arc<Mutex<InodeTable>>,
+}
+
+fn with_inode(attr: path_reply::FileAttr, ino: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime.into(),
+        mtime: attr.mtime.into(),
+        ctime: attr.ctime.into(),
+        kind: attr.kind,
+        perm: attr.perm,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        blksize: attr.blksize,
+    }
+}
+
+fn child(parent: &OsStr, name: &OsStr) -> OsString {
+    Path::new(parent).join(name).into_os_string()
+}
+
+impl InodeFilesystem {
+    pub fn new(inner: NextcloudFilesystem) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            inodes: Arc::new(Mutex::new(InodeTable::new())),
+        }
+    }
+
+    fn path(&self, inode: u64) -> Result<OsString> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .path(inode)
+            .map(OsStr::to_os_string)
+            .ok_or_else(|| Errno::from(libc::ENOENT))
+    }
+
+    // Inode number and generation of whatever is at path now, handed to the kernel
+    async fn lookup(&self, path: &OsStr) -> (u64, u64) {
+        let file_id = self.inner.file_id(path).await;
+        self.inodes.lock().unwrap().lookup(path, file_id)
+    }
+
+    fn parent(&self, path: &OsStr) -> u64 {
+        Path::new(path)
+            .parent()
+            .and_then(|parent| self.inodes.lock().unwrap().inode(parent.as_os_str()))
+            .unwrap_or(ROOT)
+    }
+
+    async fn entry(&self, path: &OsStr, entry: path_reply::ReplyEntry) -> ReplyEntry {
+        let (inode, generation) = self.lookup(path).await;
+
+        ReplyEntry {
+            ttl: entry.ttl,
+            attr: with_inode(entry.attr, inode),
+            generation,
+        }
+    }
+}
+
+impl Filesystem for InodeFilesystem {
+    type DirEntryStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntry>>>
+    where
+        Self: 'a;
+    type DirEntryPlusStream<'a>
+        = Iter<std::vec::IntoIter<Result<DirectoryEntryPlus>>>
+    where
+        Self: 'a;
+
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        let reply = self.inner.init(req).await?;
+
+        let interval = self.inner.poll_interval();
+        if !interval.is_zero() {
+            tokio::spawn(watcher::watch(
+                self.inner.clone(),
+                self.inodes.clone(),
+                interval,
+            ));
+        }
+
+        Ok(ReplyInit {
+            max_write: reply.max_write,
+        })
+    }
+
+    async fn destroy(&self, req: Request) {
+        self.inner.destroy(req).await
+    }
+
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        let parent = self.path(parent)?;
+        let entry = self.inner.lookup(req, &parent, name).await?;
+
+        Ok(self.entry(&child(&parent, name), entry).await)
+    }
+
+    async fn forget(&self, _req: Request, inode: u64, nlookup: u64) {
+        self.inodes.lock().unwrap().forget(inode, nlookup)
+    }
+
+    async fn batch_forget(&self, _req: Request, inodes: &[u64]) {
+        // fuse3 leaves out the counts, the kernel only batches inodes it evicts entirely
+        let mut table = self.inodes.lock().unwrap();
+        for &inode in inodes {
+            table.forget(inode, u64::MAX);
+        }
+    }
+
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        let path = self.path(inode)?;
+        let reply = self.inner.getattr(req, Some(&path), fh, flags).await?;
+
+        // Someone else replaced the item, the kernel has to look the path up again
+        let current = InodeTable::number(&path, self.inner.file_id(&path).await);
+        if current != inode && !InodeTable::is_synthetic(inode) {
+            return Err(libc::ESTALE.into());
+        }
+
+        Ok(ReplyAttr {
+            ttl: reply.ttl,
+            attr: with_inode(reply.attr, inode),
+        })
+    }
+
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        let path = self.path(inode).ok();
+        let reply = self
+            .inner
+            .setattr(req, path.as_deref(), fh, set_attr)
+            .await?;
+
+        Ok(ReplyAttr {
+            ttl: reply.ttl,
+            attr: with_inode(reply.attr, inode),
+        })
+    }
+
+    async fn open(&self, req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
+        let path = self.path(inode)?;
+        self.inner.open(req, &path, flags).await
+    }
+
+    async fn create(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let parent = self.path(parent)?;
+        let created = self.inner.create(req, &parent, name, mode, flags).await?;
+        let (inode, generation) = self.lookup(&child(&parent, name)).await;
+
+        Ok(ReplyCreated {
+            ttl: created.ttl,
+            attr: with_inode(created.attr, inode),
+            generation,
+            fh: created.fh,
+            flags: created.flags,
+        })
+    }
+
+    async fn read(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let path = self.path(inode).ok();
+        self.inner
+            .read(req, path.as_deref(), fh, offset, size)
+            .await
+    }
+
+    async fn write(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<ReplyWrite> {
+        let path = self.path(inode).ok();
+        self.inner
+            .write(req, path.as_deref(), fh, offset, data, write_flags, flags)
+            .await
+    }
+
+    async fn flush(&self, req: Request, inode: u64, fh: u64, lock_owner: u64) -> Result<()> {
+        let path = self.path(inode).ok();
+        self.inner.flush(req, path.as_deref(), fh, lock_owner).await
+    }
+
+    async fn fsync(&self, req: Request, inode: u64, fh: u64, datasync: bool) -> Result<()> {
+        let path = self.path(inode).ok();
+        self.inner.fsync(req, path.as_deref(), fh, datasync).await
+    }
+
+    async fn release(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        let path = self.path(inode).ok();
+        self.inner
+            .release(req, path.as_deref(), fh, flags, lock_owner, flush)
+            .await
+    }
+
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let parent = self.path(parent)?;
+        let entry = self.inner.mkdir(req, &parent, name, mode, umask).await?;
+
+        Ok(self.entry(&child(&parent, name), entry).await)
+    }
+
+    async fn unlink(&self, req: Request, parent: u64, name: &OsStr) -> Result<()> {
+        let parent = self.path(parent)?;
+        self.inner.unlink(req, &parent, name).await?;
+
+        self.inodes.lock().unwrap().remove(&child(&parent, name));
+        Ok(())
+    }
+
+    async fn rmdir(&self, req: Request, parent: u64, name: &OsStr) -> Result<()> {
+        let parent = self.path(parent)?;
+        self.inner.rmdir(req, &parent, name).await?;
+
+        self.inodes.lock().unwrap().remove(&child(&parent, name));
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        self.rename2(req, parent, name, new_parent, new_name, 0)
+            .await
+    }
+
+    async fn rename2(
+        &self,
+        req: Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let parent = self.path(parent)?;
+        let new_parent = self.path(new_parent)?;
+        self.inner
+            .rename2(req, &parent, name, &new_parent, new_name, flags)
+            .await?;
+
+        self.inodes
+            .lock()
+            .unwrap()
+            .rename(&child(&parent, name), &child(&new_parent, new_name));
+        Ok(())
+    }
+
+    async fn opendir(&self, req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
+        let path = self.path(inode)?;
+        self.inner.opendir(req, &path, flags).await
+    }
+
+    async fn readdir<'a>(
+        &'a self,
+        req: Request,
+        parent: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
+        let path = self.path(parent)?;
+        let entries: Vec<Result<path_reply::DirectoryEntry>> = self
+            .inner
+            .readdir(req, &path, fh, offset)
+            .await?
+            .entries
+            .collect()
+            .await;
+        let file_ids = self.inner.file_ids(&path);
+
+        // The kernel does not count readdir entries as lookups, so they are not bound
+        let mut result = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let entry = entry?;
+            let inode = if entry.name == "." {
+                parent
+            } else if entry.name == ".." {
+                self.parent(&path)
+            } else {
+                let path = child(&path, &entry.name);
+                match file_ids.get(&entry.name) {
+                    Some(&file_id) => InodeTable::number(&path, file_id),
+                    None => InodeTable::number(&path, self.inner.file_id(&path).await),
+                }
+            };
+
+            result.push(Ok(DirectoryEntry {
+                inode,
+                kind: entry.kind,
+                name: entry.name,
+                offset: entry.offset,
+            }));
+        }
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(result),
+        })
+    }
+
+    async fn readdirplus<'a>(
+        &'a self,
+        req: Request,
+        parent: u64,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'a>>> {
+        let path = self.path(parent)?;
+        let entries: Vec<Result<path_reply::DirectoryEntryPlus>> = self
+            .inner
+            .readdirplus(req, &path, fh, offset, lock_owner)
+            .await?
+            .entries
+            .collect()
+            .await;
+        let file_ids = self.inner.file_ids(&path);
+
+        let mut result = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let entry = entry?;
+            let (inode, generation) = if entry.name == "." {
+                (parent, 0)
+            } else if entry.name == ".." {
+                (self.parent(&path), 0)
+            } else {
+                let path = child(&path, &entry.name);
+                match file_ids.get(&entry.name) {
+                    Some(&file_id) => self.inodes.lock().unwrap().lookup(&path, file_id),
+                    None => self.lookup(&path).await,
+                }
+            };
+
+            result.push(Ok(DirectoryEntryPlus {
+                inode,
+                generation,
+                kind: entry.kind,
+                name: entry.name,
+                offset: entry.offset,
+                attr: with_inode(entry.attr, inode),
+                entry_ttl: entry.entry_ttl,
+                attr_ttl: entry.attr_ttl,
+            }));
+        }
+
+        Ok(ReplyDirectoryPlus {
+            entries: stream::iter(result),
+        })
+    }
+
+    async fn releasedir(&self, req: Request, inode: u64, fh: u64, flags: u32) -> Result<()> {
+        let path = self.path(inode)?;
+        self.inner.releasedir(req, &path, fh, flags).await
+    }
+
+    async fn getxattr(
+        &self,
+        req: Request,
+        inode: u64,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let path = self.path(inode)?;
+        self.inner.getxattr(req, &path, name, size).await
+    }
+
+    async fn listxattr(&self, req: Request, inode: u64, size: u32) -> Result<ReplyXAttr> {
+        let path = self.path(inode)?;
+        self.inner.listxattr(req, &path, size).await
+    }
+
+    async fn setxattr(
+        &self,
+        req: Request,
+        inode: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        position: u32,
+    ) -> Result<()> {
+        let path = self.path(inode)?;
+        self.inner
+            .setxattr(req, &path, name, value, flags, position)
+            .await
+    }
+
+    async fn removexattr(&self, req: Request, inode: u64, name: &OsStr) -> Result<()> {
+        let path = self.path(inode)?;
+        self.inner.removexattr(req, &path, name).await
+    }
+
+    async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
+        let path = self.path(inode)?;
+        self.inner.statfs(req, &path).await
+    }
+}
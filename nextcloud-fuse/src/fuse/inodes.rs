@@ -0,0 +1,234 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    ffi::{OsStr, OsString},
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+pub const ROOT: u64 = 1;
+// Items without an oc:fileid, e.g. created while offline, get inode numbers from a hash of
+// their path in the upper half
+const SYNTHETIC: u64 = 1 << 63;
+
+// Which FUSE path each inode number stands for. Inode numbers are oc:fileid + 1, so they
+// survive renames and, since file ids are kept in the persistent cache, remounts too.
+// Entries are dropped once the kernel has forgotten every lookup of the inode, not when
+// the item is removed, the kernel may still know the inode.
+pub struct InodeTable {
+    paths: HashMap<u64, OsString>,
+    inodes: HashMap<OsString, u64>,
+    // How often each inode was handed to the kernel and not forgotten yet
+    lookups: HashMap<u64, u64>,
+    // Bumped whenever an inode number stops standing for an item, so a synthetic number
+    // reused for a new item at the same path can be told apart
+    generations: HashMap<u64, u64>,
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InodeTable {
+    pub fn new() -> InodeTable {
+        let mut table = InodeTable {
+            paths: HashMap::new(),
+            inodes: HashMap::new(),
+            lookups: HashMap::new(),
+            generations: HashMap::new(),
+        };
+        table.paths.insert(ROOT, OsString::from("/"));
+        table.inodes.insert(OsString::from("/"), ROOT);
+        table
+    }
+
+    pub fn is_synthetic(inode: u64) -> bool {
+        inode >= SYNTHETIC
+    }
+
+    pub fn number(path: &OsStr, file_id: Option<u64>) -> u64 {
+        if path == "/" {
+            return ROOT;
+        }
+        match file_id {
+            Some(file_id) => file_id + 1,
+            None => {
+                let mut hasher = DefaultHasher::new();
+                path.hash(&mut hasher);
+                hasher.finish() | SYNTHETIC
+            }
+        }
+    }
+
+    pub fn path(&self, inode: u64) -> Option<&OsStr> {
+        self.paths.get(&inode).map(OsString::as_os_str)
+    }
+
+    pub fn inode(&self, path: &OsStr) -> Option<u64> {
+        self.inodes.get(path).copied()
+    }
+
+    pub fn generation(&self, inode: u64) -> u64 {
+        self.generations.get(&inode).copied().unwrap_or(0)
+    }
+
+    // The inode number and generation of the item at path, which has file_id
+    pub fn bind(&mut self, path: &OsStr, file_id: Option<u64>) -> (u64, u64) {
+        let inode = InodeTable::number(path, file_id);
+
+        match self.inodes.get(path) {
+            // A synthetic number keeps working for an item that got its file id, it may
+            // still be open
+            Some(&old) if old != inode && !InodeTable::is_synthetic(old) => {
+                // Another item took the path
+                self.paths.remove(&old);
+                *self.generations.entry(old).or_default() += 1;
+            }
+            _ => (),
+        }
+        if let Some(old_path) = self.paths.get(&inode) {
+            // Moved by someone else
+            if old_path != path {
+                self.inodes.remove(old_path);
+            }
+        }
+
+        self.paths.insert(inode, path.to_os_string());
+        self.inodes.insert(path.to_os_string(), inode);
+        (inode, self.generation(inode))
+    }
+
+    // Like bind, for an inode handed to the kernel, which later forgets it
+    pub fn lookup(&mut self, path: &OsStr, file_id: Option<u64>) -> (u64, u64) {
+        let (inode, generation) = self.bind(path, file_id);
+        *self.lookups.entry(inode).or_default() += 1;
+        (inode, generation)
+    }
+
+    pub fn forget(&mut self, inode: u64, nlookup: u64) {
+        if inode == ROOT {
+            return;
+        }
+        let lookups = self.lookups.entry(inode).or_default();
+        *lookups = lookups.saturating_sub(nlookup);
+        if *lookups > 0 {
+            return;
+        }
+
+        self.lookups.remove(&inode);
+        // The kernel cannot mix the inode up with an earlier item anymore
+        self.generations.remove(&inode);
+        if let Some(path) = self.paths.remove(&inode) {
+            if self.inodes.get(&path) == Some(&inode) {
+                self.inodes.remove(&path);
+            }
+        }
+    }
+
+    // Inodes standing for path or something below it
+    fn below(&self, path: &OsStr) -> Vec<(u64, OsString)> {
+        self.paths
+            .iter()
+            .filter(|(_, other)| Path::new(other).starts_with(path))
+            .map(|(inode, other)| (*inode, other.clone()))
+            .collect()
+    }
+
+    pub fn remove(&mut self, path: &OsStr) {
+        for (inode, _) in self.below(path) {
+            self.paths.remove(&inode);
+            *self.generations.entry(inode).or_default() += 1;
+        }
+        self.inodes
+            .retain(|other, _| !Path::new(other).starts_with(path));
+    }
+
+    pub fn rename(&mut self, from: &OsStr, to: &OsStr) {
+        self.remove(to);
+        let moved = |path: &OsStr| {
+            let rest = Path::new(path).strip_prefix(from).unwrap_or(Path::new(""));
+            if rest.as_os_str().is_empty() {
+                to.to_os_string()
+            } else {
+                Path::new(to).join(rest).into_os_string()
+            }
+        };
+
+        for (inode, path) in self.below(from) {
+            self.paths.insert(inode, moved(&path));
+        }
+        let inodes: Vec<(OsString, u64)> = self
+            .inodes
+            .iter()
+            .filter(|(path, _)| Path::new(path).starts_with(from))
+            .map(|(path, inode)| (path.clone(), *inode))
+            .collect();
+        for (path, inode) in inodes {
+            self.inodes.remove(&path);
+            self.inodes.insert(moved(&path), inode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(path: &str) -> &OsStr {
+        OsStr::new(path)
+    }
+
+    #[test]
+    fn test_inodes() {
+        let mut table = InodeTable::new();
+        assert_eq!(table.bind(os("/"), Some(7)), (ROOT, 0));
+        assert_eq!(table.bind(os("/Docs"), Some(10)), (11, 0));
+        assert_eq!(table.bind(os("/Docs/a.txt"), Some(12)), (13, 0));
+        assert_eq!(table.bind(os("/Docsa"), Some(14)), (15, 0));
+
+        // Renames keep the numbers, also of everything below
+        table.rename(os("/Docs"), os("/Papers"));
+        assert_eq!(table.path(13), Some(os("/Papers/a.txt")));
+        assert_eq!(table.inode(os("/Docsa")), Some(15));
+        assert_eq!(table.bind(os("/Papers/a.txt"), Some(12)), (13, 0));
+
+        // Moved by someone else
+        table.bind(os("/a.txt"), Some(12));
+        assert_eq!(table.path(13), Some(os("/a.txt")));
+        assert_eq!(table.inode(os("/Papers/a.txt")), None);
+
+        // A new item at a path, the old number stops working
+        table.bind(os("/Docsa"), Some(20));
+        assert_eq!(table.path(15), None);
+        assert_eq!(table.generation(15), 1);
+
+        // Synthetic numbers are reused for the same path, with a new generation
+        let (synthetic, generation) = table.bind(os("/offline.txt"), None);
+        assert!(InodeTable::is_synthetic(synthetic));
+        table.remove(os("/offline.txt"));
+        assert_eq!(table.path(synthetic), None);
+        assert_eq!(
+            table.bind(os("/offline.txt"), None),
+            (synthetic, generation + 1)
+        );
+
+        // ...and keep working after the item got its file id
+        assert_eq!(table.bind(os("/offline.txt"), Some(30)), (31, 0));
+        assert_eq!(table.path(synthetic), Some(os("/offline.txt")));
+        table.rename(os("/offline.txt"), os("/online.txt"));
+        assert_eq!(table.path(synthetic), Some(os("/online.txt")));
+        assert_eq!(table.path(31), Some(os("/online.txt")));
+
+        // Inodes are dropped once every lookup is forgotten
+        assert_eq!(table.lookup(os("/b.txt"), Some(40)), (41, 0));
+        assert_eq!(table.lookup(os("/b.txt"), Some(40)), (41, 0));
+        table.forget(41, 1);
+        assert_eq!(table.path(41), Some(os("/b.txt")));
+        table.forget(41, 1);
+        assert_eq!(table.path(41), None);
+        assert_eq!(table.inode(os("/b.txt")), None);
+        table.forget(ROOT, 1);
+        assert_eq!(table.path(ROOT), Some(os("/")));
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use nextcloud::{DavError, DavItem, Folder, Nextcloud};
+
+use super::{
+    cache::{Lookup, MetadataCache},
+    conflict::{conflict_path, hostname},
+    database::Database,
+};
+use crate::cli::ConflictPolicy;
+
+// How often queued modifications are retried while the server is unreachable
+const REPLAY_INTERVAL: Duration = Duration::from_secs(15);
+
+// A modification of the files on the server. Paths are relative to the user's files.
+// Uploads and renames of files only go through if the file still has the etag it had
+// when we last saw it, if there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    // Replace path with the local file data
+    Upload {
+        path: String,
+        data: PathBuf,
+        mtime: Option<SystemTime>,
+        etag: Option<String>,
+    },
+    // Create path with the local file data, which is empty, unless something is there
+    // already
+    Create {
+        path: String,
+        data: PathBuf,
+    },
+    Mkdir {
+        path: String,
+    },
+    // Files and folders alike, with everything in them
+    Delete {
+        path: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+        overwrite: bool,
+        etag: Option<String>,
+    },
+}
+
+pub enum Outcome {
+    Done,
+    // Uploaded to path, which is a conflicted copy if both versions were kept
+    Uploaded { path: String, etag: Option<String> },
+    // Changed on the server meanwhile, and the server's version wins
+    Discarded,
+    // Left in the journal until the server can be reached
+    Queued,
+}
+
+// Modifications made while the server could not be reached. They are kept in the database
+// and replayed in the order they were made once it is back. Refused uploads are kept as
+// conflicted copies, anything else the server refuses then stays in the database as a
+// conflict, along with the data of uploads not even a copy could be made of.
+pub struct Journal {
+    client: Nextcloud,
+    conflicts: ConflictPolicy,
+    database: Arc<Database>,
+    // Copies of the files to upload, the staged files are gone by the time of the replay
+    dir: PathBuf,
+    cache: Arc<MetadataCache>,
+    // Entries not replayed yet, oldest first
+    pending: Mutex<Vec<(i64, Operation)>>,
+    next_file: AtomicU64,
+    replaying: tokio::sync::Mutex<()>,
+}
+
+fn name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+fn is_below(path: &str, folder: &str) -> bool {
+    path.strip_prefix(folder)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn is_precondition_failed(error: &DavError) -> bool {
+    matches!(error, DavError::UnexpectedStatus(status) if status.as_u16() == 412)
+}
+
+impl Operation {
+    // Paths whose metadata the operation changes
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            Operation::Upload { path, .. }
+            | Operation::Create { path, .. }
+            | Operation::Mkdir { path }
+            | Operation::Delete { path } => vec![path],
+            Operation::Rename { from, to, .. } => vec![from, to],
+        }
+    }
+
+    pub async fn perform(
+        &self,
+        client: &Nextcloud,
+        conflicts: ConflictPolicy,
+    ) -> Result<Outcome, DavError> {
+        match self {
+            Operation::Upload {
+                path,
+                data,
+                mtime,
+                etag,
+            } => {
+                let error = match client.upload(path, data, *mtime, etag.as_deref()).await {
+                    Ok(etag) => {
+                        return Ok(Outcome::Uploaded {
+                            path: path.clone(),
+                            etag,
+                        })
+                    }
+                    Err(error) => error,
+                };
+                if !is_precondition_failed(&error) {
+                    return Err(error);
+                }
+
+                let target = match conflicts {
+                    ConflictPolicy::KeepBoth => conflict_path(path, &hostname(), SystemTime::now()),
+                    ConflictPolicy::LocalWins => path.clone(),
+                    ConflictPolicy::ServerWins => {
+                        log::warn!("{} changed on the server, dropping our changes", path);
+                        return Ok(Outcome::Discarded);
+                    }
+                };
+                log::warn!(
+                    "{} changed on the server, uploading ours to {}",
+                    path,
+                    target
+                );
+                let etag = client.upload(&target, data, *mtime, None).await?;
+                Ok(Outcome::Uploaded { path: target, etag })
+            }
+            Operation::Create { path, .. } => {
+                client.create(path).await.map(|etag| Outcome::Uploaded {
+                    path: path.clone(),
+                    etag,
+                })
+            }
+            Operation::Mkdir { path } => client.mkdir(path).await.map(|_| Outcome::Done),
+            Operation::Delete { path } => client.delete(path).await.map(|_| Outcome::Done),
+            Operation::Rename {
+                from,
+                to,
+                overwrite,
+                etag,
+            } => {
+                let error = match client.rename(from, to, *overwrite, etag.as_deref()).await {
+                    Ok(()) => return Ok(Outcome::Done),
+                    Err(error) => error,
+                };
+                // Without overwrite a 412 may also be about the destination
+                let changed = match (is_precondition_failed(&error), etag) {
+                    (true, Some(etag)) => client.stat(from).await?.etag() != Some(etag.as_str()),
+                    _ => false,
+                };
+                if !changed {
+                    return Err(error);
+                }
+
+                // Moving the file moves their changes along, nothing is lost either way
+                match conflicts {
+                    ConflictPolicy::ServerWins => {
+                        log::warn!("{} changed on the server, not moving it", from);
+                        Ok(Outcome::Discarded)
+                    }
+                    _ => client
+                        .rename(from, to, *overwrite, None)
+                        .await
+                        .map(|_| Outcome::Done),
+                }
+            }
+        }
+    }
+
+    // Local file the operation sends to the server
+    fn data(&self) -> Option<&Path> {
+        match self {
+            Operation::Upload { data, .. } | Operation::Create { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    // Uploads and renames expect the etag left by earlier operations of ours
+    fn rebase(&mut self, etags: &HashMap<String, Option<String>>) {
+        let etag = match self {
+            Operation::Upload { etag, .. } | Operation::Rename { etag, .. } => etag,
+            _ => return,
+        };
+        if let Some(new) = etag.as_ref().and_then(|old| etags.get(old)) {
+            *etag = new.clone();
+        }
+    }
+
+    // The server already is in the state the operation leads to, e.g. because a replay
+    // was interrupted after the request went through
+    fn is_done(&self, error: &DavError) -> bool {
+        let status = match error {
+            DavError::UnexpectedStatus(status) => status.as_u16(),
+            _ => return false,
+        };
+        matches!(
+            (self, status),
+            (Operation::Mkdir { .. }, 405) | (Operation::Delete { .. }, 404)
+        )
+    }
+}
+
+impl Journal {
+    // Picks up what an earlier mount could not replay and shows it in the cache
+    pub fn new(
+        client: Nextcloud,
+        conflicts: ConflictPolicy,
+        database: Arc<Database>,
+        dir: &Path,
+        cache: Arc<MetadataCache>,
+    ) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let entries = database.journal().map_err(io::Error::other)?;
+
+        // Data of conflicts is kept as well, everything else in dir is left over
+        let used: HashSet<&Path> = entries
+            .iter()
+            .filter_map(|entry| entry.operation.data())
+            .collect();
+        let mut next_file = 0;
+        for file in std::fs::read_dir(dir)? {
+            let path = file?.path();
+            if used.contains(path.as_path()) {
+                let number = path
+                    .file_name()
+                    .and_then(|name| name.to_str()?.parse().ok());
+                next_file = next_file.max(number.unwrap_or(0));
+            } else {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        let pending: Vec<(i64, Operation)> = entries
+            .into_iter()
+            .filter(|entry| entry.error.is_none())
+            .map(|entry| (entry.id, entry.operation))
+            .collect();
+        let journal = Self {
+            client,
+            conflicts,
+            database,
+            dir: dir.to_path_buf(),
+            cache,
+            pending: Mutex::new(Vec::new()),
+            next_file: AtomicU64::new(next_file + 1),
+            replaying: tokio::sync::Mutex::new(()),
+        };
+        for (_, operation) in &pending {
+            journal.apply(operation);
+        }
+        *journal.pending.lock().unwrap() = pending;
+
+        Ok(journal)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    // Durably record an operation for later and show its effect in the cache right away.
+    // Uploads are copied, their data may change or go away before the replay.
+    pub async fn queue(&self, operation: Operation) -> io::Result<()> {
+        let operation = match operation {
+            Operation::Upload {
+                path,
+                data,
+                mtime,
+                etag,
+            } => Operation::Upload {
+                path,
+                data: self.copy(&data).await?,
+                mtime,
+                etag,
+            },
+            Operation::Create { path, data } => Operation::Create {
+                path,
+                data: self.copy(&data).await?,
+            },
+            operation => operation,
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        let id = match self.database.queue(&operation) {
+            Ok(id) => id,
+            Err(error) => {
+                if let Some(data) = operation.data() {
+                    let _ = std::fs::remove_file(data);
+                }
+                return Err(io::Error::other(error));
+            }
+        };
+        log::info!("offline, queued {:?}", operation);
+        self.apply(&operation);
+        pending.push((id, operation));
+
+        Ok(())
+    }
+
+    async fn copy(&self, data: &Path) -> io::Result<PathBuf> {
+        let copy = self
+            .dir
+            .join(self.next_file.fetch_add(1, Ordering::Relaxed).to_string());
+        tokio::fs::copy(data, &copy).await?;
+        tokio::fs::File::open(&copy).await?.sync_all().await?;
+        Ok(copy)
+    }
+
+    // Local file with the content path will have once the journal is replayed, if an
+    // upload to it is pending
+    pub fn pending_upload(&self, path: &str) -> Option<PathBuf> {
+        let pending = self.pending.lock().unwrap();
+        // Pending uploads by the path they end up at after the operations queued since
+        let mut uploads: HashMap<String, &PathBuf> = HashMap::new();
+
+        for (_, operation) in pending.iter() {
+            match operation {
+                Operation::Upload { path, data, .. } | Operation::Create { path, data } => {
+                    uploads.insert(path.clone(), data);
+                }
+                Operation::Mkdir { .. } => (),
+                Operation::Delete { path } => uploads.retain(|key, _| !is_below(key, path)),
+                Operation::Rename { from, to, .. } => {
+                    uploads.retain(|key, _| !is_below(key, to));
+                    uploads = uploads
+                        .into_iter()
+                        .map(|(key, data)| match key.strip_prefix(from.as_str()) {
+                            Some(rest) if is_below(&key, from) => (format!("{}{}", to, rest), data),
+                            _ => (key, data),
+                        })
+                        .collect();
+                }
+            }
+        }
+
+        uploads.get(path).map(|data| data.to_path_buf())
+    }
+
+    fn apply(&self, operation: &Operation) {
+        match operation {
+            Operation::Upload {
+                path, data, mtime, ..
+            } => self.apply_upload(path, data, *mtime),
+            Operation::Create { path, data } => self.apply_upload(path, data, None),
+            Operation::Mkdir { path } => self.cache.put_local(
+                path,
+                DavItem::Folder(Folder {
+                    name: name(path),
+                    path: path.clone(),
+                    file_id: None,
+                    etag: None,
+                    last_modified: Some(SystemTime::now()),
+                    permissions: None,
+                    lock: None,
+                }),
+            ),
+            Operation::Delete { path } => self.cache.remove_local(path),
+            Operation::Rename { from, to, .. } => self.cache.rename_local(from, to),
+        }
+    }
+
+    fn apply_upload(&self, path: &str, data: &Path, mtime: Option<SystemTime>) {
+        let size = std::fs::metadata(data).map_or(0, |metadata| metadata.len());
+        let found = match self.cache.get_stale(path) {
+            Lookup::Found(item) => Some(*item),
+            _ => None,
+        };
+        let mut file = match found {
+            Some(DavItem::File(file)) => file,
+            _ => nextcloud::File {
+                name: name(path),
+                path: path.to_string(),
+                file_id: None,
+                size: 0,
+                etag: None,
+                last_modified: None,
+                permissions: None,
+                lock: None,
+            },
+        };
+        // Without an etag contents are not taken from the content cache
+        file.size = size;
+        file.etag = None;
+        file.last_modified = Some(mtime.unwrap_or_else(SystemTime::now));
+        self.cache.put_local(path, DavItem::File(file));
+    }
+
+    // Send queued operations to the server until it cannot be reached anymore
+    pub async fn replay(&self) {
+        let _replaying = self.replaying.lock().await;
+        // Etags our uploads replaced, by the etag they replaced
+        let mut etags = HashMap::new();
+
+        loop {
+            let next = self.pending.lock().unwrap().first().cloned();
+            let (id, mut operation) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            operation.rebase(&etags);
+
+            let result = match operation.perform(&self.client, self.conflicts).await {
+                Err(error) if operation.is_done(&error) => Ok(Outcome::Done),
+                Err(error) if !error.is_offline() => self.keep_refused(&operation, error).await,
+                result => result,
+            };
+            let recorded = match &result {
+                Err(error) if error.is_offline() => return,
+                Ok(_) => self.database.finish(id),
+                Err(error) => {
+                    log::error!("conflict, the server refused {:?}: {:?}", operation, error);
+                    self.database.fail(id, &format!("{:?}", error))
+                }
+            };
+            if let Err(error) = recorded {
+                log::warn!("cannot update the journal: {}", error);
+                return;
+            }
+            if let (Ok(_), Some(data)) = (&result, operation.data()) {
+                let _ = std::fs::remove_file(data);
+            }
+
+            // What the server has now, with the operations still queued on top
+            let mut pending = self.pending.lock().unwrap();
+            pending.remove(0);
+            for path in operation.paths() {
+                self.cache.invalidate(path);
+            }
+            if let Ok(Outcome::Uploaded { path, etag }) = &result {
+                self.cache.invalidate(path);
+                // Later uploads of a file that ended up as a conflicted copy conflict too
+                if let Operation::Upload {
+                    path: uploaded,
+                    etag: Some(old),
+                    ..
+                } = &operation
+                {
+                    if uploaded == path {
+                        etags.insert(old.clone(), etag.clone());
+                    }
+                }
+            }
+            for (_, operation) in pending.iter() {
+                self.apply(operation);
+            }
+        }
+    }
+
+    // Nobody is around to see an upload fail during a replay, so ours is put next to the
+    // file as a conflicted copy instead, or at the top if that is refused as well
+    async fn keep_refused(
+        &self,
+        operation: &Operation,
+        error: DavError,
+    ) -> Result<Outcome, DavError> {
+        let (path, data, mtime) = match operation {
+            Operation::Upload {
+                path, data, mtime, ..
+            } => (path, data, *mtime),
+            _ => return Err(error),
+        };
+
+        let copy = conflict_path(path, &hostname(), SystemTime::now());
+        let mut targets = vec![copy.clone()];
+        if copy.contains('/') {
+            targets.push(name(&copy));
+        }
+        for target in targets {
+            match self.client.upload(&target, data, mtime, None).await {
+                Ok(etag) => {
+                    log::warn!(
+                        "the server refused {}: {:?}, kept ours as {}",
+                        path,
+                        error,
+                        target
+                    );
+                    return Ok(Outcome::Uploaded { path: target, etag });
+                }
+                Err(refused) if refused.is_offline() => return Err(refused),
+                Err(refused) => log::warn!("cannot upload {}: {:?}", target, refused),
+            }
+        }
+        Err(error)
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(REPLAY_INTERVAL).await;
+            if !self.is_empty() {
+                self.replay().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_upload() {
+        let upload = |path: &str, data: &str| Operation::Upload {
+            path: path.to_string(),
+            data: PathBuf::from(data),
+            mtime: None,
+            etag: None,
+        };
+        let rename = |from: &str, to: &str| Operation::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+            overwrite: true,
+            etag: None,
+        };
+        let dir =
+            std::env::temp_dir().join(format!("nextcloud-fuse-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = Arc::new(Database::open(&dir.join("cache.sqlite")).unwrap());
+        let cache = Arc::new(MetadataCache::new(
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            None,
+        ));
+        let client = Nextcloud::new(
+            "http://localhost".to_string(),
+            "remote.php/dav".to_string(),
+            "alice".to_string(),
+            "secret".to_string(),
+        );
+        let journal = Journal::new(
+            client,
+            ConflictPolicy::KeepBoth,
+            database,
+            &dir.join("journal"),
+            cache,
+        )
+        .unwrap();
+
+        *journal.pending.lock().unwrap() = vec![
+            (1, upload("Docs/a.txt", "1")),
+            (2, upload("b.txt", "2")),
+            (3, rename("Docs", "Old")),
+            (4, rename("b.txt", "Old/a.txt")),
+            (5, upload("c.txt", "3")),
+            (
+                6,
+                Operation::Delete {
+                    path: "c.txt".to_string(),
+                },
+            ),
+        ];
+        assert_eq!(
+            journal.pending_upload("Old/a.txt"),
+            Some(PathBuf::from("2"))
+        );
+        assert_eq!(journal.pending_upload("Docs/a.txt"), None);
+        assert_eq!(journal.pending_upload("b.txt"), None);
+        assert_eq!(journal.pending_upload("c.txt"), None);
+
+        drop(journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1 +1,18 @@
+mod cache;
+mod conflict;
+mod content;
+mod database;
+mod exclude;
 mod filesystem;
+mod inode_filesystem;
+mod inodes;
+mod journal;
+mod staging;
+mod trash;
+mod versions;
+mod watcher;
+mod xattr;
+
+pub use exclude::Excludes;
+pub use filesystem::{FilesystemOptions, NextcloudFilesystem};
+pub use inode_filesystem::InodeFilesystem;
@@ -0,0 +1,196 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::Range,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// Up to size bytes from offset, fewer at the end of the file
+pub fn read_at(file: &File, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; size];
+    let mut read = 0;
+
+    while read < size {
+        match file.read_at(&mut buffer[read..], offset + read as u64)? {
+            0 => break,
+            count => read += count,
+        }
+    }
+    buffer.truncate(read);
+    Ok(buffer)
+}
+
+// Local copy of a file opened for writing. Writes land here and the whole file is uploaded
+// when it is flushed. The content the file had is copied in as it is needed, parts that
+// are overwritten or cut off first are never fetched.
+pub struct StagedFile {
+    path: PathBuf,
+    file: File,
+    // Parts of the original content that were not copied in yet, in order
+    missing: Vec<Range<u64>>,
+}
+
+impl StagedFile {
+    // Staged copy of a file with size bytes of content, all of it still missing
+    pub fn create(dir: &Path, id: u64, size: u64) -> io::Result<StagedFile> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("staged-{}-{}", std::process::id(), id));
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+        };
+        // A file left behind by an earlier process with the same pid is removed, never reused
+        let file = match open() {
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                std::fs::remove_file(&path)?;
+                open()?
+            }
+            result => result?,
+        };
+        file.set_len(size)?;
+
+        let mut staged = StagedFile {
+            path,
+            file,
+            missing: Vec::new(),
+        };
+        if size > 0 {
+            staged.missing.push(0..size);
+        }
+        Ok(staged)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub fn read_at(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        read_at(&self.file, offset, size)
+    }
+
+    // Parts of offset..offset + size that still have to be copied in
+    pub fn missing(&self, offset: u64, size: u64) -> Vec<Range<u64>> {
+        let end = offset.saturating_add(size);
+        self.missing
+            .iter()
+            .map(|range| range.start.max(offset)..range.end.min(end))
+            .filter(|range| !range.is_empty())
+            .collect()
+    }
+
+    // The original content of range is not needed anymore, it reads as it is now
+    pub fn forget(&mut self, range: Range<u64>) {
+        self.missing = self
+            .missing
+            .iter()
+            .flat_map(|missing| {
+                [
+                    missing.start..missing.end.min(range.start),
+                    missing.start.max(range.end)..missing.end,
+                ]
+            })
+            .filter(|missing| !missing.is_empty())
+            .collect();
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)?;
+        self.forget(offset..offset + data.len() as u64);
+        Ok(())
+    }
+
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)?;
+        self.forget(size..u64::MAX);
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A file handle handed out by open or create
+pub struct OpenFile {
+    // Path relative to the user's files root, follows renames of the open file
+    pub path: String,
+    // Only files opened for writing are staged
+    pub staged: Option<StagedFile>,
+    // Written to since the last upload
+    pub dirty: bool,
+    // Removed while open, or changes lost against the server's, nothing is uploaded anymore
+    pub unlinked: bool,
+    // Etag of the version on the server the staged copy replaces
+    pub etag: Option<String>,
+    // Modification time to upload with, set through setattr
+    pub mtime: Option<SystemTime>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staged_file() {
+        let dir = std::env::temp_dir().join("nextcloud-fuse-staging-test");
+        let mut staged = StagedFile::create(&dir, 1, 0).unwrap();
+        let path = staged.path().to_path_buf();
+        assert!(staged.missing(0, u64::MAX).is_empty());
+
+        staged.write_at(0, b"hello world").unwrap();
+        staged.write_at(6, b"there").unwrap();
+        assert_eq!(staged.read_at(0, 100).unwrap(), b"hello there");
+        assert_eq!(staged.read_at(6, 3).unwrap(), b"the");
+
+        staged.set_len(5).unwrap();
+        assert_eq!(staged.len().unwrap(), 5);
+        assert!(staged.read_at(5, 10).unwrap().is_empty());
+
+        drop(staged);
+        assert!(!path.exists());
+
+        // Only what was neither written nor cut off is missing from the original content
+        let mut staged = StagedFile::create(&dir, 1, 100).unwrap();
+        assert_eq!(staged.len().unwrap(), 100);
+        assert_eq!(staged.missing(0, u64::MAX), [Range { start: 0, end: 100 }]);
+        staged.write_at(10, b"0123456789").unwrap();
+        staged.set_len(90).unwrap();
+        assert_eq!(staged.missing(0, u64::MAX), [0..10, 20..90]);
+        assert_eq!(staged.missing(5, 20), [5..10, 20..25]);
+        assert!(staged.missing(12, 5).is_empty());
+        staged.forget(0..50);
+        assert_eq!(staged.missing(0, u64::MAX), [Range { start: 50, end: 90 }]);
+        staged.set_len(200).unwrap();
+        assert_eq!(staged.missing(0, u64::MAX), [Range { start: 50, end: 90 }]);
+        drop(staged);
+
+        // Whatever is left at the staged path is replaced, links are not followed
+        let target = dir.join("target");
+        std::fs::write(&target, b"keep").unwrap();
+        let path = dir.join(format!("staged-{}-2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        let mut staged = StagedFile::create(&dir, 2, 0).unwrap();
+        staged.write_at(0, b"staged").unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"keep");
+        assert!(!std::fs::symlink_metadata(&path).unwrap().is_symlink());
+        drop(staged);
+        std::fs::remove_file(&target).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use nextcloud::{DavError, Nextcloud, TrashItem};
+
+// Name of the trash bin in the root of the mount, it hides a folder of the same name
+pub const NAME: &str = ".trash";
+
+// Items of one folder of the trash with the names they are shown with
+type Listing = Vec<(String, TrashItem)>;
+
+// The trash bin of the account. Items show up with the names they were deleted with, or
+// with their names in the trash ("<name>.d<deletion time>") where several share one.
+pub struct Trash {
+    client: Nextcloud,
+    ttl: Duration,
+    // Listings by path relative to the trash, "" for the items deleted themselves
+    listings: Mutex<HashMap<String, (Instant, Listing)>>,
+}
+
+// Names to show the items of one folder of the trash with
+fn shown_names(items: Vec<TrashItem>) -> Listing {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for item in &items {
+        *counts.entry(item.name().to_string()).or_default() += 1;
+    }
+
+    items
+        .into_iter()
+        .map(|item| {
+            let name = if counts[item.name()] > 1 {
+                item.item.name().to_string()
+            } else {
+                item.name().to_string()
+            };
+            (name, item)
+        })
+        .collect()
+}
+
+impl Trash {
+    pub fn new(client: Nextcloud, ttl: Duration) -> Self {
+        Self {
+            client,
+            ttl,
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Contents of a folder of the trash, path is relative to the trash
+    pub async fn list(&self, path: &str) -> Result<Vec<(String, TrashItem)>, DavError> {
+        if let Some((fetched, items)) = self.listings.lock().unwrap().get(path) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(items.clone());
+            }
+        }
+
+        let items = shown_names(self.client.trash(path).await?);
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), (Instant::now(), items.clone()));
+        Ok(items)
+    }
+
+    // The item at a path of shown names below the trash, None if there is none
+    pub async fn find(&self, path: &str) -> Result<Option<TrashItem>, DavError> {
+        let mut found: Option<TrashItem> = None;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let folder = found.as_ref().map(|item| item.item.path()).unwrap_or("");
+            let items = self.list(folder).await?;
+            found = match items.into_iter().find(|(shown, _)| shown == name) {
+                Some((_, item)) => Some(item),
+                None => return Ok(None),
+            };
+        }
+
+        Ok(found)
+    }
+
+    // After something was restored or purged
+    pub fn forget(&self) {
+        self.listings.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nextcloud::{DavItem, File};
+
+    fn trashed(path: &str, original_name: &str) -> TrashItem {
+        TrashItem {
+            item: DavItem::File(File {
+                name: path.to_string(),
+                path: path.to_string(),
+                file_id: None,
+                size: 0,
+                etag: None,
+                last_modified: None,
+                permissions: None,
+                lock: None,
+            }),
+            original_name: Some(original_name.to_string()),
+            original_location: Some(original_name.to_string()),
+            deleted: None,
+        }
+    }
+
+    #[test]
+    fn test_shown_names() {
+        let names: Vec<String> = shown_names(vec![
+            trashed("a.txt.d1", "a.txt"),
+            trashed("b.txt.d2", "b.txt"),
+            trashed("a.txt.d3", "a.txt"),
+        ])
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+
+        assert_eq!(names, ["a.txt.d1", "b.txt", "a.txt.d3"]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use nextcloud::{DavError, Nextcloud, Version};
+
+// Name of the folder in the root of the mount with the older versions of all files. It
+// mirrors the tree below it, with every file a folder of its versions.
+pub const NAME: &str = ".versions";
+
+// Versions of one file with the names they are shown with
+type Listing = Vec<(String, Version)>;
+
+pub struct Versions {
+    client: Nextcloud,
+    ttl: Duration,
+    // Listings by file id
+    listings: Mutex<HashMap<u64, (Instant, Listing)>>,
+}
+
+// Versions show up as when they were saved, with the server's name for them added where
+// several were saved in the same second
+fn shown_names(versions: Vec<Version>) -> Listing {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for version in &versions {
+        *counts.entry(version.timestamp()).or_default() += 1;
+    }
+
+    versions
+        .into_iter()
+        .map(|version| {
+            let timestamp = version.timestamp();
+            let name = if counts[&timestamp] > 1 {
+                format!("{}.{}", timestamp, version.item.name())
+            } else {
+                timestamp
+            };
+            (name, version)
+        })
+        .collect()
+}
+
+impl Versions {
+    pub fn new(client: Nextcloud, ttl: Duration) -> Self {
+        Self {
+            client,
+            ttl,
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn list(&self, file_id: u64) -> Result<Vec<(String, Version)>, DavError> {
+        if let Some((fetched, versions)) = self.listings.lock().unwrap().get(&file_id) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(versions.clone());
+            }
+        }
+
+        let mut versions = shown_names(self.client.versions(file_id).await?);
+        versions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(file_id, (Instant::now(), versions.clone()));
+        Ok(versions)
+    }
+
+    pub async fn find(&self, file_id: u64, name: &str) -> Result<Option<Version>, DavError> {
+        Ok(self
+            .list(file_id)
+            .await?
+            .into_iter()
+            .find(|(shown, _)| shown == name)
+            .map(|(_, version)| version))
+    }
+
+    // After a version was restored, which adds the replaced content as one
+    pub fn forget(&self, file_id: u64) {
+        self.listings.lock().unwrap().remove(&file_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nextcloud::{DavItem, File};
+    use std::time::UNIX_EPOCH;
+
+    fn version(name: &str, seconds: u64) -> Version {
+        Version {
+            item: DavItem::File(File {
+                name: name.to_string(),
+                path: name.to_string(),
+                file_id: None,
+                size: 0,
+                etag: None,
+                last_modified: Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+                permissions: None,
+                lock: None,
+            }),
+            label: None,
+        }
+    }
+
+    #[test]
+    fn test_shown_names() {
+        let names: Vec<String> = shown_names(vec![
+            version("1700000000", 1_700_000_000),
+            version("1700000001", 1_700_000_100),
+            version("1700000002", 1_700_000_100),
+        ])
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+
+        assert_eq!(
+            names,
+            [
+                "2023-11-14T22:13:20Z",
+                "2023-11-14T22:15:00Z.1700000001",
+                "2023-11-14T22:15:00Z.1700000002"
+            ]
+        );
+    }
+}
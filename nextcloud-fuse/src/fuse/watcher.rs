@@ -0,0 +1,111 @@
+use std::{
+    ffi::OsString,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::MissedTickBehavior;
+
+use super::{filesystem::NextcloudFilesystem, inodes::InodeTable};
+
+// Something changed on the server at a FUSE path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(OsString),
+    Removed(OsString),
+    Modified(OsString),
+}
+
+// Asks the server for changes made elsewhere every interval and brings the metadata cache
+// up to date with them. The kernel is not told: fuse3 only hands out a Notify along with
+// poll requests, which plain files never get. It keeps entries and attributes for a second
+// and drops the cached pages of a file whenever it is opened, so it catches up on its own.
+pub async fn watch(
+    filesystem: Arc<NextcloudFilesystem>,
+    inodes: Arc<Mutex<InodeTable>>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is right away, the cache was just refreshed by init
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+        if let Err(error) = check(&filesystem, &inodes).await {
+            log::debug!("cannot look for changes: {:?}", error);
+        }
+    }
+}
+
+async fn check(
+    filesystem: &NextcloudFilesystem,
+    inodes: &Mutex<InodeTable>,
+) -> fuse3::Result<Vec<Change>> {
+    let changes = filesystem.changes().await?;
+    for change in &changes {
+        log::debug!("changed on the server: {:?}", change);
+        // Whatever is there by the name next is looked up afresh
+        if let Change::Removed(path) = change {
+            inodes.lock().unwrap().remove(path);
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use fuse3::{path::PathFilesystem, raw::Request};
+    use nextcloud::mock::MockServer;
+
+    use super::*;
+    use crate::fuse::filesystem::FilesystemOptions;
+
+    #[tokio::test]
+    async fn test_check() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"a");
+        server.add_file("Docs/b.txt", b"b");
+        let filesystem =
+            NextcloudFilesystem::new(server.client(), FilesystemOptions::test("watcher"));
+        let inodes = Mutex::new(InodeTable::new());
+
+        // What the kernel has seen so far
+        for path in ["/", "/Docs"] {
+            filesystem
+                .readdir(Request::default(), OsStr::new(path), 0, 0)
+                .await
+                .unwrap();
+        }
+        for path in ["Docs", "Docs/a.txt", "Docs/b.txt"] {
+            let fuse_path = format!("/{}", path);
+            inodes
+                .lock()
+                .unwrap()
+                .bind(OsStr::new(&fuse_path), server.file_id(path));
+        }
+        assert!(check(&filesystem, &inodes).await.unwrap().is_empty());
+
+        server.add_file("Docs/a.txt", b"changed");
+        server.add_file("Docs/c.txt", b"c");
+        server.client().delete("Docs/b.txt").await.unwrap();
+        let changes = check(&filesystem, &inodes).await.unwrap();
+        assert!(changes.contains(&Change::Modified(OsString::from("/Docs/a.txt"))));
+        assert!(changes.contains(&Change::Added(OsString::from("/Docs/c.txt"))));
+        assert!(changes.contains(&Change::Removed(OsString::from("/Docs/b.txt"))));
+        assert_eq!(
+            inodes.lock().unwrap().inode(OsStr::new("/Docs/b.txt")),
+            None
+        );
+        assert!(inodes
+            .lock()
+            .unwrap()
+            .inode(OsStr::new("/Docs/a.txt"))
+            .is_some());
+
+        // Nothing new since
+        assert!(check(&filesystem, &inodes).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use fuse3::{path::reply::ReplyXAttr, Errno, Result};
+use nextcloud::Metadata;
+
+// Nextcloud's properties are exposed below this prefix, everything else in user.* is stored
+// on the server as a dead property
+pub const PREFIX: &str = "user.nextcloud.";
+pub const FAVORITE: &str = "user.nextcloud.favorite";
+
+pub fn is_user(name: &str) -> bool {
+    name.starts_with("user.") && !name.starts_with(PREFIX)
+}
+
+// The properties the server sent, as attribute names and text values
+pub fn nextcloud_attributes(metadata: &Metadata) -> Vec<(String, Vec<u8>)> {
+    let flag = |value: bool| if value { "1" } else { "0" }.to_string();
+    let share_types = metadata
+        .share_types
+        .iter()
+        .map(|share_type| share_type.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+
+    [
+        ("fileid", metadata.file_id.map(|id| id.to_string())),
+        ("etag", metadata.etag.clone()),
+        ("permissions", metadata.permissions.clone()),
+        ("owner", metadata.owner_display_name.clone()),
+        ("share-types", Some(share_types)),
+        ("favorite", Some(flag(metadata.favorite))),
+        ("has-preview", Some(flag(metadata.has_preview))),
+        (
+            "comments-unread",
+            Some(metadata.comments_unread.to_string()),
+        ),
+        ("content-type", metadata.content_type.clone()),
+        ("checksums", metadata.checksums.clone()),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((format!("{}{}", PREFIX, name), value?.into_bytes())))
+    .collect()
+}
+
+pub fn parse_flag(value: &[u8]) -> Result<bool> {
+    match std::str::from_utf8(value).map(str::trim) {
+        Ok("1" | "true" | "yes") => Ok(true),
+        Ok("0" | "false" | "no" | "") => Ok(false),
+        _ => Err(libc::EINVAL.into()),
+    }
+}
+
+// With a size of 0 the caller only wants to know how large the value is
+pub fn reply(value: Vec<u8>, size: u32) -> Result<ReplyXAttr> {
+    if size == 0 {
+        Ok(ReplyXAttr::Size(value.len() as u32))
+    } else if value.len() > size as usize {
+        Err(Errno::from(libc::ERANGE))
+    } else {
+        Ok(ReplyXAttr::Data(value.into()))
+    }
+}
+
+// listxattr wants the names one after another, each ending in a NUL
+pub fn name_list<'a>(names: impl Iterator<Item = &'a str>) -> Vec<u8> {
+    names.flat_map(|name| name.bytes().chain([0])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let metadata = Metadata {
+            file_id: Some(42),
+            share_types: vec![0, 3],
+            favorite: true,
+            ..Default::default()
+        };
+        let attributes = nextcloud_attributes(&metadata);
+        let value = |name: &str| {
+            attributes
+                .iter()
+                .find(|(attribute, _)| attribute == name)
+                .map(|(_, value)| String::from_utf8(value.clone()).unwrap())
+        };
+
+        assert_eq!(value("user.nextcloud.fileid").as_deref(), Some("42"));
+        assert_eq!(value("user.nextcloud.share-types").as_deref(), Some("0,3"));
+        assert_eq!(value(FAVORITE).as_deref(), Some("1"));
+        assert_eq!(value("user.nextcloud.etag"), None);
+
+        assert!(is_user("user.comment"));
+        assert!(!is_user(FAVORITE));
+        assert!(!is_user("security.selinux"));
+        assert!(parse_flag(b"1\n").unwrap());
+        assert!(parse_flag(b"maybe").is_err());
+        assert_eq!(name_list(["a", "bc"].into_iter()), b"a\0bc\0");
+    }
+}
@@ -1,5 +1,414 @@
+use std::{
+    fs::{DirBuilder, File, Permissions},
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use cli::{Command, ConflictPolicy, LoginArgs, LogoutArgs, MountArgs, OfflineMode, Store};
+use config::{Config, Profile, DEFAULT_DAV_PATH};
+use credentials::{Account, Keyring, SecretServiceKeyring};
+use fuse3::{
+    raw::{MountHandle, Session},
+    MountOptions,
+};
+use nextcloud::Nextcloud;
+use tokio::signal::{self, unix::SignalKind};
+
+mod cli;
+mod config;
+mod credentials;
 mod fuse;
 
-fn main() {
-    println!("Hello, world!");
+// Metadata is served from memory this many seconds before being revalidated
+const DEFAULT_ATTR_TIMEOUT: u64 = 30;
+const DEFAULT_NEGATIVE_TIMEOUT: u64 = 5;
+// Seconds between looking for changes made elsewhere
+const DEFAULT_POLL_INTERVAL: u64 = 30;
+// Where a mount running in the background logs to, in the data dir
+const LOG_FILE: &str = "nextcloud-fuse.log";
+// Content cache blocks in KiB and the content cache limit in MiB
+const DEFAULT_BLOCK_SIZE: u64 = 1024;
+const DEFAULT_CACHE_SIZE: u64 = 1024;
+
+fn main() -> ExitCode {
+    let cli = cli::parse(std::env::args_os().collect());
+
+    let result = match cli.command {
+        Command::Mount(mut args) => {
+            if let Err(error) = args.apply_options() {
+                eprintln!("nextcloud-fuse: {}", error);
+                return ExitCode::from(2);
+            }
+            env_logger::Builder::new()
+                .filter_level(args.log_level)
+                .init();
+
+            mount(*args)
+        }
+        Command::Login(args) => block_on(login(args)),
+        Command::Logout(args) => block_on(logout(args)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("nextcloud-fuse: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Runs a future on a runtime without worker threads, which is gone again before forking
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start runtime")
+        .block_on(future)
+}
+
+fn account(url: &str, user: Option<&String>) -> Result<Account, String> {
+    let (origin, url_user) = cli::split_url(url)?;
+    let user = user
+        .cloned()
+        .or(url_user)
+        .ok_or("no user given, pass --user or put it in the URL")?;
+
+    Ok(Account { origin, user })
+}
+
+async fn login(args: LoginArgs) -> Result<(), String> {
+    let account = account(&args.url, args.user.as_ref())?;
+
+    let prompt = format!("App password for {}@{}: ", account.user, account.origin);
+    let password = rpassword::prompt_password(prompt).map_err(|error| error.to_string())?;
+
+    // Refuse to store a password the server does not accept
+    let client = Nextcloud::new(
+        account.origin.clone(),
+        DEFAULT_DAV_PATH.to_string(),
+        account.user.clone(),
+        password.clone(),
+    );
+    client
+        .user_info()
+        .await
+        .map_err(|error| format!("cannot log in: {:?}", error))?;
+
+    match args.store {
+        Store::Keyring => SecretServiceKeyring.store(&account, &password).await,
+        Store::File => {
+            let path = args
+                .credentials_file
+                .unwrap_or_else(credentials::default_credentials_file);
+            credentials::file_store(&path, &account, &password)
+        }
+    }
+    .map_err(|error| error.to_string())
+}
+
+async fn logout(args: LogoutArgs) -> Result<(), String> {
+    let account = account(&args.url, args.user.as_ref())?;
+    let path = args
+        .credentials_file
+        .unwrap_or_else(credentials::default_credentials_file);
+
+    // The keyring may not be reachable, the file still has to be cleaned up
+    let from_keyring = SecretServiceKeyring.delete(&account).await;
+    let from_file = credentials::file_delete(&path, &account).map_err(|error| error.to_string())?;
+
+    match from_keyring {
+        Ok(from_keyring) if from_keyring || from_file => Ok(()),
+        Ok(_) => Err("no stored password found".to_string()),
+        Err(_) if from_file => Ok(()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+// A profile ready to be mounted
+struct Mount {
+    name: String,
+    profile: Profile,
+    mountpoint: PathBuf,
+    client: Nextcloud,
+}
+
+fn prepare(name: String, mut profile: Profile) -> Result<Mount, String> {
+    // The daemon leaves the directory it was started in
+    let mountpoint = profile
+        .mountpoint
+        .as_deref()
+        .map(std::path::absolute)
+        .ok_or_else(|| format!("{}: no mountpoint given", name))?
+        .map_err(|error| format!("{}: {}", name, error))?;
+    if let Some(cache_dir) = &profile.cache_dir {
+        profile.cache_dir =
+            Some(std::path::absolute(cache_dir).map_err(|error| format!("{}: {}", name, error))?);
+    }
+    let account = account(&profile.url, profile.user.as_ref())?;
+    let credentials_file = profile
+        .credentials_file
+        .clone()
+        .unwrap_or_else(credentials::default_credentials_file);
+    let password = block_on(credentials::find_password(
+        &SecretServiceKeyring,
+        &account,
+        profile.password_command.as_deref(),
+        &credentials_file,
+        &credentials::password_variable(&name),
+    ))
+    .map_err(|error| format!("{}: {}", name, error))?;
+
+    create_private_dir(&cache_dir(&profile))?;
+    if profile.offline == Some(OfflineMode::ReadWrite) {
+        create_private_dir(&config::data_dir())?;
+    }
+
+    let client = Nextcloud::new(
+        account.origin,
+        profile.dav_path().to_string(),
+        account.user,
+        password,
+    );
+
+    Ok(Mount {
+        name,
+        profile,
+        mountpoint,
+        client,
+    })
+}
+
+fn mount(args: MountArgs) -> Result<(), String> {
+    let config = Config::load(args.config.as_deref())?;
+    let mounts = config
+        .resolve(&args)?
+        .into_iter()
+        .map(|(name, profile)| prepare(name, profile))
+        .collect::<Result<Vec<Mount>, String>>()?;
+
+    // Report the outcome of mounting to the waiting parent, nothing to report in the
+    // foreground
+    let ready = if args.foreground {
+        None
+    } else {
+        Some(daemonize()?)
+    };
+
+    let runtime = tokio::runtime::Runtime::new().map_err(|error| error.to_string())?;
+    runtime.block_on(serve_all(mounts, ready))
+}
+
+// Fork into the background. The parent stays until the child reports whether the mount
+// succeeded, so mount(8) and fstab see errors.
+fn daemonize() -> Result<File, String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().to_string()),
+        0 => {
+            drop(reader);
+            unsafe { libc::setsid() };
+            Ok(writer)
+        }
+        _ => {
+            drop(writer);
+            let mut status = [1u8];
+            let _ = reader.read_exact(&mut status);
+            std::process::exit(status[0] as i32);
+        }
+    }
+}
+
+// Once the parent is gone there is no terminal to write to, nor a reason to keep the
+// directory we were started in busy. Log messages go to a file in the data dir instead.
+fn detach() {
+    let _ = std::env::set_current_dir("/");
+    let null = match File::options().read(true).write(true).open("/dev/null") {
+        Ok(null) => null,
+        Err(_) => return,
+    };
+    let log = create_private_dir(&config::data_dir()).ok().and_then(|()| {
+        File::options()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(config::data_dir().join(LOG_FILE))
+            .ok()
+    });
+
+    unsafe { libc::dup2(null.as_raw_fd(), 0) };
+    for fd in 1..3 {
+        let target = log.as_ref().unwrap_or(&null);
+        unsafe { libc::dup2(target.as_raw_fd(), fd) };
+    }
+}
+
+// Ctrl-C in the foreground, kill(1) and systemd in the background
+async fn terminated() {
+    match signal::unix::signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = signal::ctrl_c() => (),
+                _ = terminate.recv() => (),
+            }
+        }
+        Err(_) => {
+            let _ = signal::ctrl_c().await;
+        }
+    }
+}
+
+// Mount every profile, then wait until all of them are unmounted
+async fn serve_all(mounts: Vec<Mount>, ready: Option<File>) -> Result<(), String> {
+    let mut handles = Vec::new();
+    let mut failed = false;
+
+    for mount in mounts {
+        let name = mount.name.clone();
+        match start(mount).await {
+            Ok(handle) => handles.push((name, handle)),
+            Err(error) => {
+                log::error!("{}: {}", name, error);
+                eprintln!("nextcloud-fuse: {}: {}", name, error);
+                failed = true;
+            }
+        }
+    }
+
+    if let Some(mut ready) = ready {
+        let _ = ready.write_all(&[failed as u8]);
+        detach();
+    }
+    if handles.is_empty() {
+        return Err("nothing mounted".to_string());
+    }
+
+    let sessions = handles.into_iter().map(|(name, mut handle)| async move {
+        let result = tokio::select! {
+            result = &mut handle => result,
+            _ = terminated() => handle.unmount().await,
+        };
+        if let Err(error) = &result {
+            log::error!("{}: {}", name, error);
+        }
+        result.is_ok()
+    });
+
+    let results = futures_util::future::join_all(sessions).await;
+    if failed || results.contains(&false) {
+        Err("not all mounts ended cleanly".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Local state of a mount that can be thrown away: files open for writing in staging/,
+// cached contents in content/<profile name>/ and the index in <profile name>.sqlite.
+// Uploads made offline are not, they go to journal/<profile name>/ in the data dir.
+fn cache_dir(profile: &Profile) -> PathBuf {
+    match &profile.cache_dir {
+        Some(cache_dir) => cache_dir.clone(),
+        None => config::cache_dir(),
+    }
+}
+
+// Staged and cached files are opened by name, so nobody else may be able to
+// add or swap files in the directory
+fn create_private_dir(dir: &Path) -> Result<(), String> {
+    let error = |error: std::io::Error| format!("cannot create {}: {}", dir.display(), error);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(error)?;
+    let metadata = std::fs::metadata(dir).map_err(error)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } {
+        return Err(format!("{} is not a directory owned by us", dir.display()));
+    }
+    // mode only applies to new directories
+    if metadata.mode() & 0o077 != 0 {
+        std::fs::set_permissions(dir, Permissions::from_mode(0o700)).map_err(error)?;
+    }
+    Ok(())
+}
+
+async fn start(mount: Mount) -> Result<MountHandle, String> {
+    let Mount {
+        name,
+        profile,
+        mountpoint,
+        client,
+    } = mount;
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let uid = profile.uid.unwrap_or(uid);
+    let cache_name = name.replace('/', "_");
+    let gid = profile.gid.unwrap_or(gid);
+
+    let mut patterns = match &profile.exclude_file {
+        Some(file) => std::fs::read_to_string(file)
+            .map_err(|error| format!("{}: {}", file.display(), error))?,
+        None => String::new(),
+    };
+    for pattern in &profile.exclude {
+        patterns.push('\n');
+        patterns.push_str(pattern);
+    }
+
+    let filesystem = fuse::NextcloudFilesystem::new(
+        client,
+        fuse::FilesystemOptions {
+            root: profile.remote_path().to_string(),
+            uid,
+            gid,
+            umask: profile.umask.unwrap_or(0o022),
+            read_only: profile.read_only,
+            staging_dir: cache_dir(&profile).join("staging"),
+            attr_timeout: Duration::from_secs(profile.attr_timeout.unwrap_or(DEFAULT_ATTR_TIMEOUT)),
+            negative_timeout: Duration::from_secs(
+                profile.negative_timeout.unwrap_or(DEFAULT_NEGATIVE_TIMEOUT),
+            ),
+            content_dir: cache_dir(&profile).join("content").join(&cache_name),
+            block_size: profile.block_size.unwrap_or(DEFAULT_BLOCK_SIZE).max(1) * 1024,
+            cache_size: profile.cache_size.unwrap_or(DEFAULT_CACHE_SIZE) * 1024 * 1024,
+            database: Some(cache_dir(&profile).join(format!("{}.sqlite", cache_name))),
+            offline: profile.offline.unwrap_or(OfflineMode::ReadOnly),
+            journal_dir: config::data_dir().join("journal").join(&cache_name),
+            conflicts: profile.conflicts.unwrap_or(ConflictPolicy::KeepBoth),
+            trash: profile.trash,
+            versions: profile.versions,
+            excludes: fuse::Excludes::new(&patterns, &profile.allow, &profile.deny),
+            poll_interval: Duration::from_secs(
+                profile.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            ),
+        },
+    );
+
+    let mut mount_options = MountOptions::default();
+    mount_options
+        .fs_name("nextcloud")
+        .read_only(profile.read_only)
+        .allow_other(profile.allow_other)
+        .uid(uid)
+        .gid(gid);
+
+    // Served through the inode API so inode numbers follow oc:fileid
+    let handle = Session::new(mount_options)
+        .mount_with_unprivileged(fuse::InodeFilesystem::new(filesystem), &mountpoint)
+        .await
+        .map_err(|error| format!("cannot mount {}: {}", mountpoint.display(), error))?;
+    log::info!("mounted {} on {}", profile.url, mountpoint.display());
+
+    Ok(handle)
 }
@@ -1,3 +1,12 @@
+use std::time::SystemTime;
+
+use super::{
+    http_date::parse_http_date,
+    lock::{ActiveLock, NcLock},
+    prop::MultiStatusResponse,
+    xml::XmlTag,
+};
+
 pub fn mkcol_method() -> reqwest::Method {
     reqwest::Method::from_bytes(b"MKCOL").unwrap()
 }
@@ -13,6 +22,15 @@ pub fn copy_method() -> reqwest::Method {
 pub fn move_method() -> reqwest::Method {
     reqwest::Method::from_bytes(b"MOVE").unwrap()
 }
+pub fn lock_method() -> reqwest::Method {
+    reqwest::Method::from_bytes(b"LOCK").unwrap()
+}
+pub fn unlock_method() -> reqwest::Method {
+    reqwest::Method::from_bytes(b"UNLOCK").unwrap()
+}
+pub fn report_method() -> reqwest::Method {
+    reqwest::Method::from_bytes(b"REPORT").unwrap()
+}
 
 #[derive(Debug)]
 pub enum DavError {
@@ -21,25 +39,203 @@ pub enum DavError {
     XmlParse(quick_xml::Error),
     NoContent,
     InvariantViolation,
+    // The resource is locked by someone else (423)
+    Locked,
+    UnexpectedStatus(reqwest::StatusCode),
+    // The server is in maintenance mode or failing, either reported by the server
+    // or because the circuit breaker is open
+    ServerUnavailable,
+    // An OCS API call answered with a failure status code and message
+    OcsFailure(u16, String),
+    // The notify_push websocket failed or was closed
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+    // notify_push did not accept the credentials, with its message
+    PushRejected(String),
+    // Reading the local file to upload failed
+    Io(std::io::Error),
 }
 
+impl DavError {
+    // The server could not be reached at all, as opposed to refusing the request
+    pub fn is_offline(&self) -> bool {
+        match self {
+            DavError::Network(error) => !error.is_builder() && !error.is_status(),
+            DavError::ServerUnavailable => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Folder {
     pub name: String,
     pub path: String,
+    // Stays the same across renames and content changes
+    pub file_id: Option<u64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    // oc:permissions letters, e.g. "RGDNVCK"
+    pub permissions: Option<String>,
+    pub lock: Option<NcLock>,
 }
 
+#[derive(Debug, Clone)]
 pub struct File {
     pub name: String,
     pub path: String,
+    pub file_id: Option<u64>,
     pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    pub permissions: Option<String>,
+    pub lock: Option<NcLock>,
 }
 
+#[derive(Debug, Clone)]
 pub enum DavItem {
     Folder(Folder),
     File(File),
 }
 
+impl DavItem {
+    // Properties from_response reads
+    pub fn props() -> Vec<XmlTag> {
+        let mut props = vec![
+            XmlTag::new("d".to_string(), "resourcetype".to_string()),
+            XmlTag::new("d".to_string(), "getcontentlength".to_string()),
+            XmlTag::new("d".to_string(), "getetag".to_string()),
+            XmlTag::new("d".to_string(), "getlastmodified".to_string()),
+            XmlTag::new("oc".to_string(), "fileid".to_string()),
+            XmlTag::new("oc".to_string(), "permissions".to_string()),
+        ];
+        props.extend(NcLock::props());
+        props
+    }
+
+    // Build an item from a PROPFIND response, root_path is the path part of the files URL
+    pub fn from_response(response: &MultiStatusResponse, root_path: &str) -> DavItem {
+        let href = percent_encoding::percent_decode_str(&response.href).decode_utf8_lossy();
+        let root_path = percent_encoding::percent_decode_str(root_path).decode_utf8_lossy();
+
+        let path = href
+            .strip_prefix(root_path.as_ref())
+            .unwrap_or(&href)
+            .trim_matches('/')
+            .to_string();
+        let name = path.rsplit('/').next().unwrap_or_default().to_string();
+        let lock = NcLock::from_response(response);
+        let etag = response
+            .prop_text("d", "getetag")
+            .map(|etag| etag.trim().trim_matches('"').to_string());
+        let last_modified = response
+            .prop_text("d", "getlastmodified")
+            .and_then(|date| parse_http_date(date.trim()));
+        let file_id = response
+            .prop_text("oc", "fileid")
+            .and_then(|id| id.trim().parse().ok());
+        let permissions = response
+            .prop_text("oc", "permissions")
+            .map(|permissions| permissions.trim().to_string());
+
+        let is_collection = response
+            .prop("d", "resourcetype")
+            .map(|resource_type| {
+                resource_type
+                    .children_vec()
+                    .iter()
+                    .any(|child| child.tag().name == "collection")
+            })
+            .unwrap_or(false);
+
+        if is_collection {
+            DavItem::Folder(Folder {
+                name,
+                path,
+                file_id,
+                etag,
+                last_modified,
+                permissions,
+                lock,
+            })
+        } else {
+            DavItem::File(File {
+                name,
+                path,
+                file_id,
+                size: response
+                    .prop_text("d", "getcontentlength")
+                    .and_then(|size| size.trim().parse().ok())
+                    .unwrap_or(0),
+                etag,
+                last_modified,
+                permissions,
+                lock,
+            })
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            DavItem::Folder(folder) => &folder.name,
+            DavItem::File(file) => &file.name,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            DavItem::Folder(folder) => &folder.path,
+            DavItem::File(file) => &file.path,
+        }
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        match self {
+            DavItem::Folder(folder) => folder.etag.as_deref(),
+            DavItem::File(file) => file.etag.as_deref(),
+        }
+    }
+
+    pub fn file_id(&self) -> Option<u64> {
+        match self {
+            DavItem::Folder(folder) => folder.file_id,
+            DavItem::File(file) => file.file_id,
+        }
+    }
+
+    pub fn permissions(&self) -> Option<&str> {
+        match self {
+            DavItem::Folder(folder) => folder.permissions.as_deref(),
+            DavItem::File(file) => file.permissions.as_deref(),
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        match self {
+            DavItem::Folder(folder) => folder.last_modified,
+            DavItem::File(file) => file.last_modified,
+        }
+    }
+
+    pub fn lock(&self) -> Option<&NcLock> {
+        match self {
+            DavItem::Folder(folder) => folder.lock.as_ref(),
+            DavItem::File(file) => file.lock.as_ref(),
+        }
+    }
+}
+
 pub trait DavProvider {
     fn files_url_string(&self) -> String;
+    fn uploads_url_string(&self) -> String;
+    fn trashbin_url_string(&self) -> String;
+    fn versions_url_string(&self) -> String;
+    fn ocs_url_string(&self) -> String;
     fn add_auth_header(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder;
+    // Requests are built on it so they share its connection pool
+    fn http_client(&self) -> &reqwest::Client;
+
+    // A lock we hold on path, its token is sent along with modifying requests
+    fn active_lock(&self, _path: &str) -> Option<ActiveLock> {
+        None
+    }
 }
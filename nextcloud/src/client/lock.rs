@@ -0,0 +1,240 @@
+use quick_xml::{events::Event, Reader};
+
+use super::{
+    dav::DavError,
+    prop::MultiStatusResponse,
+    xml::{ToXml, XmlTag},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockTimeout {
+    Seconds(u32),
+    Infinite,
+}
+
+impl LockTimeout {
+    pub fn header_value(&self) -> String {
+        match self {
+            LockTimeout::Seconds(seconds) => format!("Second-{}", seconds),
+            LockTimeout::Infinite => "Infinite".to_string(),
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<LockTimeout> {
+        // The server may answer with a list of timeouts, the first one is the one in effect
+        let value = value.split(',').next()?.trim();
+
+        if value.eq_ignore_ascii_case("Infinite") {
+            Some(LockTimeout::Infinite)
+        } else if value.len() > 7 && value[..7].eq_ignore_ascii_case("Second-") {
+            value[7..].parse::<u32>().ok().map(LockTimeout::Seconds)
+        } else {
+            None
+        }
+    }
+}
+
+// Body of a LOCK request, we only ever ask for exclusive write locks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub owner: String,
+}
+
+impl ToXml for LockInfo {
+    fn to_xml(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+            <d:lockinfo xmlns:d="DAV:">
+                <d:lockscope><d:exclusive /></d:lockscope>
+                <d:locktype><d:write /></d:locktype>
+                <d:owner>{}</d:owner>
+            </d:lockinfo>"#,
+            quick_xml::escape::escape(&self.owner)
+        )
+    }
+}
+
+// A lock we hold on the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveLock {
+    pub path: String,
+    pub token: String,
+    pub owner: Option<String>,
+    pub timeout: Option<LockTimeout>,
+}
+
+impl ActiveLock {
+    // Value of the If header that has to accompany requests modifying the locked resource
+    pub fn if_header(&self) -> String {
+        format!("(<{}>)", self.token)
+    }
+
+    // The same as a tagged list, for a locked resource other than the request URL such as
+    // the Destination of a MOVE
+    pub fn tagged_if_header(&self, url: &str) -> String {
+        format!("<{}> {}", url, self.if_header())
+    }
+}
+
+// Parse the d:prop/d:lockdiscovery body returned by a successful LOCK
+pub fn parse_lock_response(
+    path: &str,
+    lock_token_header: Option<&str>,
+    body: &str,
+) -> Result<ActiveLock, DavError> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+
+    let mut stack: Vec<XmlTag> = Vec::new();
+
+    let mut token: Option<String> = None;
+    let mut owner: Option<String> = None;
+    let mut timeout: Option<LockTimeout> = None;
+
+    loop {
+        match reader.read_event().map_err(DavError::XmlParse)? {
+            Event::Start(e) => stack.push(XmlTag::from(e.name())),
+            Event::End(_) => {
+                stack.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape().map_err(DavError::XmlParse)?.to_string();
+                let parent = stack.iter().rev().nth(1).map(|tag| tag.name.as_str());
+
+                match stack.last().map(|tag| tag.name.as_str()) {
+                    Some("href") if parent == Some("locktoken") => token = Some(text),
+                    Some("timeout") => timeout = LockTimeout::parse(&text),
+                    // d:owner may contain plain text or an d:href
+                    Some("owner") => owner = Some(text),
+                    Some("href") if parent == Some("owner") => owner = Some(text),
+                    _ => (),
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    // The Lock-Token header wins, it is the only place the token has to be present
+    let token = match lock_token_header {
+        Some(header) => Some(
+            header
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string(),
+        ),
+        None => token,
+    };
+
+    match token {
+        Some(token) if !token.is_empty() => Ok(ActiveLock {
+            path: path.to_string(),
+            token,
+            owner,
+            timeout,
+        }),
+        _ => Err(DavError::NoContent),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NcLockOwnerType {
+    User,
+    App,
+    Token,
+}
+
+// Lock state as reported by the Nextcloud files_lock app, these locks are read only for us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NcLock {
+    pub owner: Option<String>,
+    pub owner_display_name: Option<String>,
+    pub owner_type: Option<NcLockOwnerType>,
+    // Unix timestamp of when the lock was taken
+    pub time: Option<u64>,
+    // Seconds after time at which the lock expires, 0 means never
+    pub timeout: Option<u64>,
+}
+
+impl NcLock {
+    pub fn props() -> Vec<XmlTag> {
+        vec![
+            XmlTag::new("nc".to_string(), "lock".to_string()),
+            XmlTag::new("nc".to_string(), "lock-owner".to_string()),
+            XmlTag::new("nc".to_string(), "lock-owner-displayname".to_string()),
+            XmlTag::new("nc".to_string(), "lock-owner-type".to_string()),
+            XmlTag::new("nc".to_string(), "lock-time".to_string()),
+            XmlTag::new("nc".to_string(), "lock-timeout".to_string()),
+        ]
+    }
+
+    pub fn from_response(response: &MultiStatusResponse) -> Option<NcLock> {
+        match response.prop_text("nc", "lock") {
+            Some(locked) if locked.trim() == "1" || locked.trim() == "true" => (),
+            _ => return None,
+        }
+
+        Some(NcLock {
+            owner: response.prop_text("nc", "lock-owner").cloned(),
+            owner_display_name: response.prop_text("nc", "lock-owner-displayname").cloned(),
+            owner_type: match response
+                .prop_text("nc", "lock-owner-type")
+                .map(|t| t.trim())
+            {
+                Some("0") => Some(NcLockOwnerType::User),
+                Some("1") => Some(NcLockOwnerType::App),
+                Some("2") => Some(NcLockOwnerType::Token),
+                _ => None,
+            },
+            time: response
+                .prop_text("nc", "lock-time")
+                .and_then(|t| t.trim().parse().ok()),
+            timeout: response
+                .prop_text("nc", "lock-timeout")
+                .and_then(|t| t.trim().parse().ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lock_response() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+        <d:prop xmlns:d="DAV:">
+          <d:lockdiscovery>
+            <d:activelock>
+              <d:lockscope><d:exclusive/></d:lockscope>
+              <d:locktype><d:write/></d:locktype>
+              <d:depth>infinity</d:depth>
+              <d:owner>nextcloud-fuse</d:owner>
+              <d:timeout>Second-1800</d:timeout>
+              <d:locktoken><d:href>opaquelocktoken:e71d4fae-5dec-22d6-fea5-00a0c91e6be4</d:href></d:locktoken>
+              <d:lockroot><d:href>/remote.php/dav/files/user/a.odt</d:href></d:lockroot>
+            </d:activelock>
+          </d:lockdiscovery>
+        </d:prop>"#;
+
+        let lock = parse_lock_response("a.odt", None, body).unwrap();
+        assert_eq!(
+            lock.token,
+            "opaquelocktoken:e71d4fae-5dec-22d6-fea5-00a0c91e6be4"
+        );
+        assert_eq!(lock.owner.as_deref(), Some("nextcloud-fuse"));
+        assert_eq!(lock.timeout, Some(LockTimeout::Seconds(1800)));
+        assert_eq!(
+            lock.if_header(),
+            "(<opaquelocktoken:e71d4fae-5dec-22d6-fea5-00a0c91e6be4>)"
+        );
+        assert_eq!(
+            lock.tagged_if_header("https://cloud.example.com/a.odt"),
+            "<https://cloud.example.com/a.odt> (<opaquelocktoken:e71d4fae-5dec-22d6-fea5-00a0c91e6be4>)"
+        );
+
+        let lock = parse_lock_response("a.odt", Some("<opaquelocktoken:abc>"), body).unwrap();
+        assert_eq!(lock.token, "opaquelocktoken:abc");
+    }
+}
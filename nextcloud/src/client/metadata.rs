@@ -0,0 +1,186 @@
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
+
+use super::{
+    prop::{MultiStatusResponse, PropStatStatus},
+    xml::XmlTag,
+};
+
+// Namespace of the dead properties set through set_attribute, "nf" in requests and parsed
+// responses
+pub const ATTRIBUTE_NAMESPACE: &str = "https://github.com/jthoward64/nextcloud-fuse/ns";
+
+// Bytes of attribute values that are escaped, so any value survives as XML text. Spaces
+// too because the parser trims text.
+const VALUE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>');
+
+// Nextcloud's own properties of a file or folder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_id: Option<u64>,
+    pub etag: Option<String>,
+    pub permissions: Option<String>,
+    pub owner_display_name: Option<String>,
+    // 0 user, 1 group, 3 public link, 4 email, 6 federated, ...
+    pub share_types: Vec<u32>,
+    pub favorite: bool,
+    pub has_preview: bool,
+    pub comments_unread: u64,
+    pub content_type: Option<String>,
+    // Space separated "algorithm:hash" pairs, e.g. "SHA1:... MD5:..."
+    pub checksums: Option<String>,
+}
+
+impl Metadata {
+    pub fn props() -> Vec<XmlTag> {
+        [
+            ("oc", "fileid"),
+            ("d", "getetag"),
+            ("oc", "permissions"),
+            ("oc", "owner-display-name"),
+            ("oc", "share-types"),
+            ("oc", "favorite"),
+            ("nc", "has-preview"),
+            ("oc", "comments-unread"),
+            ("d", "getcontenttype"),
+            ("oc", "checksums"),
+        ]
+        .iter()
+        .map(|(namespace, name)| XmlTag::new(namespace.to_string(), name.to_string()))
+        .collect()
+    }
+
+    pub fn from_response(response: &MultiStatusResponse) -> Metadata {
+        let text = |namespace: &str, name: &str| {
+            response
+                .prop_text(namespace, name)
+                .map(|text| text.trim().to_string())
+                .filter(|text| !text.is_empty())
+        };
+        let children = |namespace: &str, name: &str| -> Vec<String> {
+            response
+                .prop(namespace, name)
+                .map(|prop| {
+                    prop.children_vec()
+                        .iter()
+                        .filter_map(|child| child.text())
+                        .map(|text| text.trim().to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Metadata {
+            file_id: text("oc", "fileid").and_then(|id| id.parse().ok()),
+            etag: text("d", "getetag").map(|etag| etag.trim_matches('"').to_string()),
+            permissions: text("oc", "permissions"),
+            owner_display_name: text("oc", "owner-display-name"),
+            share_types: children("oc", "share-types")
+                .iter()
+                .filter_map(|share_type| share_type.parse().ok())
+                .collect(),
+            favorite: text("oc", "favorite").is_some_and(|favorite| favorite == "1"),
+            has_preview: text("nc", "has-preview").is_some_and(|preview| preview == "true"),
+            comments_unread: text("oc", "comments-unread")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(0),
+            content_type: text("d", "getcontenttype"),
+            checksums: Some(children("oc", "checksums").join(" "))
+                .filter(|checksums| !checksums.is_empty()),
+        }
+    }
+}
+
+// Attribute names may contain anything, property names have to be XML names. Everything but
+// letters, digits, '.' and '-' becomes _XX for each UTF-8 byte, and a name never starts with
+// a digit, '.' or '-'.
+pub fn attribute_property(name: &str) -> XmlTag {
+    let mut property = String::new();
+    for (index, byte) in name.bytes().enumerate() {
+        let allowed = byte.is_ascii_alphabetic()
+            || (index > 0 && (byte.is_ascii_digit() || byte == b'.' || byte == b'-'));
+        if allowed {
+            property.push(byte as char);
+        } else {
+            property.push_str(&format!("_{:02X}", byte));
+        }
+    }
+
+    XmlTag::new("nf".to_string(), property)
+}
+
+pub fn attribute_name(property: &XmlTag) -> Option<String> {
+    if property.namespace != "nf" {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    let mut rest = property.name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'_' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub fn encode_value(value: &[u8]) -> String {
+    percent_encode(value, VALUE).to_string()
+}
+
+pub fn decode_value(text: &str) -> Vec<u8> {
+    percent_decode_str(text.trim()).collect()
+}
+
+// Every attribute set on the item of a response, by name
+pub fn attributes_from_response(response: &MultiStatusResponse) -> Vec<(String, Vec<u8>)> {
+    response
+        .prop_stats
+        .iter()
+        .filter(|prop_stat| matches!(prop_stat.status, PropStatStatus::Ok))
+        .flat_map(|prop_stat| prop_stat.prop_list.children_vec())
+        .filter_map(|prop| {
+            let name = attribute_name(prop.tag())?;
+            let value = prop
+                .text()
+                .map(|text| decode_value(text))
+                .unwrap_or_default();
+            Some((name, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute_encoding() {
+        for name in ["user.comment", "user.x y/ü_1", "0", ".hidden", "user.-"] {
+            let property = attribute_property(name);
+            assert!(property
+                .name
+                .chars()
+                .next()
+                .is_some_and(|first| first.is_ascii_alphabetic() || first == '_'));
+            assert_eq!(attribute_name(&property).as_deref(), Some(name));
+        }
+        assert_eq!(attribute_property("user.tag").name, "user.tag");
+
+        let value = b" <a & b>\n\x00\xff 100% ";
+        let encoded = encode_value(value);
+        assert!(!encoded.contains(|c: char| " <>&\n".contains(c)));
+        assert_eq!(decode_value(&encoded), value);
+    }
+}
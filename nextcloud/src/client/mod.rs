@@ -1,8 +1,29 @@
 mod dav;
+pub(crate) mod http_date;
+mod lock;
+mod metadata;
 mod nextcloud;
+mod parse_ocs;
 mod pase_propfind;
+mod permissions;
 mod prop;
+mod push;
+mod quota;
+mod retry;
 mod start_dav;
+mod sync;
+mod trash;
+mod versions;
 mod xml;
 
+pub use dav::{DavError, DavItem, File, Folder};
+pub use lock::{ActiveLock, LockTimeout, NcLock, NcLockOwnerType};
+pub use metadata::Metadata;
 pub use nextcloud::Nextcloud;
+pub use permissions::Permissions;
+pub use push::{PushEvent, PushEvents};
+pub use quota::{Quota, QuotaValue, UserInfo, UserQuota};
+pub use retry::{CircuitBreakerConfig, RetryPolicy};
+pub use sync::SyncChanges;
+pub use trash::TrashItem;
+pub use versions::Version;
@@ -1,7 +1,34 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::AsyncReadExt;
+
 use super::{
-    dav::{DavItem, DavProvider},
+    dav::{mkcol_method, move_method, propfind_method, DavError, DavItem, DavProvider},
+    lock::{parse_lock_response, ActiveLock, LockInfo, LockTimeout, NcLock},
+    metadata::{attribute_property, attributes_from_response, encode_value, Metadata},
+    parse_ocs::{parse_push_endpoint, parse_user_info},
     pase_propfind::pase_propfind,
-    start_dav::start_propfind,
+    prop::{MultiStatus, PropFind, PropPatch, PropStatStatus},
+    push::{self, PushEvents},
+    quota::{Quota, UserInfo},
+    retry::{CircuitBreakerConfig, RequestExecutor, RetryPolicy},
+    start_dav::{
+        files_url, start_delete, start_get, start_lock, start_mkcol, start_move, start_ocs,
+        start_propfind, start_proppatch, start_put, start_report, start_trashbin, start_unlock,
+        start_upload, start_versions, trashbin_url, versions_url, with_destination,
+    },
+    sync::{parse_sync_token, SyncChanges, SyncCollection},
+    trash::TrashItem,
+    versions::Version,
+    xml::{ToXml, Xml, XmlTag},
 };
 
 #[derive(Debug, Clone)]
@@ -12,8 +39,21 @@ pub struct Nextcloud {
     dav_path: String,
     username: String,
     password: String,
+    // Locks taken through this client, keyed by path
+    locks: Arc<Mutex<HashMap<String, ActiveLock>>>,
+    executor: Arc<RequestExecutor>,
+    // Shared by all clones, so connections to the server are kept open and reused
+    http: reqwest::Client,
+    // Files larger than this are uploaded in chunks of this size
+    chunk_size: u64,
 }
 
+// Nextcloud accepts chunks between 5 MiB and 5 GiB, except for the last one
+const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+// Distinguishes the chunked uploads of one process
+static NEXT_TRANSFER: AtomicU64 = AtomicU64::new(0);
+
 impl Nextcloud {
     pub fn new(origin: String, dav_path: String, username: String, password: String) -> Self {
         Self {
@@ -21,29 +61,707 @@ impl Nextcloud {
             dav_path,
             username,
             password,
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            executor: Arc::new(RequestExecutor::default()),
+            http: reqwest::Client::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    pub fn with_chunk_size(self, chunk_size: u64) -> Self {
+        Self {
+            chunk_size: chunk_size.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_retry_policy(self, policy: RetryPolicy, breaker: CircuitBreakerConfig) -> Self {
+        Self {
+            executor: Arc::new(RequestExecutor::new(policy, breaker)),
+            ..self
         }
     }
 
+    // True while the circuit breaker fails requests without contacting the server
+    pub fn is_unavailable(&self) -> bool {
+        self.executor.breaker().is_open()
+    }
+
     pub async fn ls(&self, path: &str) -> Result<Vec<super::dav::DavItem>, super::dav::DavError> {
+        Ok(self.listing(path).await?.1)
+    }
+
+    // A folder along with its contents, both come from the same Depth: 1 PROPFIND. Some
+    // servers leave out the folder itself.
+    pub async fn listing(&self, path: &str) -> Result<(Option<DavItem>, Vec<DavItem>), DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: DavItem::props(),
+                    depth: 1,
+                },
+            )
+            .await?;
+        let root_path = self.files_root_path()?;
+        let own_path = path.trim_matches('/');
+
+        let (own, contents): (Vec<DavItem>, Vec<DavItem>) = value
+            .responses
+            .iter()
+            .map(|response| DavItem::from_response(response, &root_path))
+            // The collection itself is part of a Depth: 1 listing
+            .partition(|item| item.path() == own_path);
+
+        Ok((own.into_iter().next(), contents))
+    }
+
+    // A single file or folder, "" is the root folder
+    pub async fn stat(&self, path: &str) -> Result<DavItem, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: DavItem::props(),
+                    depth: 0,
+                },
+            )
+            .await?;
+        let root_path = self.files_root_path()?;
+
+        value
+            .responses
+            .first()
+            .map(|response| DavItem::from_response(response, &root_path))
+            .ok_or(DavError::NoContent)
+    }
+
+    // Up to size bytes of a file starting at offset, fewer at the end of the file
+    pub async fn read(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, DavError> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.read_range(start_get(self, path)?, offset, size).await
+    }
+
+    async fn read_range(
+        &self,
+        request: reqwest::RequestBuilder,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, DavError> {
+        let request = request.header("Range", format!("bytes={}-{}", offset, offset + size - 1));
+        let response = self.executor.execute(request).await?;
+
+        // Reading at or past the end of the file
+        if response.status().as_u16() == 416 {
+            return Ok(Vec::new());
+        }
+        let response = check_status(response)?;
+        let partial = response.status().as_u16() == 206;
+        let body = response.bytes().await.map_err(DavError::Network)?;
+
+        if partial {
+            Ok(body.to_vec())
+        } else {
+            // The server ignored the range and sent the whole file
+            let start = (offset as usize).min(body.len());
+            let end = (offset.saturating_add(size) as usize).min(body.len());
+            Ok(body[start..end].to_vec())
+        }
+    }
+
+    // Create or replace a file, returns the new etag if the server sent one. With if_match
+    // the file is only replaced if it still has that etag, otherwise this fails with 412.
+    pub async fn put(
+        &self,
+        path: &str,
+        content: Vec<u8>,
+        mtime: Option<SystemTime>,
+        if_match: Option<&str>,
+    ) -> Result<Option<String>, DavError> {
+        let mut request = with_if_match(start_put(self, path)?.body(content), if_match);
+        if let Some(mtime) = mtime {
+            request = request.header("X-OC-Mtime", unix_seconds(mtime).to_string());
+        }
+        let response = self.send(request).await?;
+
+        Ok(etag_header(&response))
+    }
+
+    // Create an empty file, fails with 412 if path already exists
+    pub async fn create(&self, path: &str) -> Result<Option<String>, DavError> {
+        let request = start_put(self, path)?
+            .header("If-None-Match", "*")
+            .body(Vec::new());
+        let response = self.send(request).await?;
+
+        Ok(etag_header(&response))
+    }
+
+    // Upload a local file, in chunks if it is larger than the chunk size. if_match is
+    // checked when the chunks are assembled.
+    pub async fn upload(
+        &self,
+        path: &str,
+        local: &Path,
+        mtime: Option<SystemTime>,
+        if_match: Option<&str>,
+    ) -> Result<Option<String>, DavError> {
+        let mut file = tokio::fs::File::open(local).await.map_err(DavError::Io)?;
+        let size = file.metadata().await.map_err(DavError::Io)?.len();
+
+        if size <= self.chunk_size {
+            let mut content = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut content).await.map_err(DavError::Io)?;
+            return self.put(path, content, mtime, if_match).await;
+        }
+
+        let transfer_id = format!(
+            "nextcloud-fuse-{}-{}-{}",
+            std::process::id(),
+            unix_seconds(SystemTime::now()),
+            NEXT_TRANSFER.fetch_add(1, Ordering::Relaxed)
+        );
+        let result = self
+            .upload_chunks(path, &transfer_id, file, size, mtime, if_match)
+            .await;
+
+        if result.is_err() {
+            // Do not leave the chunks lying around on the server
+            if let Ok(request) = start_upload(self, reqwest::Method::DELETE, &transfer_id) {
+                let _ = self.executor.execute(request).await;
+            }
+        }
+        result
+    }
+
+    async fn upload_chunks(
+        &self,
+        path: &str,
+        transfer_id: &str,
+        mut file: tokio::fs::File,
+        size: u64,
+        mtime: Option<SystemTime>,
+        if_match: Option<&str>,
+    ) -> Result<Option<String>, DavError> {
+        let destination = files_url(self, path);
+
         let request =
-            start_propfind(self, path)?.body("<propfind xmlns=\"DAV:\"><allprop /></propfind>");
-        let response = request
-            .send()
-            .await
-            .map_err(super::dav::DavError::Network)?;
-        let body = response
-            .text()
-            .await
-            .map_err(super::dav::DavError::Network)?;
-        let value = pase_propfind(body)?;
+            start_upload(self, mkcol_method(), transfer_id)?.header("Destination", &destination);
+        self.send(request).await?;
 
-        let contents: Vec<DavItem> = vec![];
+        let mut offset = 0;
+        let mut number = 1;
+        while offset < size {
+            let length = self.chunk_size.min(size - offset);
+            let mut chunk = vec![0; length as usize];
+            file.read_exact(&mut chunk).await.map_err(DavError::Io)?;
 
-        for response in value.responses {
-            println!("{:?}", response)
+            let request = start_upload(
+                self,
+                reqwest::Method::PUT,
+                &format!("{}/{:05}", transfer_id, number),
+            )?
+            .header("Destination", &destination)
+            .header("OC-Total-Length", size.to_string())
+            .body(chunk);
+            self.send(request).await?;
+
+            offset += length;
+            number += 1;
+        }
+
+        let request = start_upload(self, move_method(), &format!("{}/.file", transfer_id))?
+            .header("OC-Total-Length", size.to_string());
+        let request = with_destination(self, None, path, request);
+        let mut request = with_if_match(request, if_match);
+        if let Some(mtime) = mtime {
+            request = request.header("X-OC-Mtime", unix_seconds(mtime).to_string());
         }
+        let response = self.send(request).await?;
+
+        Ok(etag_header(&response))
+    }
+
+    // Create a folder, its parent has to exist
+    pub async fn mkdir(&self, path: &str) -> Result<(), DavError> {
+        self.send(start_mkcol(self, path)?).await?;
+        Ok(())
+    }
+
+    // Remove a file, or a folder with everything in it
+    pub async fn delete(&self, path: &str) -> Result<(), DavError> {
+        self.send(start_delete(self, path)?).await?;
+        Ok(())
+    }
+
+    // Move a file or folder. Without overwrite an existing destination fails with 412, as
+    // does a source that no longer has the etag if_match.
+    pub async fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        overwrite: bool,
+        if_match: Option<&str>,
+    ) -> Result<(), DavError> {
+        let request =
+            start_move(self, from, to)?.header("Overwrite", if overwrite { "T" } else { "F" });
+        let request = with_if_match(request, if_match);
+        self.send(request).await?;
+
+        // The server moves locks along with the file
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(lock) = locks.remove(&lock_key(from)) {
+            locks.insert(lock_key(to), lock);
+        }
+        Ok(())
+    }
+
+    // Take an exclusive write lock on path, subsequent PUT/MOVE/DELETE requests on it will
+    // carry the lock token until unlock is called
+    pub async fn lock(
+        &self,
+        path: &str,
+        owner: &str,
+        timeout: LockTimeout,
+    ) -> Result<ActiveLock, DavError> {
+        let info = LockInfo {
+            owner: owner.to_string(),
+        };
+        let request = start_lock(self, path)?
+            .header("Timeout", timeout.header_value())
+            .header("Depth", "0")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(info.to_xml());
+        let response = self.send(request).await?;
 
-        Ok(contents)
+        let lock_token = response
+            .headers()
+            .get("Lock-Token")
+            .and_then(|header| header.to_str().ok())
+            .map(|header| header.to_string());
+        let body = response.text().await.map_err(DavError::Network)?;
+        let lock = parse_lock_response(path, lock_token.as_deref(), &body)?;
+
+        self.locks
+            .lock()
+            .unwrap()
+            .insert(lock_key(path), lock.clone());
+
+        Ok(lock)
+    }
+
+    // Refresh a lock we hold before its timeout runs out
+    pub async fn refresh_lock(
+        &self,
+        path: &str,
+        timeout: LockTimeout,
+    ) -> Result<ActiveLock, DavError> {
+        let held = match self.locks.lock().unwrap().get(&lock_key(path)) {
+            Some(lock) => lock.clone(),
+            None => return Err(DavError::NoContent),
+        };
+
+        let request = start_lock(self, path)?
+            .header("Timeout", timeout.header_value())
+            .header("If", held.if_header());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        let lock = match parse_lock_response(path, Some(&held.token), &body) {
+            Ok(lock) => lock,
+            Err(_) => ActiveLock {
+                timeout: Some(timeout),
+                ..held
+            },
+        };
+
+        self.locks
+            .lock()
+            .unwrap()
+            .insert(lock_key(path), lock.clone());
+
+        Ok(lock)
+    }
+
+    pub async fn unlock(&self, path: &str) -> Result<(), DavError> {
+        let lock = match self.locks.lock().unwrap().remove(&lock_key(path)) {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+
+        let request = start_unlock(self, path)?.header("Lock-Token", format!("<{}>", lock.token));
+        self.send(request).await?;
+
+        Ok(())
+    }
+
+    pub fn held_lock(&self, path: &str) -> Option<ActiveLock> {
+        self.locks.lock().unwrap().get(&lock_key(path)).cloned()
+    }
+
+    // Nextcloud files_lock state of a single item
+    pub async fn lock_status(&self, path: &str) -> Result<Option<NcLock>, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: NcLock::props(),
+                    depth: 0,
+                },
+            )
+            .await?;
+
+        Ok(value.responses.first().and_then(NcLock::from_response))
+    }
+
+    // Used and available bytes of the folder at path, "" for the whole account
+    pub async fn quota(&self, path: &str) -> Result<Quota, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: Quota::props(),
+                    depth: 0,
+                },
+            )
+            .await?;
+
+        value
+            .responses
+            .first()
+            .and_then(Quota::from_response)
+            .ok_or(DavError::NoContent)
+    }
+
+    pub async fn user_info(&self) -> Result<UserInfo, DavError> {
+        let request = start_ocs(self, reqwest::Method::GET, "cloud/user")?;
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        parse_user_info(&body)
+    }
+
+    // Contents of the trash bin, or of a deleted folder in it. path is relative to the
+    // trash, "" for all items deleted.
+    pub async fn trash(&self, path: &str) -> Result<Vec<TrashItem>, DavError> {
+        let trash_path = format!("trash/{}", path.trim_matches('/'));
+        let propfind = PropFind {
+            props: TrashItem::props(),
+            depth: 1,
+        };
+        let request = start_trashbin(self, propfind_method(), &format!("{}/", trash_path))?
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(propfind.to_xml());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        let url = url::Url::parse(&trashbin_url(self, "trash/")).map_err(DavError::BadUrl)?;
+        let own_path = path.trim_matches('/');
+        Ok(pase_propfind(body)?
+            .responses
+            .iter()
+            .map(|response| TrashItem::from_response(response, url.path()))
+            .filter(|trashed| trashed.item.path() != own_path)
+            .collect())
+    }
+
+    // Like read, for a file in the trash bin
+    pub async fn read_trash(
+        &self,
+        path: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, DavError> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let request = start_trashbin(self, reqwest::Method::GET, &format!("trash/{}", path))?;
+        self.read_range(request, offset, size).await
+    }
+
+    // Put an item deleted itself back where it was deleted from
+    pub async fn restore(&self, path: &str) -> Result<(), DavError> {
+        let request = start_trashbin(self, move_method(), &format!("trash/{}", path))?.header(
+            "Destination",
+            trashbin_url(self, &format!("restore/{}", path)),
+        );
+        self.send(request).await?;
+        Ok(())
+    }
+
+    // Delete an item from the trash bin for good
+    pub async fn purge(&self, path: &str) -> Result<(), DavError> {
+        let request = start_trashbin(self, reqwest::Method::DELETE, &format!("trash/{}", path))?;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    // Older versions of the file with this oc:fileid, the current one is not among them
+    pub async fn versions(&self, file_id: u64) -> Result<Vec<Version>, DavError> {
+        let versions_path = format!("versions/{}/", file_id);
+        let propfind = PropFind {
+            props: Version::props(),
+            depth: 1,
+        };
+        let request = start_versions(self, propfind_method(), &versions_path)?
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(propfind.to_xml());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        let url = url::Url::parse(&versions_url(self, &versions_path)).map_err(DavError::BadUrl)?;
+        Ok(pase_propfind(body)?
+            .responses
+            .iter()
+            .map(|response| Version::from_response(response, url.path()))
+            .filter(|version| !version.item.path().is_empty())
+            .collect())
+    }
+
+    // Like read, for an older version of a file
+    pub async fn read_version(
+        &self,
+        file_id: u64,
+        version: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, DavError> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        let path = format!("versions/{}/{}", file_id, version);
+        let request = start_versions(self, reqwest::Method::GET, &path)?;
+        self.read_range(request, offset, size).await
+    }
+
+    // Make an older version the current content of its file. The replaced content becomes a
+    // version itself.
+    pub async fn restore_version(&self, file_id: u64, version: &str) -> Result<(), DavError> {
+        let path = format!("versions/{}/{}", file_id, version);
+        let request = start_versions(self, move_method(), &path)?
+            .header("Destination", versions_url(self, "restore/target"));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    // The websocket of the notify_push app, None if the server does not have it
+    pub async fn push_endpoint(&self) -> Result<Option<String>, DavError> {
+        let request = start_ocs(self, reqwest::Method::GET, "cloud/capabilities")?;
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        parse_push_endpoint(&body)
+    }
+
+    // Changes to the user's files as notify_push announces them, None if the server does
+    // not have it. The connection is kept up in the background until the events are
+    // dropped, reconnecting with the backoff of the retry policy.
+    pub async fn push_events(&self) -> Result<Option<PushEvents>, DavError> {
+        let endpoint = match self.push_endpoint().await? {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+
+        Ok(Some(push::listen(
+            endpoint,
+            self.username.clone(),
+            self.password.clone(),
+            self.executor.policy().clone(),
+        )))
+    }
+
+    // Everything that changed below a folder since token, without a token everything there
+    // is. Servers without sync-collection support answer with an error status.
+    pub async fn sync_collection(
+        &self,
+        path: &str,
+        token: Option<&str>,
+    ) -> Result<SyncChanges, DavError> {
+        let body = SyncCollection {
+            token: token.map(str::to_string),
+            props: DavItem::props(),
+        };
+        let request = start_report(self, path)?
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(body.to_xml());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        let token = parse_sync_token(&body).ok_or(DavError::NoContent)?;
+        let root_path = self.files_root_path()?;
+        let own_path = path.trim_matches('/');
+        let mut changes = SyncChanges {
+            token,
+            changed: Vec::new(),
+            removed: Vec::new(),
+        };
+        for response in pase_propfind(body)?.responses {
+            let item = DavItem::from_response(&response, &root_path);
+            if item.path() == own_path {
+                continue;
+            }
+            // Removed items come with a 404 status instead of properties
+            let found = response
+                .prop_stats
+                .iter()
+                .any(|prop_stat| matches!(prop_stat.status, PropStatStatus::Ok));
+            if found {
+                changes.changed.push(item);
+            } else {
+                changes.removed.push(item.path().to_string());
+            }
+        }
+
+        Ok(changes)
+    }
+
+    // Nextcloud's properties of a single file or folder
+    pub async fn metadata(&self, path: &str) -> Result<Metadata, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: Metadata::props(),
+                    depth: 0,
+                },
+            )
+            .await?;
+
+        value
+            .responses
+            .first()
+            .map(Metadata::from_response)
+            .ok_or(DavError::NoContent)
+    }
+
+    pub async fn set_favorite(&self, path: &str, favorite: bool) -> Result<(), DavError> {
+        let mut prop = Xml::new(XmlTag::new("oc".to_string(), "favorite".to_string()));
+        prop.with_text(if favorite { "1" } else { "0" }.to_string());
+
+        self.proppatch(
+            path,
+            PropPatch {
+                set_props: vec![prop],
+                remove_props: Vec::new(),
+            },
+        )
+        .await
+    }
+
+    // Attributes are arbitrary name and value pairs kept by the server as dead properties
+    pub async fn attributes(&self, path: &str) -> Result<Vec<(String, Vec<u8>)>, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: Vec::new(),
+                    depth: 0,
+                },
+            )
+            .await?;
+
+        Ok(value
+            .responses
+            .first()
+            .map(attributes_from_response)
+            .unwrap_or_default())
+    }
+
+    pub async fn attribute(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, DavError> {
+        let value = self
+            .propfind(
+                path,
+                PropFind {
+                    props: vec![attribute_property(name)],
+                    depth: 0,
+                },
+            )
+            .await?;
+
+        Ok(value
+            .responses
+            .first()
+            .map(attributes_from_response)
+            .and_then(|attributes| {
+                attributes
+                    .into_iter()
+                    .find(|(attribute, _)| attribute == name)
+            })
+            .map(|(_, value)| value))
+    }
+
+    // Set an attribute, or remove it without a value
+    pub async fn set_attribute(
+        &self,
+        path: &str,
+        name: &str,
+        value: Option<&[u8]>,
+    ) -> Result<(), DavError> {
+        let property = attribute_property(name);
+        let proppatch = match value {
+            Some(value) => {
+                let mut prop = Xml::new(property);
+                prop.with_text(encode_value(value));
+                PropPatch {
+                    set_props: vec![prop],
+                    remove_props: Vec::new(),
+                }
+            }
+            None => PropPatch {
+                set_props: Vec::new(),
+                remove_props: vec![property],
+            },
+        };
+
+        self.proppatch(path, proppatch).await
+    }
+
+    // A PROPPATCH is applied completely or not at all, a failure is reported per property
+    async fn proppatch(&self, path: &str, proppatch: PropPatch) -> Result<(), DavError> {
+        let request = start_proppatch(self, path)?
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(proppatch.to_xml());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        let failed = pase_propfind(body)?
+            .responses
+            .iter()
+            .flat_map(|response| response.prop_stats.iter())
+            .find(|prop_stat| !matches!(prop_stat.status, PropStatStatus::Ok))
+            .map(|prop_stat| prop_stat.status.status_code());
+        match failed {
+            Some(status) => Err(DavError::UnexpectedStatus(status)),
+            None => Ok(()),
+        }
+    }
+
+    async fn propfind(&self, path: &str, propfind: PropFind) -> Result<MultiStatus, DavError> {
+        let request = start_propfind(self, path)?
+            .header("Depth", propfind.depth.to_string())
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(propfind.to_xml());
+        let response = self.send(request).await?;
+        let body = response.text().await.map_err(DavError::Network)?;
+
+        pase_propfind(body)
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, DavError> {
+        check_status(self.executor.execute(request).await?)
+    }
+
+    // Path component of the files URL, hrefs in PROPFIND responses start with it
+    fn files_root_path(&self) -> Result<String, DavError> {
+        let url = url::Url::parse(&self.files_url_string()).map_err(DavError::BadUrl)?;
+        Ok(url.path().to_string())
     }
 }
 
@@ -52,7 +770,87 @@ impl DavProvider for Nextcloud {
         format!("{}/{}/files/{}/", self.origin, self.dav_path, self.username)
     }
 
+    fn uploads_url_string(&self) -> String {
+        format!(
+            "{}/{}/uploads/{}/",
+            self.origin, self.dav_path, self.username
+        )
+    }
+
+    fn trashbin_url_string(&self) -> String {
+        format!(
+            "{}/{}/trashbin/{}/",
+            self.origin, self.dav_path, self.username
+        )
+    }
+
+    fn versions_url_string(&self) -> String {
+        format!(
+            "{}/{}/versions/{}/",
+            self.origin, self.dav_path, self.username
+        )
+    }
+
+    fn ocs_url_string(&self) -> String {
+        format!("{}/ocs/v2.php/", self.origin)
+    }
+
     fn add_auth_header(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
         req.basic_auth(&self.username, Some(&self.password))
     }
+
+    fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    fn active_lock(&self, path: &str) -> Option<ActiveLock> {
+        self.held_lock(path)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn with_if_match(request: reqwest::RequestBuilder, etag: Option<&str>) -> reqwest::RequestBuilder {
+    match etag {
+        Some(etag) => request.header("If-Match", format!("\"{}\"", etag)),
+        None => request,
+    }
+}
+
+// Nextcloud sends OC-ETag because proxies may rewrite ETag
+fn etag_header(response: &reqwest::Response) -> Option<String> {
+    ["OC-ETag", "ETag"].iter().find_map(|name| {
+        response
+            .headers()
+            .get(*name)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| {
+                etag.trim()
+                    .trim_start_matches("W/")
+                    .trim_matches('"')
+                    .to_string()
+            })
+    })
+}
+
+fn lock_key(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response, DavError> {
+    let status = response.status();
+
+    if status.is_success() {
+        Ok(response)
+    } else if status.as_u16() == 423 {
+        Err(DavError::Locked)
+    } else if status.as_u16() == 503 {
+        Err(DavError::ServerUnavailable)
+    } else {
+        Err(DavError::UnexpectedStatus(status))
+    }
 }
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use quick_xml::{events::Event, Reader};
+
+use super::{
+    dav::DavError,
+    quota::{QuotaValue, UserInfo, UserQuota},
+    xml::XmlTag,
+};
+
+// An OCS reply flattened into slash separated paths relative to ocs/data,
+// e.g. "quota/free" for <ocs><data><quota><free>
+#[derive(Debug, Clone)]
+pub struct OcsResponse {
+    pub status_code: u16,
+    pub message: Option<String>,
+    pub data: HashMap<String, String>,
+}
+
+impl OcsResponse {
+    pub fn get(&self, path: &str) -> Option<&String> {
+        self.data.get(path)
+    }
+
+    // v1 answers 100 on success, v2 mirrors the HTTP status
+    pub fn is_ok(&self) -> bool {
+        self.status_code == 100 || self.status_code == 200
+    }
+}
+
+pub fn parse_ocs(body: &str) -> Result<OcsResponse, DavError> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+
+    let mut stack: Vec<XmlTag> = Vec::new();
+
+    let mut status_code: Option<u16> = None;
+    let mut message: Option<String> = None;
+    let mut data: HashMap<String, String> = HashMap::new();
+
+    loop {
+        match reader.read_event().map_err(DavError::XmlParse)? {
+            Event::Start(e) => stack.push(XmlTag::from(e.name())),
+            Event::End(_) => {
+                stack.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape().map_err(DavError::XmlParse)?.to_string();
+                let names: Vec<&str> = stack.iter().map(|tag| tag.name.as_str()).collect();
+
+                match names.as_slice() {
+                    ["ocs", "meta", "statuscode"] => status_code = text.parse().ok(),
+                    ["ocs", "meta", "message"] => message = Some(text),
+                    ["ocs", "data", rest @ ..] if !rest.is_empty() => {
+                        // Repeated elements (lists) keep their first value
+                        data.entry(rest.join("/")).or_insert(text);
+                    }
+                    _ => (),
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    match status_code {
+        Some(status_code) => Ok(OcsResponse {
+            status_code,
+            message,
+            data,
+        }),
+        None => Err(DavError::NoContent),
+    }
+}
+
+// The websocket of the notify_push app from the capabilities, None if it is not installed
+pub fn parse_push_endpoint(body: &str) -> Result<Option<String>, DavError> {
+    let response = parse_ocs(body)?;
+
+    if !response.is_ok() {
+        return Err(DavError::OcsFailure(
+            response.status_code,
+            response.message.unwrap_or_default(),
+        ));
+    }
+
+    Ok(response
+        .get("capabilities/notify_push/endpoints/websocket")
+        .cloned())
+}
+
+pub fn parse_user_info(body: &str) -> Result<UserInfo, DavError> {
+    let response = parse_ocs(body)?;
+
+    if !response.is_ok() {
+        return Err(DavError::OcsFailure(
+            response.status_code,
+            response.message.unwrap_or_default(),
+        ));
+    }
+
+    let number = |path: &str| {
+        response
+            .get(path)
+            .and_then(|value| QuotaValue::parse(value))
+    };
+
+    Ok(UserInfo {
+        id: response.get("id").cloned().ok_or(DavError::NoContent)?,
+        // v1 uses display-name, v2 displayname
+        display_name: response
+            .get("displayname")
+            .or(response.get("display-name"))
+            .cloned(),
+        email: response.get("email").cloned(),
+        quota: UserQuota {
+            free: number("quota/free").and_then(|free| free.bytes()),
+            used: number("quota/used")
+                .and_then(|used| used.bytes())
+                .unwrap_or(0),
+            total: number("quota/total").and_then(|total| total.bytes()),
+            relative: response
+                .get("quota/relative")
+                .and_then(|relative| relative.trim().parse().ok())
+                .unwrap_or(0.0),
+            quota: number("quota/quota").unwrap_or(QuotaValue::Unknown),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_info() {
+        let body = r#"<?xml version="1.0"?>
+        <ocs>
+         <meta>
+          <status>ok</status>
+          <statuscode>200</statuscode>
+          <message>OK</message>
+         </meta>
+         <data>
+          <enabled>1</enabled>
+          <id>jthoward</id>
+          <quota>
+           <free>96637222912</free>
+           <used>10737418240</used>
+           <total>107374641152</total>
+           <relative>10</relative>
+           <quota>-3</quota>
+          </quota>
+          <email>tag@example.com</email>
+          <displayname>Tag Howard</displayname>
+          <groups>
+           <element>admin</element>
+           <element>family</element>
+          </groups>
+         </data>
+        </ocs>"#;
+
+        let user = parse_user_info(body).unwrap();
+        assert_eq!(user.id, "jthoward");
+        assert_eq!(user.display_name.as_deref(), Some("Tag Howard"));
+        assert_eq!(user.email.as_deref(), Some("tag@example.com"));
+        assert_eq!(user.quota.free, Some(96637222912));
+        assert_eq!(user.quota.used, 10737418240);
+        assert_eq!(user.quota.total, Some(107374641152));
+        assert_eq!(user.quota.relative, 10.0);
+        assert_eq!(user.quota.quota, QuotaValue::Unlimited);
+    }
+}
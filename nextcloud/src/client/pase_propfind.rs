@@ -1,6 +1,4 @@
-use std::ops::Deref;
-
-use quick_xml::{events::Event, Reader};
+use quick_xml::{events::Event, NsReader};
 
 use super::{
     dav::DavError,
@@ -9,7 +7,7 @@ use super::{
 };
 
 pub fn pase_propfind(body: String) -> Result<MultiStatus, DavError> {
-    let mut reader = Reader::from_str(&body);
+    let mut reader = NsReader::from_str(&body);
     reader.trim_text(true);
 
     let mut multi_status: Option<MultiStatus> = None;
@@ -23,9 +21,11 @@ pub fn pase_propfind(body: String) -> Result<MultiStatus, DavError> {
     let mut prop_list_stack: Vec<*const Xml> = Vec::new();
 
     loop {
-        match reader.read_event().unwrap() {
+        let (namespace, event) = reader.read_resolved_event().map_err(DavError::XmlParse)?;
+
+        match event {
             Event::Start(e) => {
-                let tag = XmlTag::from(e.name());
+                let tag = XmlTag::from_resolved(&namespace, e.name());
 
                 if tag.namespace == "d" && tag.name == "multistatus" && multi_status.is_none() {
                     // d:multistatus is the root element
@@ -71,7 +71,7 @@ pub fn pase_propfind(body: String) -> Result<MultiStatus, DavError> {
                 stack.push(tag);
             }
             Event::End(e) => {
-                let tag = XmlTag::from(e.name());
+                let tag = XmlTag::from_resolved(&namespace, e.name());
 
                 if tag.namespace == "d" && tag.name == "response" {
                     // If we have a response, add it to the multi_status
@@ -118,7 +118,7 @@ pub fn pase_propfind(body: String) -> Result<MultiStatus, DavError> {
                 stack.pop();
             }
             Event::Empty(e) => {
-                let tag = XmlTag::from(e.name());
+                let tag = XmlTag::from_resolved(&namespace, e.name());
 
                 let this_prop = Xml::new(tag.clone());
 
@@ -222,9 +222,44 @@ fn parse_prop_stat_code(status: String) -> Option<PropStatStatus> {
 
 #[test]
 fn test_parse() {
-    // let _ = pase_propfind(include_str!("../../../text.xml").to_string());
-    println!(
-        "{:#?}",
-        pase_propfind(include_str!("../../../text.xml").to_string())
-    );
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::dav::DavItem;
+
+    let multi_status = pase_propfind(include_str!("../../../text.xml").to_string()).unwrap();
+    let items: Vec<DavItem> = multi_status
+        .responses
+        .iter()
+        .map(|response| DavItem::from_response(response, "/remote.php/dav/files/jthoward"))
+        .collect();
+    assert_eq!(items.len(), 2);
+
+    // The forbidden d:secret is in a propstat of its own
+    match &items[0] {
+        DavItem::File(file) => {
+            assert_eq!(file.name, "textcompare.vcf");
+            assert_eq!(file.path, "textcompare.vcf");
+            assert_eq!(file.size, 1892);
+            assert_eq!(
+                file.etag.as_deref(),
+                Some("f16d1418cd3d8b2178bbd0bf1e0ac3e7")
+            );
+            assert_eq!(
+                file.last_modified,
+                Some(UNIX_EPOCH + Duration::from_secs(1711658676))
+            );
+            assert_eq!(file.file_id, None);
+        }
+        item => panic!("expected a file, got {:?}", item),
+    }
+    match &items[1] {
+        DavItem::Folder(folder) => {
+            assert_eq!(folder.name, "Personal");
+            assert_eq!(folder.path, "Personal");
+            assert_eq!(folder.etag.as_deref(), Some("65f4bd951577c"));
+            assert_eq!(folder.file_id, Some(4045));
+            assert_eq!(folder.permissions.as_deref(), Some("RGDNVCK"));
+        }
+        item => panic!("expected a folder, got {:?}", item),
+    }
 }
@@ -0,0 +1,71 @@
+use super::dav::DavItem;
+
+// What the server allows on an item, from its oc:permissions letters. S (shared),
+// R (reshareable), M (mounted) and G (readable) do not restrict what we do with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    // D
+    pub delete: bool,
+    // N, within the same folder
+    pub rename: bool,
+    // V, into another folder
+    pub r#move: bool,
+    // W, change the contents of a file
+    pub write: bool,
+    // C and K, in a folder
+    pub create_file: bool,
+    pub create_folder: bool,
+}
+
+impl Permissions {
+    // Everything is allowed when the server did not say, it still has the final word
+    pub fn all() -> Permissions {
+        Permissions {
+            delete: true,
+            rename: true,
+            r#move: true,
+            write: true,
+            create_file: true,
+            create_folder: true,
+        }
+    }
+
+    pub fn parse(letters: &str) -> Permissions {
+        Permissions {
+            delete: letters.contains('D'),
+            rename: letters.contains('N'),
+            r#move: letters.contains('V'),
+            write: letters.contains('W'),
+            create_file: letters.contains('C'),
+            create_folder: letters.contains('K'),
+        }
+    }
+
+    pub fn of(item: &DavItem) -> Permissions {
+        item.permissions()
+            .map(Permissions::parse)
+            .unwrap_or_else(Permissions::all)
+    }
+
+    pub fn can_create(&self) -> bool {
+        self.create_file || self.create_folder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let shared = Permissions::parse("SRGDNVW");
+        assert!(shared.write && shared.delete && !shared.can_create());
+
+        let read_only = Permissions::parse("SG");
+        assert!(!read_only.write && !read_only.delete && !read_only.rename);
+
+        let folder = Permissions::parse("RGDNVCK");
+        assert!(folder.create_file && folder.create_folder && !folder.write);
+        assert_eq!(Permissions::parse(""), Permissions::parse("SRMG"));
+    }
+}
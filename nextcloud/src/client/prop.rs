@@ -1,4 +1,7 @@
-use super::xml::{ToXml, Xml, XmlTag};
+use super::{
+    metadata::ATTRIBUTE_NAMESPACE,
+    xml::{ToXml, Xml, XmlTag},
+};
 
 #[derive(Debug, Clone)]
 pub enum UnknownStatus {
@@ -24,27 +27,50 @@ pub struct PropFind {
 }
 
 impl ToXml for PropFind {
+    // Without props every property is asked for, dead ones included
     fn to_xml(&self) -> String {
-        let props = self
-            .props
-            .iter()
-            .map(|prop| format!("<{} />", prop.full_name()))
-            .collect::<Vec<String>>()
-            .join("");
+        let props = if self.props.is_empty() {
+            "<d:allprop />".to_string()
+        } else {
+            let props = self
+                .props
+                .iter()
+                .map(|prop| format!("<{} />", prop.full_name()))
+                .collect::<Vec<String>>()
+                .join("");
+            format!("<d:prop>{}</d:prop>", props)
+        };
         format!(
             r#"<d:propfind
               xmlns:d="DAV:"
               xmlns:oc="http://owncloud.org/ns"
               xmlns:nc="http://nextcloud.org/ns"
-              xmlns:ocs="http://open-collaboration-services.org/ns">
-              xmlns:ocm="http://open-cloud-mesh.org/ns">
-                <d:prop>{}</d:prop>
+              xmlns:ocs="http://open-collaboration-services.org/ns"
+              xmlns:ocm="http://open-cloud-mesh.org/ns"
+              xmlns:nf="{}">
+                {}
             </d:propfind>"#,
-            props
+            ATTRIBUTE_NAMESPACE, props
         )
     }
 }
 
+impl PropStatStatus {
+    // Closest status code, for reporting a failed propstat as an error
+    pub fn status_code(&self) -> reqwest::StatusCode {
+        match self {
+            PropStatStatus::Ok => reqwest::StatusCode::OK,
+            PropStatStatus::Unauthorized => reqwest::StatusCode::UNAUTHORIZED,
+            PropStatStatus::Forbidden => reqwest::StatusCode::FORBIDDEN,
+            PropStatStatus::NotFound => reqwest::StatusCode::NOT_FOUND,
+            PropStatStatus::Unknown(UnknownStatus::UnknownClientError) => {
+                reqwest::StatusCode::CONFLICT
+            }
+            PropStatStatus::Unknown(_) => reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PropStat {
     pub status: PropStatStatus,
@@ -72,6 +98,21 @@ pub struct MultiStatusResponse {
     pub response_description: Option<String>,
 }
 
+impl MultiStatusResponse {
+    // Look up a property among the successful propstats of this response
+    pub fn prop(&self, namespace: &str, name: &str) -> Option<&Xml> {
+        self.prop_stats
+            .iter()
+            .filter(|prop_stat| matches!(prop_stat.status, PropStatStatus::Ok))
+            .flat_map(|prop_stat| prop_stat.prop_list.children_vec())
+            .find(|prop| prop.tag().namespace == namespace && prop.tag().name == name)
+    }
+
+    pub fn prop_text(&self, namespace: &str, name: &str) -> Option<&String> {
+        self.prop(namespace, name).and_then(|prop| prop.text())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MultiStatus {
     pub responses: Vec<MultiStatusResponse>,
@@ -94,6 +135,42 @@ pub struct PropPatch {
     pub remove_props: Vec<XmlTag>,
 }
 
+impl ToXml for PropPatch {
+    fn to_xml(&self) -> String {
+        let set = self
+            .set_props
+            .iter()
+            .map(|prop| prop.to_xml())
+            .collect::<Vec<String>>()
+            .join("");
+        let remove = self
+            .remove_props
+            .iter()
+            .map(|prop| format!("<{} />", prop.full_name()))
+            .collect::<Vec<String>>()
+            .join("");
+
+        let mut updates = String::new();
+        if !set.is_empty() {
+            updates.push_str(&format!("<d:set><d:prop>{}</d:prop></d:set>", set));
+        }
+        if !remove.is_empty() {
+            updates.push_str(&format!("<d:remove><d:prop>{}</d:prop></d:remove>", remove));
+        }
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+            <d:propertyupdate
+              xmlns:d="DAV:"
+              xmlns:oc="http://owncloud.org/ns"
+              xmlns:nc="http://nextcloud.org/ns"
+              xmlns:nf="{}">
+                {}
+            </d:propertyupdate>"#,
+            ATTRIBUTE_NAMESPACE, updates
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MkColStatus {
     Unknown(UnknownStatus),
@@ -0,0 +1,161 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_tungstenite::{connect_async, tungstenite, tungstenite::Message};
+
+use super::{dav::DavError, retry::RetryPolicy};
+
+// What the notify_push app tells about changes to the user's files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushEvent {
+    // Authenticated, after every reconnect too. Changes made while disconnected were not
+    // announced.
+    Connected,
+    // Something changed, without saying what
+    Files,
+    // Changes to the items with these oc:fileid
+    FileIds(Vec<u64>),
+}
+
+// Events from the background connection, which ends when this is dropped
+pub struct PushEvents {
+    receiver: mpsc::Receiver<PushEvent>,
+    task: JoinHandle<()>,
+}
+
+impl Stream for PushEvents {
+    type Item = PushEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<PushEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for PushEvents {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// "notify_file", or "notify_file_id [1,2]" for clients that asked for ids. Activity and
+// notification events say nothing about files.
+pub fn parse_message(message: &str) -> Option<PushEvent> {
+    let (kind, body) = message
+        .trim()
+        .split_once(' ')
+        .unwrap_or((message.trim(), ""));
+
+    match kind {
+        "notify_file" => Some(PushEvent::Files),
+        "notify_file_id" => {
+            let ids = body
+                .trim()
+                .strip_prefix('[')?
+                .strip_suffix(']')?
+                .split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| id.trim().parse().ok())
+                .collect::<Option<Vec<u64>>>()?;
+            Some(PushEvent::FileIds(ids))
+        }
+        _ => None,
+    }
+}
+
+fn websocket_error(error: tungstenite::Error) -> DavError {
+    DavError::WebSocket(Box::new(error))
+}
+
+pub fn listen(url: String, username: String, password: String, policy: RetryPolicy) -> PushEvents {
+    let (sender, receiver) = mpsc::channel(64);
+    let task = tokio::spawn(async move {
+        let mut attempt = 0;
+        // Only ends once nobody listens anymore
+        while session(&url, &username, &password, &sender, &mut attempt)
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(policy.backoff(attempt)).await;
+            attempt = attempt.saturating_add(1);
+        }
+    });
+
+    PushEvents { receiver, task }
+}
+
+// One connection, Ok once the receiver is gone. attempt is reset when authenticated.
+async fn session(
+    url: &str,
+    username: &str,
+    password: &str,
+    sender: &mpsc::Sender<PushEvent>,
+    attempt: &mut u32,
+) -> Result<(), DavError> {
+    let (mut socket, _) = connect_async(url).await.map_err(websocket_error)?;
+    socket
+        .send(Message::Text(username.to_string()))
+        .await
+        .map_err(websocket_error)?;
+    socket
+        .send(Message::Text(password.to_string()))
+        .await
+        .map_err(websocket_error)?;
+
+    let mut authenticated = false;
+    while let Some(message) = socket.next().await {
+        let text = match message.map_err(websocket_error)? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // Pings are answered by tungstenite
+            _ => continue,
+        };
+
+        let event = if authenticated {
+            match parse_message(&text) {
+                Some(event) => event,
+                None => continue,
+            }
+        } else if text == "authenticated" {
+            authenticated = true;
+            *attempt = 0;
+            socket
+                .send(Message::Text("listen notify_file_id".to_string()))
+                .await
+                .map_err(websocket_error)?;
+            PushEvent::Connected
+        } else {
+            let message = text.strip_prefix("err: ").unwrap_or(&text);
+            return Err(DavError::PushRejected(message.to_string()));
+        };
+
+        if sender.send(event).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    Err(websocket_error(tungstenite::Error::ConnectionClosed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        assert_eq!(parse_message("notify_file"), Some(PushEvent::Files));
+        assert_eq!(
+            parse_message("notify_file_id [12, 345]"),
+            Some(PushEvent::FileIds(vec![12, 345]))
+        );
+        assert_eq!(
+            parse_message("notify_file_id []"),
+            Some(PushEvent::FileIds(Vec::new()))
+        );
+        assert_eq!(parse_message("notify_file_id [x]"), None);
+        assert_eq!(parse_message("notify_activity"), None);
+    }
+}
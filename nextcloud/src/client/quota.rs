@@ -0,0 +1,117 @@
+use super::{prop::MultiStatusResponse, xml::XmlTag};
+
+// Nextcloud reports quotas as signed numbers with negative sentinels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaValue {
+    Bytes(u64),
+    // -1, the size has not been computed yet
+    NotComputed,
+    // -2, the storage cannot report its free space (e.g. external storage)
+    Unknown,
+    // -3, no quota is set
+    Unlimited,
+}
+
+impl QuotaValue {
+    pub fn from_raw(raw: i64) -> QuotaValue {
+        match raw {
+            -1 => QuotaValue::NotComputed,
+            -3 => QuotaValue::Unlimited,
+            raw if raw >= 0 => QuotaValue::Bytes(raw as u64),
+            _ => QuotaValue::Unknown,
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<QuotaValue> {
+        let text = text.trim();
+
+        // Some servers send floats for huge numbers (e.g. "1.0E+14")
+        match text.parse::<i64>() {
+            Ok(raw) => Some(QuotaValue::from_raw(raw)),
+            Err(_) => text
+                .parse::<f64>()
+                .ok()
+                .map(|raw| QuotaValue::from_raw(raw as i64)),
+        }
+    }
+
+    pub fn bytes(&self) -> Option<u64> {
+        match self {
+            QuotaValue::Bytes(bytes) => Some(*bytes),
+            _ => None,
+        }
+    }
+}
+
+// Quota of a folder as returned by a Depth: 0 PROPFIND
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub used: u64,
+    pub available: QuotaValue,
+}
+
+impl Quota {
+    pub fn props() -> Vec<XmlTag> {
+        vec![
+            XmlTag::new("d".to_string(), "quota-available-bytes".to_string()),
+            XmlTag::new("d".to_string(), "quota-used-bytes".to_string()),
+        ]
+    }
+
+    pub fn from_response(response: &MultiStatusResponse) -> Option<Quota> {
+        let used = response
+            .prop_text("d", "quota-used-bytes")
+            .and_then(|used| QuotaValue::parse(used))
+            .and_then(|used| used.bytes())?;
+        let available = response
+            .prop_text("d", "quota-available-bytes")
+            .and_then(|available| QuotaValue::parse(available))
+            .unwrap_or(QuotaValue::Unknown);
+
+        Some(Quota { used, available })
+    }
+
+    // Total size of the storage, if the server knows it
+    pub fn total(&self) -> Option<u64> {
+        self.available
+            .bytes()
+            .map(|available| available + self.used)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserQuota {
+    pub free: Option<u64>,
+    pub used: u64,
+    pub total: Option<u64>,
+    // Percentage of the quota that is used
+    pub relative: f64,
+    pub quota: QuotaValue,
+}
+
+// The current user as reported by the OCS cloud/user endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub quota: UserQuota,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_value() {
+        assert_eq!(QuotaValue::parse("1024"), Some(QuotaValue::Bytes(1024)));
+        assert_eq!(QuotaValue::parse("-1"), Some(QuotaValue::NotComputed));
+        assert_eq!(QuotaValue::parse("-2"), Some(QuotaValue::Unknown));
+        assert_eq!(QuotaValue::parse("-3"), Some(QuotaValue::Unlimited));
+        assert_eq!(
+            QuotaValue::parse("1.0E+14"),
+            Some(QuotaValue::Bytes(100_000_000_000_000))
+        );
+        assert_eq!(QuotaValue::parse("none"), None);
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{dav::DavError, http_date::parse_http_date};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Retries after the first attempt, 0 disables retrying
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // A Retry-After longer than this is not waited for, the error is returned instead
+    pub max_retry_after: Duration,
+    pub jitter: bool,
+    pub retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(30),
+            max_retry_after: Duration::from_secs(120),
+            jitter: true,
+            retry_statuses: vec![429, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    // Exponential backoff for the given retry (0 based), with full jitter if enabled
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.min(31)))
+            .min(self.max_delay);
+
+        if self.jitter {
+            exponential.mul_f64(random_fraction())
+        } else {
+            exponential
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    // Consecutive failures after which requests fail fast
+    pub failure_threshold: u32,
+    // How long to fail fast before letting a trial request through
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A single trial request is in flight
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    // None while requests should fail fast, otherwise a permit the outcome of the request
+    // has to be recorded with
+    pub fn allow(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+
+        let trial = match *state {
+            BreakerState::Closed { .. } => false,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen => return None,
+        };
+        Some(Permit {
+            breaker: self,
+            trial,
+            recorded: false,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), BreakerState::Closed { .. })
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.config.failure_threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => BreakerState::Open {
+                until: Instant::now() + self.config.open_duration,
+            },
+        };
+    }
+}
+
+// Lets one request through the breaker. A trial request dropped before its outcome is known,
+// because the caller gave up on it, lets the next request try instead.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        if success {
+            self.breaker.record_success();
+        } else {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.trial || self.recorded {
+            return;
+        }
+        let mut state = self.breaker.state.lock().unwrap();
+        if *state == BreakerState::HalfOpen {
+            *state = BreakerState::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+// Sends requests, retrying transient failures according to the policy and failing fast
+// while the server is known to be down
+#[derive(Debug)]
+pub struct RequestExecutor {
+    policy: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl Default for RequestExecutor {
+    fn default() -> Self {
+        Self::new(RetryPolicy::default(), CircuitBreakerConfig::default())
+    }
+}
+
+impl RequestExecutor {
+    pub fn new(policy: RetryPolicy, breaker: CircuitBreakerConfig) -> Self {
+        Self {
+            policy,
+            breaker: CircuitBreaker::new(breaker),
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    pub async fn execute(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, DavError> {
+        let (client, request) = request.build_split();
+        let mut request = request.map_err(DavError::Network)?;
+        let idempotent = is_idempotent(request.method());
+        let mut attempt = 0;
+
+        // The breaker counts requests, however many attempts each of them took
+        let permit = self.breaker.allow().ok_or(DavError::ServerUnavailable)?;
+        loop {
+            // Streaming bodies cannot be cloned, those requests are only sent once
+            let retry_request = request.try_clone();
+            let result = client.execute(request).await;
+
+            let delay = match &result {
+                Ok(response) => self.retry_delay_for_response(response, attempt, idempotent),
+                Err(error) => self.retry_delay_for_error(error, attempt, idempotent),
+            };
+
+            match (delay, retry_request) {
+                (Some(delay), Some(next)) => {
+                    tokio::time::sleep(delay).await;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => {
+                    permit.record(
+                        matches!(&result, Ok(response) if !is_server_failure(response.status())),
+                    );
+                    return result.map_err(DavError::Network);
+                }
+            }
+        }
+    }
+
+    fn retry_delay_for_response(
+        &self,
+        response: &reqwest::Response,
+        attempt: u32,
+        idempotent: bool,
+    ) -> Option<Duration> {
+        let status = response.status().as_u16();
+
+        if attempt >= self.policy.max_retries || !self.policy.retry_statuses.contains(&status) {
+            return None;
+        }
+
+        // A gateway error may come after the request was processed, only repeat it if that is
+        // harmless. 429 and maintenance mode are rejected before anything happens.
+        let maintenance = response
+            .headers()
+            .get("X-Nextcloud-Maintenance-Mode")
+            .is_some();
+        if !idempotent && status != 429 && !maintenance {
+            return None;
+        }
+
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+
+        match retry_after {
+            Some(delay) if delay > self.policy.max_retry_after => None,
+            Some(delay) => Some(delay),
+            None => Some(self.policy.backoff(attempt)),
+        }
+    }
+
+    fn retry_delay_for_error(
+        &self,
+        error: &reqwest::Error,
+        attempt: u32,
+        idempotent: bool,
+    ) -> Option<Duration> {
+        if attempt >= self.policy.max_retries {
+            return None;
+        }
+
+        // A failed connect never reached the server, anything else might have
+        let retryable =
+            error.is_connect() || (idempotent && (error.is_timeout() || error.is_request()));
+
+        if retryable {
+            Some(self.policy.backoff(attempt))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_idempotent(method: &reqwest::Method) -> bool {
+    matches!(
+        method.as_str(),
+        "GET"
+            | "HEAD"
+            | "OPTIONS"
+            | "TRACE"
+            | "PUT"
+            | "DELETE"
+            | "PROPFIND"
+            | "PROPPATCH"
+            | "REPORT"
+            | "SEARCH"
+            | "UNLOCK"
+    )
+}
+
+fn is_server_failure(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            parse_http_date(value).map(|date| date.duration_since(now).unwrap_or(Duration::ZERO))
+        }
+    }
+}
+
+// Uniformly distributed in [0, 1), good enough for jitter without pulling in a rng
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(250));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(40), Duration::from_secs(30));
+
+        let policy = RetryPolicy::default();
+        for attempt in 0..10 {
+            assert!(policy.backoff(attempt) <= policy.max_delay);
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1711658676);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Thu, 28 Mar 2024 20:45:36 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Thu, 28 Mar 2024 20:40:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_duration: Duration::ZERO,
+        });
+
+        breaker.allow().unwrap().record(false);
+        assert!(!breaker.is_open());
+        breaker.allow().unwrap().record(false);
+        assert!(breaker.is_open());
+
+        // The open duration has passed, one trial request goes through
+        let trial = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        trial.record(true);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow().is_some());
+
+        // A failed trial opens the breaker again, for another open duration
+        breaker.allow().unwrap().record(false);
+        breaker.allow().unwrap().record(false);
+        let trial = breaker.allow().unwrap();
+        trial.record(false);
+        assert!(breaker.is_open());
+        assert!(breaker.allow().is_some());
+
+        // So does a trial that was given up on, without an outcome
+        let trial = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        drop(trial);
+        assert!(breaker.is_open());
+        breaker.allow().unwrap().record(true);
+        assert!(!breaker.is_open());
+
+        // Permits of requests let through before the breaker opened don't reopen it
+        let closed = breaker.allow().unwrap();
+        drop(closed);
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent(&reqwest::Method::GET));
+        assert!(is_idempotent(&reqwest::Method::PUT));
+        assert!(!is_idempotent(&reqwest::Method::POST));
+        assert!(!is_idempotent(
+            &reqwest::Method::from_bytes(b"MOVE").unwrap()
+        ));
+    }
+}
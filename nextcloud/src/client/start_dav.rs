@@ -1,23 +1,101 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
 use super::dav::{
-    copy_method, mkcol_method, move_method, propfind_method, proppatch_method, DavError,
-    DavProvider,
+    copy_method, lock_method, mkcol_method, move_method, propfind_method, proppatch_method,
+    report_method, unlock_method, DavError, DavProvider,
 };
 
+// Characters of a path segment that have to be escaped in a URL, '/' separates segments
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+// Absolute URL of a path below the user's files, as used in Destination headers
+pub fn files_url(provider: &dyn DavProvider, path: &str) -> String {
+    provider.files_url_string() + &utf8_percent_encode(path, PATH).to_string()
+}
+
+// Absolute URL below the user's trash bin, "trash/..." for trashed items
+pub fn trashbin_url(provider: &dyn DavProvider, path: &str) -> String {
+    provider.trashbin_url_string() + &utf8_percent_encode(path, PATH).to_string()
+}
+
+// Absolute URL below the versions of the user's files, "versions/<file id>/..." for those
+// of one file
+pub fn versions_url(provider: &dyn DavProvider, path: &str) -> String {
+    provider.versions_url_string() + &utf8_percent_encode(path, PATH).to_string()
+}
+
 fn start_request(
     provider: &dyn DavProvider,
     method: reqwest::Method,
     path: &str,
 ) -> Result<reqwest::RequestBuilder, DavError> {
-    let url_string = provider.files_url_string() + path;
-    let url = url::Url::parse(&url_string).map_err(DavError::BadUrl)?;
-    let client = reqwest::Client::new();
-    let request = client
+    start_request_url(provider, method, &files_url(provider, path))
+}
+
+fn start_request_url(
+    provider: &dyn DavProvider,
+    method: reqwest::Method,
+    url_string: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    let url = url::Url::parse(url_string).map_err(DavError::BadUrl)?;
+    let request = provider
+        .http_client()
         .request(method, url)
         .header("User-Agent", "provider-fuse");
 
     Ok(provider.add_auth_header(request))
 }
 
+// Requests that modify a resource we hold a lock on have to present the lock token
+fn with_lock_token(
+    provider: &dyn DavProvider,
+    path: &str,
+    request: reqwest::RequestBuilder,
+) -> reqwest::RequestBuilder {
+    match provider.active_lock(path) {
+        Some(lock) => request.header("If", lock.if_header()),
+        None => request,
+    }
+}
+
+// Point a MOVE at to. Locks we hold on either end have to be presented, each tagged with
+// the resource it is on since only one of them is the request URL.
+pub fn with_destination(
+    provider: &dyn DavProvider,
+    from: Option<&str>,
+    to: &str,
+    request: reqwest::RequestBuilder,
+) -> reqwest::RequestBuilder {
+    let destination = files_url(provider, to);
+    let lists: Vec<String> = from
+        .map(|from| (files_url(provider, from), from))
+        .into_iter()
+        .chain([(destination.clone(), to)])
+        .filter_map(|(url, path)| Some(provider.active_lock(path)?.tagged_if_header(&url)))
+        .collect();
+
+    let request = request.header("Destination", destination);
+    if lists.is_empty() {
+        return request;
+    }
+    request.header("If", lists.join(" "))
+}
+
 pub fn start_propfind(
     provider: &dyn DavProvider,
     path: &str,
@@ -29,7 +107,8 @@ pub fn start_proppatch(
     provider: &dyn DavProvider,
     path: &str,
 ) -> Result<reqwest::RequestBuilder, DavError> {
-    start_request(provider, proppatch_method(), path)
+    let request = start_request(provider, proppatch_method(), path)?;
+    Ok(with_lock_token(provider, path, request))
 }
 
 pub fn start_mkcol(
@@ -50,14 +129,16 @@ pub fn start_put(
     provider: &dyn DavProvider,
     path: &str,
 ) -> Result<reqwest::RequestBuilder, DavError> {
-    start_request(provider, reqwest::Method::PUT, path)
+    let request = start_request(provider, reqwest::Method::PUT, path)?;
+    Ok(with_lock_token(provider, path, request))
 }
 
 pub fn start_delete(
     provider: &dyn DavProvider,
     path: &str,
 ) -> Result<reqwest::RequestBuilder, DavError> {
-    start_request(provider, reqwest::Method::DELETE, path)
+    let request = start_request(provider, reqwest::Method::DELETE, path)?;
+    Ok(with_lock_token(provider, path, request))
 }
 
 pub fn start_copy(
@@ -69,9 +150,11 @@ pub fn start_copy(
 
 pub fn start_move(
     provider: &dyn DavProvider,
-    path: &str,
+    from: &str,
+    to: &str,
 ) -> Result<reqwest::RequestBuilder, DavError> {
-    start_request(provider, move_method(), path)
+    let request = start_request(provider, move_method(), from)?;
+    Ok(with_destination(provider, Some(from), to, request))
 }
 
 pub fn start_post(
@@ -87,3 +170,62 @@ pub fn start_head(
 ) -> Result<reqwest::RequestBuilder, DavError> {
     start_request(provider, reqwest::Method::HEAD, path)
 }
+
+pub fn start_lock(
+    provider: &dyn DavProvider,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    start_request(provider, lock_method(), path)
+}
+
+pub fn start_report(
+    provider: &dyn DavProvider,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    start_request(provider, report_method(), path)
+}
+
+pub fn start_unlock(
+    provider: &dyn DavProvider,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    start_request(provider, unlock_method(), path)
+}
+
+// OCS endpoints live outside of the DAV tree and need the OCS-APIRequest header
+pub fn start_ocs(
+    provider: &dyn DavProvider,
+    method: reqwest::Method,
+    endpoint: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    let url_string = provider.ocs_url_string() + endpoint;
+    let request = start_request_url(provider, method, &url_string)?;
+
+    Ok(request.header("OCS-APIRequest", "true"))
+}
+
+pub fn start_trashbin(
+    provider: &dyn DavProvider,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    start_request_url(provider, method, &trashbin_url(provider, path))
+}
+
+pub fn start_versions(
+    provider: &dyn DavProvider,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    start_request_url(provider, method, &versions_url(provider, path))
+}
+
+// Chunked uploads are staged below the uploads collection of the user, path is relative to it
+pub fn start_upload(
+    provider: &dyn DavProvider,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::RequestBuilder, DavError> {
+    let url_string = provider.uploads_url_string() + &utf8_percent_encode(path, PATH).to_string();
+    start_request_url(provider, method, &url_string)
+}
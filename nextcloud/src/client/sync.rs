@@ -0,0 +1,92 @@
+use quick_xml::{events::Event, Reader};
+
+use super::{
+    dav::DavItem,
+    xml::{ToXml, XmlTag},
+};
+
+// Body of a sync-collection REPORT (RFC 6578). Without a token every item is reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncCollection {
+    pub token: Option<String>,
+    pub props: Vec<XmlTag>,
+}
+
+impl ToXml for SyncCollection {
+    fn to_xml(&self) -> String {
+        let props = self
+            .props
+            .iter()
+            .map(|prop| format!("<{} />", prop.full_name()))
+            .collect::<Vec<String>>()
+            .join("");
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8" ?>
+            <d:sync-collection
+              xmlns:d="DAV:"
+              xmlns:oc="http://owncloud.org/ns"
+              xmlns:nc="http://nextcloud.org/ns">
+                <d:sync-token>{}</d:sync-token>
+                <d:sync-level>infinite</d:sync-level>
+                <d:prop>{}</d:prop>
+            </d:sync-collection>"#,
+            quick_xml::escape::escape(self.token.as_deref().unwrap_or("")),
+            props
+        )
+    }
+}
+
+// What changed below a collection since the token of the previous sync
+#[derive(Debug, Clone)]
+pub struct SyncChanges {
+    // To ask with next time
+    pub token: String,
+    pub changed: Vec<DavItem>,
+    pub removed: Vec<String>,
+}
+
+// The d:sync-token of a multistatus, which the propfind parser skips
+pub fn parse_sync_token(body: &str) -> Option<String> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+    let mut in_token = false;
+
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) => in_token = e.local_name().as_ref() == b"sync-token",
+            Event::Text(e) if in_token => return e.unescape().ok().map(|token| token.to_string()),
+            Event::End(_) => in_token = false,
+            Event::Eof => return None,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_token() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/a.txt</d:href>
+    <d:status>HTTP/1.1 404 Not Found</d:status>
+  </d:response>
+  <d:sync-token>http://example.com/ns/sync/1234</d:sync-token>
+</d:multistatus>"#;
+        assert_eq!(
+            parse_sync_token(body).as_deref(),
+            Some("http://example.com/ns/sync/1234")
+        );
+        assert_eq!(parse_sync_token("<d:multistatus xmlns:d=\"DAV:\"/>"), None);
+
+        let request = SyncCollection {
+            token: None,
+            props: vec![XmlTag::new("d".to_string(), "getetag".to_string())],
+        };
+        assert!(request.to_xml().contains("<d:sync-token></d:sync-token>"));
+        assert!(request.to_xml().contains("<d:getetag />"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{dav::DavItem, prop::MultiStatusResponse, xml::XmlTag};
+
+// An item in the trash bin. The path of item is relative to the trash, e.g.
+// "a.txt.d1700000000" or "Folder.d1700000000/b.txt" for what was inside a deleted folder.
+#[derive(Debug, Clone)]
+pub struct TrashItem {
+    pub item: DavItem,
+    // Name and folder the item was deleted from, relative to the user's files. Only the
+    // items deleted themselves have these.
+    pub original_name: Option<String>,
+    pub original_location: Option<String>,
+    pub deleted: Option<SystemTime>,
+}
+
+impl TrashItem {
+    pub fn props() -> Vec<XmlTag> {
+        let mut props = DavItem::props();
+        props.extend(
+            [
+                "trashbin-filename",
+                "trashbin-original-location",
+                "trashbin-deletion-time",
+            ]
+            .iter()
+            .map(|name| XmlTag::new("nc".to_string(), name.to_string())),
+        );
+        props
+    }
+
+    // root_path is the path part of the URL of the trash
+    pub fn from_response(response: &MultiStatusResponse, root_path: &str) -> TrashItem {
+        let text = |name: &str| {
+            response
+                .prop_text("nc", name)
+                .map(|text| text.trim().to_string())
+                .filter(|text| !text.is_empty())
+        };
+
+        TrashItem {
+            item: DavItem::from_response(response, root_path),
+            original_name: text("trashbin-filename"),
+            original_location: text("trashbin-original-location"),
+            deleted: text("trashbin-deletion-time")
+                .and_then(|time| time.parse().ok())
+                .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+        }
+    }
+
+    // Name to show for the item, what it was called before it was deleted
+    pub fn name(&self) -> &str {
+        self.original_name
+            .as_deref()
+            .unwrap_or_else(|| self.item.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::pase_propfind::pase_propfind;
+
+    #[test]
+    fn test_trash_item() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/trashbin/user/trash/a%20b.txt.d1700000000</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>3</d:getcontentlength>
+        <nc:trashbin-filename>a b.txt</nc:trashbin-filename>
+        <nc:trashbin-original-location>Documents/a b.txt</nc:trashbin-original-location>
+        <nc:trashbin-deletion-time>1700000000</nc:trashbin-deletion-time>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let response = &pase_propfind(body.to_string()).unwrap().responses[0];
+        let trashed = TrashItem::from_response(response, "/remote.php/dav/trashbin/user/trash/");
+
+        assert_eq!(trashed.item.path(), "a b.txt.d1700000000");
+        assert_eq!(trashed.name(), "a b.txt");
+        assert_eq!(
+            trashed.original_location.as_deref(),
+            Some("Documents/a b.txt")
+        );
+        assert_eq!(
+            trashed.deleted,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use super::{dav::DavItem, http_date::format_iso_date, prop::MultiStatusResponse, xml::XmlTag};
+
+// An older version of a file. The name of item is what the server calls the version,
+// the time it was replaced as a unix timestamp.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub item: DavItem,
+    // Set in the versions sidebar of the web interface
+    pub label: Option<String>,
+}
+
+impl Version {
+    pub fn props() -> Vec<XmlTag> {
+        let mut props = DavItem::props();
+        props.push(XmlTag::new("nc".to_string(), "version-label".to_string()));
+        props
+    }
+
+    // root_path is the path part of the URL of the versions of the file
+    pub fn from_response(response: &MultiStatusResponse, root_path: &str) -> Version {
+        Version {
+            item: DavItem::from_response(response, root_path),
+            label: response
+                .prop_text("nc", "version-label")
+                .map(|label| label.trim().to_string())
+                .filter(|label| !label.is_empty()),
+        }
+    }
+
+    // When the file was saved like this, e.g. "2024-03-28T20:44:36Z"
+    pub fn timestamp(&self) -> String {
+        match self.item.last_modified() {
+            Some(time) => format_iso_date(time),
+            None => self.item.name().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::pase_propfind::pase_propfind;
+
+    #[test]
+    fn test_version() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:nc="http://nextcloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/versions/user/versions/42/1711658676</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>3</d:getcontentlength>
+        <d:getlastmodified>Thu, 28 Mar 2024 20:44:36 GMT</d:getlastmodified>
+        <nc:version-label>Draft</nc:version-label>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let response = &pase_propfind(body.to_string()).unwrap().responses[0];
+        let version =
+            Version::from_response(response, "/remote.php/dav/versions/user/versions/42/");
+
+        assert_eq!(version.item.name(), "1711658676");
+        assert_eq!(version.label.as_deref(), Some("Draft"));
+        assert_eq!(version.timestamp(), "2024-03-28T20:44:36Z");
+    }
+}
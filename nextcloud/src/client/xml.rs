@@ -1,11 +1,25 @@
 use std::ptr;
 
+use super::metadata::ATTRIBUTE_NAMESPACE;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct XmlTag {
     pub namespace: String,
     pub name: String,
 }
 
+// Prefixes used throughout the crate for the namespaces we know about, so "d" always means
+// DAV: no matter which prefix the server chose
+const KNOWN_NAMESPACES: [(&[u8], &str); 7] = [
+    (b"DAV:", "d"),
+    (b"http://owncloud.org/ns", "oc"),
+    (b"http://nextcloud.org/ns", "nc"),
+    (b"http://sabredav.org/ns", "s"),
+    (b"http://open-collaboration-services.org/ns", "ocs"),
+    (b"http://open-cloud-mesh.org/ns", "ocm"),
+    (ATTRIBUTE_NAMESPACE.as_bytes(), "nf"),
+];
+
 impl XmlTag {
     pub fn new(namespace: String, name: String) -> Self {
         Self { namespace, name }
@@ -14,6 +28,28 @@ impl XmlTag {
     pub fn full_name(&self) -> String {
         format!("{}:{}", self.namespace, self.name)
     }
+
+    pub fn from_resolved(
+        namespace: &quick_xml::name::ResolveResult,
+        qname: quick_xml::name::QName,
+    ) -> Self {
+        let known = match namespace {
+            quick_xml::name::ResolveResult::Bound(namespace) => KNOWN_NAMESPACES
+                .iter()
+                .find(|(uri, _)| *uri == namespace.into_inner())
+                .map(|(_, prefix)| prefix.to_string()),
+            _ => None,
+        };
+
+        match known {
+            Some(prefix) => Self {
+                namespace: prefix,
+                ..Self::from(qname)
+            },
+            // Unknown namespaces keep the prefix used in the document
+            None => Self::from(qname),
+        }
+    }
 }
 
 impl<'a> From<quick_xml::name::QName<'a>> for XmlTag {
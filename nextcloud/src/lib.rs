@@ -1,6 +1,12 @@
 mod client;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
 
-pub use client::Nextcloud;
+pub use client::{
+    ActiveLock, CircuitBreakerConfig, DavError, DavItem, File, Folder, LockTimeout, Metadata,
+    NcLock, NcLockOwnerType, Nextcloud, Permissions, PushEvent, PushEvents, Quota, QuotaValue,
+    RetryPolicy, SyncChanges, TrashItem, UserInfo, UserQuota, Version,
+};
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -8,23 +14,490 @@ pub fn add(left: usize, right: usize) -> usize {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
+    use mock::{Failure, MockServer};
+
+    #[tokio::test]
+    async fn it_works() {
+        let server = MockServer::start().await;
+        server.add_file("Documents/report.odt", b"report");
+        server.add_folder("Photos");
+
+        let provider = server.client();
+        let mut items = provider.ls("").await.unwrap();
+        items.sort_by(|a, b| a.path().cmp(b.path()));
+
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[0], DavItem::Folder(_)));
+        assert_eq!(items[0].path(), "Documents");
+
+        let items = provider.ls("Documents/").await.unwrap();
+        match &items[..] {
+            [DavItem::File(file)] => {
+                assert_eq!(file.name, "report.odt");
+                assert_eq!(file.path, "Documents/report.odt");
+                assert_eq!(file.size, 6);
+            }
+            _ => panic!("unexpected listing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quota_and_user_info() {
+        let server = MockServer::start().await;
+        server.add_file("a.bin", &[0; 100]);
+        server.set_quota(Some(1000));
+
+        let provider = server.client();
+        let quota = provider.quota("").await.unwrap();
+        assert_eq!(quota.used, 100);
+        assert_eq!(quota.available, QuotaValue::Bytes(900));
+
+        let user = provider.user_info().await.unwrap();
+        assert_eq!(user.id, mock::USER);
+        assert_eq!(user.quota.total, Some(1000));
+    }
 
-    #[test]
-    fn it_works() {
-        let mut config = include_str!("../../.config").split_whitespace();
-        let username = config.next().unwrap();
-        let password = config.next().unwrap();
-        let base_url = config.next().unwrap();
-        let dav_path = config.next().unwrap();
+    #[tokio::test]
+    async fn test_stat_and_read() {
+        let server = MockServer::start().await;
+        server.add_file("Notes/Report #3 (draft).txt", b"0123456789");
 
-        let provider = client::Nextcloud::new(
-            base_url.to_string(),
-            dav_path.to_string(),
-            username.to_string(),
-            password.to_string(),
+        let provider = server.client();
+        let item = provider.stat("Notes/Report #3 (draft).txt").await.unwrap();
+        assert_eq!(item.name(), "Report #3 (draft).txt");
+        assert!(matches!(item, DavItem::File(File { size: 10, .. })));
+        assert_eq!(
+            item.etag(),
+            server.etag("Notes/Report #3 (draft).txt").as_deref()
         );
+        assert!(item.last_modified().is_some());
+        assert_eq!(
+            item.file_id(),
+            server.file_id("Notes/Report #3 (draft).txt")
+        );
+        assert!(item
+            .permissions()
+            .is_some_and(|permissions| permissions.contains('W')));
+
+        let item = provider.stat("").await.unwrap();
+        assert!(matches!(item, DavItem::Folder(_)));
+        assert_eq!(item.path(), "");
+
+        let path = "Notes/Report #3 (draft).txt";
+        assert_eq!(provider.read(path, 2, 3).await.unwrap(), b"234");
+        assert_eq!(provider.read(path, 8, 100).await.unwrap(), b"89");
+        assert!(provider.read(path, 10, 5).await.unwrap().is_empty());
+        assert!(matches!(
+            provider.stat("Notes/missing").await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 404
+        ));
+
+        // Requests reuse the connection, those of clones with other settings too
+        let chunked = provider.clone().with_chunk_size(4);
+        assert_eq!(chunked.read(path, 0, 1).await.unwrap(), b"0");
+        let requests = server.requests();
+        assert!(requests.len() > 5);
+        assert!(requests.iter().all(|request| request.connection == 0));
+    }
+
+    #[tokio::test]
+    async fn test_put_and_upload() {
+        let server = MockServer::start().await;
+        server.add_folder("Backup");
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+
+        let provider = server.client().with_chunk_size(4);
+        let etag = provider
+            .put("Backup/small.txt", b"abc".to_vec(), Some(mtime), None)
+            .await
+            .unwrap();
+        assert_eq!(etag, server.etag("Backup/small.txt"));
+        assert_eq!(server.read_file("Backup/small.txt").unwrap(), b"abc");
+        assert_eq!(server.last_modified("Backup/small.txt"), Some(mtime));
+
+        let etag = provider.create("Backup/empty.txt").await.unwrap();
+        assert_eq!(etag, server.etag("Backup/empty.txt"));
+        assert_eq!(server.read_file("Backup/empty.txt").unwrap(), b"");
+        assert!(matches!(
+            provider.create("Backup/small.txt").await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 412
+        ));
+        assert_eq!(server.read_file("Backup/small.txt").unwrap(), b"abc");
+
+        let local = std::env::temp_dir().join(format!("nextcloud-upload-{}", std::process::id()));
+        std::fs::write(&local, b"0123456789").unwrap();
+        server.clear_requests();
+
+        let etag = provider
+            .upload("Backup/large #1.bin", &local, Some(mtime), None)
+            .await
+            .unwrap();
+        std::fs::remove_file(&local).unwrap();
+
+        assert_eq!(etag, server.etag("Backup/large #1.bin"));
+        assert_eq!(
+            server.read_file("Backup/large #1.bin").unwrap(),
+            b"0123456789"
+        );
+        assert_eq!(server.last_modified("Backup/large #1.bin"), Some(mtime));
+        let methods: Vec<String> = server
+            .requests()
+            .into_iter()
+            .map(|request| request.method)
+            .collect();
+        assert_eq!(methods, ["MKCOL", "PUT", "PUT", "PUT", "MOVE"]);
+
+        // Someone else changed the file since we saw it
+        let stale = etag.unwrap();
+        let local = std::env::temp_dir().join(format!("nextcloud-conflict-{}", std::process::id()));
+        server.add_file("Backup/large #1.bin", b"theirs");
+        for size in [3, 10] {
+            std::fs::write(&local, &b"0123456789"[..size]).unwrap();
+            assert!(matches!(
+                provider
+                    .upload("Backup/large #1.bin", &local, None, Some(&stale))
+                    .await,
+                Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 412
+            ));
+        }
+        assert!(matches!(
+            provider
+                .rename("Backup/large #1.bin", "Backup/moved.bin", false, Some(&stale))
+                .await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 412
+        ));
+        let current = server.etag("Backup/large #1.bin").unwrap();
+        provider
+            .upload("Backup/large #1.bin", &local, None, Some(&current))
+            .await
+            .unwrap();
+        std::fs::remove_file(&local).unwrap();
+        assert_eq!(
+            server.read_file("Backup/large #1.bin").unwrap(),
+            b"0123456789"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mkdir_delete_rename() {
+        let server = MockServer::start().await;
+        server.add_file("Inbox/a b.txt", b"a");
+        server.add_file("Archive/old.txt", b"old");
+
+        let provider = server.client();
+        provider.mkdir("Inbox/Sorted #1").await.unwrap();
+        assert!(matches!(
+            provider.mkdir("Inbox/Sorted #1").await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 405
+        ));
+        assert!(matches!(
+            provider.mkdir("Missing/child").await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 409
+        ));
+
+        provider
+            .rename("Inbox/a b.txt", "Inbox/Sorted #1/a b.txt", false, None)
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Inbox/Sorted #1/a b.txt").unwrap(), b"a");
+        assert!(server.read_file("Inbox/a b.txt").is_none());
+
+        assert!(matches!(
+            provider
+                .rename("Inbox/Sorted #1/a b.txt", "Archive/old.txt", false, None)
+                .await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 412
+        ));
+        provider
+            .rename("Inbox/Sorted #1/a b.txt", "Archive/old.txt", true, None)
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("Archive/old.txt").unwrap(), b"a");
+
+        provider.delete("Inbox").await.unwrap();
+        assert!(provider.stat("Inbox/Sorted #1").await.is_err());
+        assert_eq!(provider.ls("").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let server = MockServer::start().await;
+        server.add_file("a.odt", b"content");
+
+        let provider = server.client();
+        let lock = provider
+            .lock("a.odt", "nextcloud-fuse", LockTimeout::Seconds(60))
+            .await
+            .unwrap();
+        assert!(lock.token.starts_with("opaquelocktoken:"));
+        assert!(provider.lock_status("a.odt").await.unwrap().is_some());
+        assert!(matches!(
+            server
+                .client()
+                .lock("a.odt", "other", LockTimeout::Infinite)
+                .await,
+            Err(DavError::Locked)
+        ));
+
+        // Replacing the locked file presents the lock on the destination
+        server.add_file("b.odt", b"replacement");
+        server.clear_requests();
+        provider.rename("b.odt", "a.odt", true, None).await.unwrap();
+        assert_eq!(server.read_file("a.odt").unwrap(), b"replacement");
+        let destination = format!(
+            "{}/{}/files/{}/a.odt",
+            server.origin(),
+            mock::DAV_PATH,
+            mock::USER
+        );
+        assert_eq!(
+            server.requests()[0].header("If"),
+            Some(lock.tagged_if_header(&destination).as_str())
+        );
+        let local = std::env::temp_dir().join(format!("nextcloud-locked-{}", std::process::id()));
+        std::fs::write(&local, b"0123456789").unwrap();
+        provider
+            .clone()
+            .with_chunk_size(4)
+            .upload("a.odt", &local, None, None)
+            .await
+            .unwrap();
+        std::fs::remove_file(&local).unwrap();
+        assert_eq!(server.read_file("a.odt").unwrap(), b"0123456789");
+
+        provider.unlock("a.odt").await.unwrap();
+        assert!(provider.lock_status("a.odt").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_and_attributes() {
+        let server = MockServer::start().await;
+        server.add_file("Notes/todo.md", b"- [ ] write tests");
+
+        let provider = server.client();
+        let metadata = provider.metadata("Notes/todo.md").await.unwrap();
+        assert_eq!(metadata.file_id, server.file_id("Notes/todo.md"));
+        assert_eq!(metadata.etag, server.etag("Notes/todo.md"));
+        assert_eq!(metadata.owner_display_name.as_deref(), Some("user"));
+        assert!(!metadata.favorite);
+
+        provider.set_favorite("Notes/todo.md", true).await.unwrap();
+        assert!(provider.metadata("Notes/todo.md").await.unwrap().favorite);
+
+        let value = b" 100% <done> & \xff";
+        provider
+            .set_attribute("Notes/todo.md", "user.x y", Some(value))
+            .await
+            .unwrap();
+        provider
+            .set_attribute("Notes/todo.md", "user.tag", Some(b""))
+            .await
+            .unwrap();
+        assert_eq!(
+            provider
+                .attribute("Notes/todo.md", "user.x y")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some(&value[..])
+        );
+        assert_eq!(
+            provider.attributes("Notes/todo.md").await.unwrap(),
+            vec![
+                ("user.tag".to_string(), Vec::new()),
+                ("user.x y".to_string(), value.to_vec()),
+            ]
+        );
+
+        provider
+            .set_attribute("Notes/todo.md", "user.tag", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            provider
+                .attribute("Notes/todo.md", "user.tag")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            provider.set_attribute("Missing", "user.tag", None).await,
+            Err(DavError::UnexpectedStatus(status)) if status.as_u16() == 404
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let server = MockServer::start().await;
+        server.add_file("a.txt", b"a");
+        server.fail(Failure::status(503).header("Retry-After", "0").times(2));
+
+        let provider = server.client();
+        assert_eq!(provider.ls("").await.unwrap().len(), 1);
+        assert_eq!(server.requests().len(), 3);
+
+        server.fail(Failure::status(502).times(10));
+        assert!(matches!(
+            provider.ls("").await,
+            Err(DavError::UnexpectedStatus(_))
+        ));
+        // One request failing after all its retries does not open the breaker
+        assert!(!provider.is_unavailable());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let server = MockServer::start().await;
+        server.add_file("a.txt", b"a");
+        let breaker = CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_duration: Duration::ZERO,
+        };
+        let provider = server
+            .client()
+            .with_retry_policy(RetryPolicy::none(), breaker.clone());
+
+        // A trial request failing in any way opens the breaker again
+        server.fail(Failure::disconnect().times(2));
+        assert!(provider.ls("").await.is_err());
+        assert!(provider.is_unavailable());
+        assert!(matches!(provider.ls("").await, Err(DavError::Network(_))));
+        assert!(provider.is_unavailable());
+        assert_eq!(provider.ls("").await.unwrap().len(), 1);
+        assert!(!provider.is_unavailable());
+
+        // A server that accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // The first connection is closed right away, later ones are left hanging
+            let _ = listener.accept().await;
+            let mut hanging = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                hanging.push(connection);
+            }
+        });
+        let provider = Nextcloud::new(origin, "remote.php/dav".into(), "user".into(), "".into())
+            .with_retry_policy(RetryPolicy::none(), breaker);
+        assert!(provider.ls("").await.is_err());
+        assert!(provider.is_unavailable());
+
+        // The trial request is given up on, the next request gets to try instead of failing
+        // fast forever
+        let timeout = Duration::from_millis(100);
+        assert!(tokio::time::timeout(timeout, provider.ls(""))
+            .await
+            .is_err());
+        assert!(tokio::time::timeout(timeout, provider.ls(""))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trash() {
+        let server = MockServer::start().await;
+        server.add_file("Docs/a.txt", b"hello");
+        server.add_file("Old/b.txt", b"bye");
+
+        let provider = server.client();
+        provider.delete("Docs/a.txt").await.unwrap();
+        provider.delete("Old").await.unwrap();
+
+        let mut trash = provider.trash("").await.unwrap();
+        trash.sort_by(|a, b| a.name().cmp(b.name()));
+        assert_eq!(trash.len(), 2);
+        assert_eq!(trash[1].name(), "a.txt");
+        assert_eq!(trash[1].original_location.as_deref(), Some("Docs/a.txt"));
+        assert!(trash[1].deleted.is_some());
+        assert!(matches!(trash[0].item, DavItem::Folder(_)));
+
+        let file = trash[1].item.path().to_string();
+        assert_eq!(provider.read_trash(&file, 1, 3).await.unwrap(), b"ell");
+        let folder = trash[0].item.path().to_string();
+        let inside = provider.trash(&folder).await.unwrap();
+        assert_eq!(inside.len(), 1);
+        assert_eq!(inside[0].name(), "b.txt");
+        assert_eq!(inside[0].original_location, None);
+
+        provider.restore(&file).await.unwrap();
+        assert_eq!(
+            server.read_file("Docs/a.txt").as_deref(),
+            Some(&b"hello"[..])
+        );
+        provider.purge(&folder).await.unwrap();
+        assert!(server.trash().is_empty());
+        assert!(!server.exists("Old"));
+    }
+
+    #[tokio::test]
+    async fn test_versions() {
+        let server = MockServer::start().await;
+        let provider = server.client();
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        provider
+            .put("a.txt", b"one".to_vec(), Some(old), None)
+            .await
+            .unwrap();
+        provider
+            .put("a.txt", b"two".to_vec(), None, None)
+            .await
+            .unwrap();
+
+        let file_id = server.file_id("a.txt").unwrap();
+        let versions = provider.versions(file_id).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].item.name(), "1700000000");
+        assert_eq!(versions[0].timestamp(), "2023-11-14T22:13:20Z");
+        assert_eq!(
+            provider
+                .read_version(file_id, "1700000000", 0, 10)
+                .await
+                .unwrap(),
+            b"one"
+        );
+
+        provider
+            .restore_version(file_id, "1700000000")
+            .await
+            .unwrap();
+        assert_eq!(server.read_file("a.txt").as_deref(), Some(&b"one"[..]));
+        assert_eq!(server.file_id("a.txt"), Some(file_id));
+        let versions = provider.versions(file_id).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(
+            provider
+                .read_version(file_id, versions[0].item.name(), 0, 10)
+                .await
+                .unwrap(),
+            b"two"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_events() {
+        use futures_util::StreamExt;
+
+        let server = MockServer::start().await;
+        let provider = server.client();
+        assert!(provider.push_events().await.unwrap().is_none());
+
+        let push = mock::PushServer::start().await;
+        server.set_push_endpoint(Some(push.url()));
+        let mut events = provider.push_events().await.unwrap().unwrap();
+        assert_eq!(events.next().await, Some(PushEvent::Connected));
+
+        push.send("notify_activity");
+        push.send("notify_file_id [41,42]");
+        push.send("notify_file");
+        assert_eq!(events.next().await, Some(PushEvent::FileIds(vec![41, 42])));
+        assert_eq!(events.next().await, Some(PushEvent::Files));
 
-        println!("{:?}", provider);
+        // Reconnects on its own
+        push.disconnect();
+        assert_eq!(events.next().await, Some(PushEvent::Connected));
+        assert_eq!(push.connections(), 2);
     }
 }
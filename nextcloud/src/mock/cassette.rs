@@ -0,0 +1,238 @@
+use quick_xml::{events::Event, Reader};
+
+use crate::DavError;
+
+// A recorded request/response pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interaction {
+    pub method: String,
+    // Percent encoded request path as sent to the server
+    pub path: String,
+    // Only matched when recorded
+    pub depth: Option<String>,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+// Exchanges captured against a real server, replayed by ReplayServer. Cassettes under
+// tests/cassettes/synthetic are written by hand to match a server's responses, they show
+// the parser copes with those shapes but are no proof of compatibility with the server.
+//
+// The on-disk format is XML with bodies in CDATA sections:
+//
+//   <cassette server="nextcloud-30" user="alice">
+//     <interaction>
+//       <request method="PROPFIND" path="/remote.php/dav/files/alice/" depth="1" />
+//       <response status="207">
+//         <header name="Content-Type">application/xml; charset=utf-8</header>
+//         <body><![CDATA[<?xml version="1.0"?><d:multistatus ...]]></body>
+//       </response>
+//     </interaction>
+//   </cassette>
+//
+// The output of `curl -v` can be used directly through from_curl_trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cassette {
+    // Server product and version the exchanges were captured from
+    pub server: String,
+    pub user: String,
+    pub interactions: Vec<Interaction>,
+}
+
+fn attribute(e: &quick_xml::events::BytesStart, name: &str) -> Result<Option<String>, DavError> {
+    for attribute in e.attributes() {
+        let attribute =
+            attribute.map_err(|error| DavError::XmlParse(quick_xml::Error::from(error)))?;
+        if attribute.key.as_ref() == name.as_bytes() {
+            let value = attribute.unescape_value().map_err(DavError::XmlParse)?;
+            return Ok(Some(value.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn empty_interaction() -> Interaction {
+    Interaction {
+        method: String::new(),
+        path: String::new(),
+        depth: None,
+        status: 0,
+        headers: Vec::new(),
+        body: String::new(),
+    }
+}
+
+// User name from a path like /remote.php/dav/files/<user>/...
+fn user_of(path: &str) -> Option<String> {
+    let mut segments = path.split('/').skip_while(|segment| *segment != "files");
+    segments.next()?;
+    segments.next().map(|user| user.to_string())
+}
+
+impl Cassette {
+    pub fn parse(xml: &str) -> Result<Cassette, DavError> {
+        let mut reader = Reader::from_str(xml);
+
+        let mut cassette: Option<Cassette> = None;
+        let mut interaction: Option<Interaction> = None;
+        let mut header_name: Option<String> = None;
+        let mut in_body = false;
+
+        loop {
+            match reader.read_event().map_err(DavError::XmlParse)? {
+                Event::Start(e) | Event::Empty(e) => match e.name().as_ref() {
+                    b"cassette" => {
+                        cassette = Some(Cassette {
+                            server: attribute(&e, "server")?.unwrap_or_default(),
+                            user: attribute(&e, "user")?.unwrap_or_default(),
+                            interactions: Vec::new(),
+                        })
+                    }
+                    b"interaction" => interaction = Some(empty_interaction()),
+                    b"request" => {
+                        let i = interaction.as_mut().ok_or(DavError::InvariantViolation)?;
+                        i.method = attribute(&e, "method")?.ok_or(DavError::NoContent)?;
+                        i.path = attribute(&e, "path")?.ok_or(DavError::NoContent)?;
+                        i.depth = attribute(&e, "depth")?;
+                    }
+                    b"response" => {
+                        let i = interaction.as_mut().ok_or(DavError::InvariantViolation)?;
+                        i.status = attribute(&e, "status")?
+                            .and_then(|status| status.parse().ok())
+                            .ok_or(DavError::NoContent)?;
+                    }
+                    b"header" => header_name = attribute(&e, "name")?,
+                    b"body" => in_body = true,
+                    _ => (),
+                },
+                Event::End(e) => match e.name().as_ref() {
+                    b"interaction" => {
+                        let i = interaction.take().ok_or(DavError::InvariantViolation)?;
+                        cassette
+                            .as_mut()
+                            .ok_or(DavError::InvariantViolation)?
+                            .interactions
+                            .push(i);
+                    }
+                    b"header" => header_name = None,
+                    b"body" => in_body = false,
+                    _ => (),
+                },
+                Event::Text(e) => {
+                    let text = e.unescape().map_err(DavError::XmlParse)?.to_string();
+                    if let (Some(i), Some(name)) = (interaction.as_mut(), header_name.as_ref()) {
+                        i.headers.push((name.clone(), text.trim().to_string()));
+                    } else if let (Some(i), true) = (interaction.as_mut(), in_body) {
+                        i.body.push_str(&text);
+                    }
+                }
+                Event::CData(e) => {
+                    if let (Some(i), true) = (interaction.as_mut(), in_body) {
+                        i.body.push_str(&String::from_utf8_lossy(&e.into_inner()));
+                    }
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+        }
+
+        cassette.ok_or(DavError::NoContent)
+    }
+
+    // Build a cassette from the output of `curl -v`, as in example.html. Request lines start
+    // with "> ", response headers with "< ", the body follows the headers until the next
+    // "* " info line.
+    pub fn from_curl_trace(server: &str, trace: &str) -> Result<Cassette, DavError> {
+        let mut interactions: Vec<Interaction> = Vec::new();
+        let mut current: Option<Interaction> = None;
+        let mut in_body = false;
+
+        for line in trace.lines() {
+            if let Some(request) = line.strip_prefix("> ") {
+                let is_header = current
+                    .as_ref()
+                    .is_some_and(|interaction| !interaction.path.is_empty() && !in_body);
+
+                if is_header {
+                    if let (Some(interaction), Some((name, value))) =
+                        (current.as_mut(), request.split_once(':'))
+                    {
+                        if name.eq_ignore_ascii_case("Depth") {
+                            interaction.depth = Some(value.trim().to_string());
+                        }
+                    }
+                    continue;
+                }
+
+                if let Some(done) = current.take() {
+                    interactions.push(done);
+                }
+                in_body = false;
+
+                let mut parts = request.split_whitespace();
+                let mut interaction = empty_interaction();
+                interaction.method = parts.next().ok_or(DavError::NoContent)?.to_string();
+                interaction.path = parts.next().ok_or(DavError::NoContent)?.to_string();
+                current = Some(interaction);
+            } else if let Some(response) = line.strip_prefix('<') {
+                let interaction = match current.as_mut() {
+                    Some(interaction) => interaction,
+                    None => continue,
+                };
+
+                if in_body {
+                    interaction.body.push_str(line);
+                    interaction.body.push('\n');
+                } else if response.trim().is_empty() {
+                    in_body = true;
+                } else if response.trim_start().starts_with("HTTP/") {
+                    interaction.status = response
+                        .split_whitespace()
+                        .nth(1)
+                        .and_then(|status| status.parse().ok())
+                        .ok_or(DavError::NoContent)?;
+                } else if let Some((name, value)) = response.split_once(':') {
+                    interaction
+                        .headers
+                        .push((name.trim().to_string(), value.trim().to_string()));
+                }
+            } else if line.starts_with("* ") || line.starts_with('>') {
+                if in_body {
+                    if let Some(done) = current.take() {
+                        interactions.push(done);
+                    }
+                    in_body = false;
+                }
+            } else if in_body {
+                if let Some(interaction) = current.as_mut() {
+                    interaction.body.push_str(line);
+                    interaction.body.push('\n');
+                }
+            }
+        }
+
+        if let Some(done) = current.take() {
+            interactions.push(done);
+        }
+
+        // The trailing info line may be glued to the end of the body
+        for interaction in interactions.iter_mut() {
+            if let Some(index) = interaction.body.rfind("* Connection #") {
+                interaction.body.truncate(index);
+            }
+        }
+
+        let user = interactions
+            .iter()
+            .find_map(|interaction| user_of(&interaction.path))
+            .unwrap_or_default();
+
+        Ok(Cassette {
+            server: server.to_string(),
+            user,
+            interactions,
+        })
+    }
+}
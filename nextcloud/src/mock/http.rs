@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+// Just enough HTTP/1.1 to talk to reqwest: content-length and chunked request bodies,
+// content-length responses and keep-alive
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    // Request target as sent, still percent encoded
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Connections are numbered in the order they were accepted, starting at 0
+    pub connection: u64,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Percent decoded path of the request target, without query string
+    pub fn path(&self) -> String {
+        let path = self.target.split('?').next().unwrap_or_default();
+        percent_encoding::percent_decode_str(path)
+            .decode_utf8_lossy()
+            .to_string()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn xml(status: u16, body: String) -> Self {
+        Self::new(status)
+            .with_header("Content-Type", "application/xml; charset=utf-8")
+            .with_body(body.into_bytes())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        416 => "Range Not Satisfiable",
+        423 => "Locked",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Read the next request of a connection, None once the client closed it
+pub async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or_else(|| invalid("no method"))?
+        .to_string();
+    let target = parts
+        .next()
+        .ok_or_else(|| invalid("no target"))?
+        .to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: Vec::new(),
+        connection: 0,
+    };
+
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line).await?;
+            let size = usize::from_str_radix(size_line.trim().split(';').next().unwrap_or(""), 16)
+                .map_err(|_| invalid("bad chunk size"))?;
+
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).await?;
+            chunk.truncate(size);
+            request.body.extend(chunk);
+
+            if size == 0 {
+                break;
+            }
+        }
+    } else if let Some(length) = request.header("Content-Length") {
+        let length: usize = length.parse().map_err(|_| invalid("bad length"))?;
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+        request.body = body;
+    }
+
+    Ok(Some(request))
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+    head: bool,
+) -> std::io::Result<()> {
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str(&format!("Content-Length: {}\r\n\r\n", response.body.len()));
+
+    writer.write_all(out.as_bytes()).await?;
+    if !head {
+        writer.write_all(&response.body).await?;
+    }
+    writer.flush().await
+}
+
+// Answers a request, None closes the connection without a response
+pub type Handler = Arc<dyn Fn(Request) -> Option<Response> + Send + Sync>;
+
+// Accept connections on listener until the returned task is aborted
+pub fn serve(listener: TcpListener, handler: Handler) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut connections = 0;
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            let connection = connections;
+            connections += 1;
+
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut reader = BufReader::new(read);
+
+                while let Ok(Some(mut request)) = read_request(&mut reader).await {
+                    request.connection = connection;
+                    let head = request.method == "HEAD";
+                    let response = match handler(request) {
+                        Some(response) => response,
+                        None => return,
+                    };
+                    if write_response(&mut write, &response, head).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    })
+}
@@ -0,0 +1,13 @@
+// Test support: an in-process server emulating the Nextcloud files DAV endpoint, so the
+// client and the FUSE layer can be tested without a real server
+mod cassette;
+mod http;
+mod push;
+mod replay;
+mod server;
+mod tree;
+
+pub use cassette::{Cassette, Interaction};
+pub use push::PushServer;
+pub use replay::ReplayServer;
+pub use server::{Failure, MockServer, RecordedRequest, DAV_PATH, PASSWORD, USER};
@@ -0,0 +1,115 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpListener, sync::broadcast, task::JoinHandle};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+use super::server::{PASSWORD, USER};
+
+// A stand-in for the websocket of the notify_push app: checks the credentials, then
+// passes on whatever the test sends
+pub struct PushServer {
+    url: String,
+    // None closes every connection
+    messages: broadcast::Sender<Option<String>>,
+    connections: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for PushServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl PushServer {
+    pub async fn start() -> PushServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind push server");
+        let url = format!("ws://{}/push/ws", listener.local_addr().unwrap());
+        let (messages, _) = broadcast::channel(16);
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let accept_messages = messages.clone();
+        let accept_connections = connections.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let messages = accept_messages.subscribe();
+                let connections = accept_connections.clone();
+                tokio::spawn(async move {
+                    let _ = connection(stream, messages, connections).await;
+                });
+            }
+        });
+
+        PushServer {
+            url,
+            messages,
+            connections,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Send a message to every client that is connected
+    pub fn send(&self, message: &str) {
+        let _ = self.messages.send(Some(message.to_string()));
+    }
+
+    pub fn disconnect(&self) {
+        let _ = self.messages.send(None);
+    }
+
+    // How often clients authenticated
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+async fn connection(
+    stream: tokio::net::TcpStream,
+    mut messages: broadcast::Receiver<Option<String>>,
+    connections: Arc<AtomicUsize>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut socket = accept_async(stream).await?;
+
+    let mut credentials = Vec::new();
+    while credentials.len() < 2 {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => credentials.push(text),
+            Some(Ok(_)) => (),
+            _ => return Ok(()),
+        }
+    }
+    if credentials != [USER, PASSWORD] {
+        socket
+            .send(Message::Text("err: Invalid credentials".to_string()))
+            .await?;
+        return socket.close(None).await;
+    }
+    socket
+        .send(Message::Text("authenticated".to_string()))
+        .await?;
+    connections.fetch_add(1, Ordering::SeqCst);
+
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Ok(Some(message)) => socket.send(Message::Text(message)).await?,
+                _ => return socket.close(None).await,
+            },
+            // "listen notify_file_id" and the like need no answer
+            message = socket.next() => match message {
+                Some(Ok(_)) => (),
+                _ => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use super::{
+    cassette::{Cassette, Interaction},
+    http::{serve, Request, Response},
+};
+use crate::{CircuitBreakerConfig, Nextcloud, RetryPolicy};
+
+// Serves the responses of a cassette to requests matching its recorded ones
+pub struct ReplayServer {
+    origin: String,
+    cassette: Cassette,
+    used: Arc<Mutex<Vec<bool>>>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+fn matches(interaction: &Interaction, request: &Request) -> bool {
+    let depth_matches = match (&interaction.depth, request.header("Depth")) {
+        (Some(recorded), Some(sent)) => recorded.eq_ignore_ascii_case(sent),
+        (Some(_), None) => false,
+        (None, _) => true,
+    };
+
+    interaction.method.eq_ignore_ascii_case(&request.method)
+        && interaction.path.trim_end_matches('/') == request.target.trim_end_matches('/')
+        && depth_matches
+}
+
+impl ReplayServer {
+    pub async fn start(cassette: Cassette) -> ReplayServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind replay server");
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let used = Arc::new(Mutex::new(vec![false; cassette.interactions.len()]));
+
+        let interactions = cassette.interactions.clone();
+        let handler_used = used.clone();
+        let handle = serve(
+            listener,
+            Arc::new(move |request| {
+                let mut used = handler_used.lock().unwrap();
+
+                // Prefer interactions that have not been replayed yet, so a cassette can
+                // record the same request twice with different answers
+                let index = (0..interactions.len())
+                    .filter(|index| matches(&interactions[*index], &request))
+                    .min_by_key(|index| used[*index]);
+
+                Some(match index {
+                    Some(index) => {
+                        used[index] = true;
+                        let interaction = &interactions[index];
+                        let mut response = Response::new(interaction.status)
+                            .with_body(interaction.body.clone().into_bytes());
+                        for (name, value) in &interaction.headers {
+                            // Framing headers are recomputed for the replayed body
+                            if !name.eq_ignore_ascii_case("Content-Length")
+                                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+                            {
+                                response = response.with_header(name, value);
+                            }
+                        }
+                        response
+                    }
+                    None => Response::new(404)
+                        .with_header("X-Replay-Error", "no recorded interaction matches"),
+                })
+            }),
+        );
+
+        ReplayServer {
+            origin,
+            cassette,
+            used,
+            handle,
+        }
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    // A client for the recorded user. Failures are part of what is replayed, so nothing
+    // is retried.
+    pub fn client(&self) -> Nextcloud {
+        Nextcloud::new(
+            self.origin.clone(),
+            super::DAV_PATH.to_string(),
+            self.cassette.user.clone(),
+            "replayed".to_string(),
+        )
+        .with_retry_policy(RetryPolicy::none(), CircuitBreakerConfig::default())
+    }
+
+    // Interactions no request has matched so far
+    pub fn unused(&self) -> Vec<Interaction> {
+        let used = self.used.lock().unwrap();
+        self.cassette
+            .interactions
+            .iter()
+            .zip(used.iter())
+            .filter(|(_, used)| !**used)
+            .map(|(interaction, _)| interaction.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DavItem;
+
+    fn sorted(mut items: Vec<DavItem>) -> Vec<DavItem> {
+        items.sort_by(|a, b| a.path().cmp(b.path()));
+        items
+    }
+
+    fn summary(items: &[DavItem]) -> Vec<(String, Option<u64>)> {
+        items
+            .iter()
+            .map(|item| match item {
+                DavItem::Folder(folder) => (format!("{}/", folder.path), None),
+                DavItem::File(file) => (file.path.clone(), Some(file.size)),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_curl_traces() {
+        let cassette =
+            Cassette::from_curl_trace("nextcloud-28", include_str!("../../../example2.html"))
+                .unwrap();
+        assert_eq!(cassette.user, "jthoward");
+        assert_eq!(cassette.interactions.len(), 1);
+        assert_eq!(cassette.interactions[0].status, 207);
+
+        let server = ReplayServer::start(cassette).await;
+        let items = sorted(server.client().ls("").await.unwrap());
+        assert!(server.unused().is_empty());
+
+        let summary = summary(&items);
+        assert_eq!(summary.len(), 18);
+        assert_eq!(
+            summary.iter().filter(|(_, size)| size.is_none()).count(),
+            15
+        );
+        assert!(summary.contains(&("Large Files/".to_string(), None)));
+        assert!(summary.contains(&("textcompare.vcf".to_string(), Some(1892))));
+        assert!(summary.contains(&("UK Legal Presentation.md".to_string(), Some(2420))));
+
+        let cassette =
+            Cassette::from_curl_trace("nextcloud-28", include_str!("../../../example.html"))
+                .unwrap();
+        assert_eq!(cassette.interactions[0].method, "PROPFIND");
+        assert!(cassette.interactions[0].body.contains("textcompare.vcf"));
+        assert!(cassette.interactions[0]
+            .body
+            .ends_with("</d:multistatus>\n"));
+    }
+
+    async fn replay(cassette: &str) -> (ReplayServer, Vec<DavItem>) {
+        let cassette = Cassette::parse(cassette).unwrap();
+        let server = ReplayServer::start(cassette).await;
+        let items = sorted(server.client().ls("Documents/").await.unwrap());
+        (server, items)
+    }
+
+    #[tokio::test]
+    async fn test_replay_synthetic_owncloud_10() {
+        let (server, items) = replay(include_str!(
+            "../../tests/cassettes/synthetic/owncloud-10.xml"
+        ))
+        .await;
+        assert!(server.unused().is_empty());
+        assert_eq!(
+            summary(&items),
+            vec![
+                ("Documents/Example.odt".to_string(), Some(36227)),
+                ("Documents/Projects/".to_string(), None),
+                ("Documents/ownCloud Manual.pdf".to_string(), Some(4850235)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_synthetic_nextcloud_25() {
+        let (server, items) = replay(include_str!(
+            "../../tests/cassettes/synthetic/nextcloud-25.xml"
+        ))
+        .await;
+        assert!(server.unused().is_empty());
+        assert_eq!(
+            summary(&items),
+            vec![
+                ("Documents/Nextcloud flyer.pdf".to_string(), Some(1083339)),
+                ("Documents/Readme.md".to_string(), Some(136)),
+                (
+                    "Documents/Welcome to Nextcloud Hub.docx".to_string(),
+                    Some(24295)
+                ),
+            ]
+        );
+        assert!(items[1].lock().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_synthetic_nextcloud_30() {
+        let (server, items) = replay(include_str!(
+            "../../tests/cassettes/synthetic/nextcloud-30.xml"
+        ))
+        .await;
+        assert!(server.unused().is_empty());
+        assert_eq!(
+            summary(&items),
+            vec![
+                ("Documents/Example.md".to_string(), Some(1095)),
+                ("Documents/Meeting notes/".to_string(), None),
+                ("Documents/Report #3 (draft).odt".to_string(), Some(30890)),
+            ]
+        );
+
+        let lock = items[2].lock().expect("report is locked");
+        assert_eq!(lock.owner.as_deref(), Some("bob"));
+        assert_eq!(lock.owner_display_name.as_deref(), Some("Bob"));
+        assert_eq!(lock.time, Some(1727794133));
+    }
+
+    #[tokio::test]
+    async fn test_replay_synthetic_sabredav() {
+        let (server, items) = replay(include_str!(
+            "../../tests/cassettes/synthetic/sabredav-4.xml"
+        ))
+        .await;
+        assert!(server.unused().is_empty());
+        assert_eq!(
+            summary(&items),
+            vec![
+                ("Documents/archive/".to_string(), None),
+                ("Documents/notes.txt".to_string(), Some(12)),
+            ]
+        );
+    }
+}
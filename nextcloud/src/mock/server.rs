@@ -0,0 +1,1298 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{events::Event, name::ResolveResult, NsReader};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use super::{
+    http::{serve, Request, Response},
+    tree::{normalize, parent_of, Node, Tree, TreeError},
+};
+use crate::client::http_date::format_http_date;
+use crate::{CircuitBreakerConfig, Nextcloud, RetryPolicy};
+
+pub const USER: &str = "user";
+pub const PASSWORD: &str = "password";
+pub const DAV_PATH: &str = "remote.php/dav";
+
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// A canned failure returned instead of handling matching requests
+#[derive(Debug, Clone)]
+pub struct Failure {
+    // Only fail requests with this method
+    pub method: Option<String>,
+    // Only fail requests below this path, relative to the files root
+    pub path: Option<String>,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    // Close the connection without answering instead of sending status
+    pub disconnect: bool,
+    // How many requests to fail
+    pub times: usize,
+}
+
+impl Failure {
+    pub fn status(status: u16) -> Self {
+        Self {
+            method: None,
+            path: None,
+            status,
+            headers: Vec::new(),
+            disconnect: false,
+            times: 1,
+        }
+    }
+
+    pub fn disconnect() -> Self {
+        Self {
+            disconnect: true,
+            ..Self::status(0)
+        }
+    }
+
+    pub fn on(mut self, method: &str, path: &str) -> Self {
+        self.method = Some(method.to_string());
+        self.path = Some(normalize(path));
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = times;
+        self
+    }
+
+    fn matches(&self, method: &str, files_path: Option<&str>) -> bool {
+        let method_matches = self
+            .method
+            .as_ref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method));
+        let path_matches = match (&self.path, files_path) {
+            (None, _) => true,
+            (Some(prefix), Some(path)) => {
+                prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+            }
+            (Some(_), None) => false,
+        };
+
+        self.times > 0 && method_matches && path_matches
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    // Percent decoded request path
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    // Number of the connection it came in on
+    pub connection: u64,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Upload {
+    chunks: BTreeMap<String, Vec<u8>>,
+}
+
+// Something deleted, kept in the trash bin until it is restored or purged
+#[derive(Debug)]
+struct Trashed {
+    // "<name>.d<deletion time>"
+    name: String,
+    original_location: String,
+    deleted: u64,
+    // The item itself at "" and what was inside it, relative to it
+    nodes: Vec<(String, Node)>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    tree: Tree,
+    // Total bytes the user may store, None for unlimited
+    quota: Option<u64>,
+    trash: Vec<Trashed>,
+    // Replaced contents of files by file id, named after when they were last modified
+    versions: HashMap<u64, Vec<(u64, Node)>>,
+    // Websocket announced as the notify_push endpoint in the capabilities
+    push_endpoint: Option<String>,
+    failures: Vec<Failure>,
+    requests: Vec<RecordedRequest>,
+    // Chunked uploads in progress, keyed by transfer id
+    uploads: HashMap<String, Upload>,
+    // Lock tokens keyed by path
+    locks: HashMap<String, String>,
+    next_lock: u64,
+}
+
+// An in-process HTTP server emulating the parts of Nextcloud the client talks to
+pub struct MockServer {
+    origin: String,
+    state: Arc<Mutex<MockState>>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock server");
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let handler_state = state.clone();
+        let handler_origin = origin.clone();
+        let handle = serve(
+            listener,
+            Arc::new(move |request| respond(&handler_state, &handler_origin, request)),
+        );
+
+        MockServer {
+            origin,
+            state,
+            handle,
+        }
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    // A client for this server, retrying quickly so injected failures do not slow tests down
+    pub fn client(&self) -> Nextcloud {
+        Nextcloud::new(
+            self.origin.clone(),
+            DAV_PATH.to_string(),
+            USER.to_string(),
+            PASSWORD.to_string(),
+        )
+        .with_retry_policy(
+            RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+                ..RetryPolicy::default()
+            },
+            CircuitBreakerConfig::default(),
+        )
+    }
+
+    // Create a folder and any missing parents
+    pub fn add_folder(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        let mut current = String::new();
+
+        for segment in normalize(path).split('/').filter(|s| !s.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(segment);
+            if state.tree.get(&current).is_none() {
+                state.tree.mkdir(&current).unwrap();
+            }
+        }
+    }
+
+    // Create or replace a file, creating missing parents
+    pub fn add_file(&self, path: &str, content: &[u8]) {
+        let path = normalize(path);
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.add_folder(parent);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.tree.put(&path, content.to_vec(), None).unwrap();
+    }
+
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .tree
+            .get(path)
+            .and_then(|node| node.content().cloned())
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.state.lock().unwrap().tree.get(path).is_some()
+    }
+
+    pub fn etag(&self, path: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.tree.get(path).map(|node| node.etag.clone())
+    }
+
+    pub fn file_id(&self, path: &str) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.tree.get(path).map(|node| node.file_id)
+    }
+
+    pub fn last_modified(&self, path: &str) -> Option<SystemTime> {
+        let state = self.state.lock().unwrap();
+        state.tree.get(path).map(|node| node.last_modified)
+    }
+
+    // Set the oc:permissions letters of an item
+    pub fn set_permissions(&self, path: &str, permissions: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(node) = state.tree.get_mut(path) {
+            node.permissions = permissions.to_string();
+        }
+    }
+
+    pub fn set_quota(&self, quota: Option<u64>) {
+        self.state.lock().unwrap().quota = quota;
+    }
+
+    pub fn set_push_endpoint(&self, endpoint: Option<&str>) {
+        self.state.lock().unwrap().push_endpoint = endpoint.map(str::to_string);
+    }
+
+    // Names of the items in the trash bin, "<name>.d<deletion time>"
+    pub fn trash(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .trash
+            .iter()
+            .map(|trashed| trashed.name.clone())
+            .collect()
+    }
+
+    pub fn fail(&self, failure: Failure) {
+        self.state.lock().unwrap().failures.push(failure);
+    }
+
+    pub fn clear_failures(&self) {
+        self.state.lock().unwrap().failures.clear();
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+fn files_prefix() -> String {
+    format!("/{}/files/{}", DAV_PATH, USER)
+}
+
+fn trashbin_prefix() -> String {
+    format!("/{}/trashbin/{}", DAV_PATH, USER)
+}
+
+fn versions_prefix() -> String {
+    format!("/{}/versions/{}", DAV_PATH, USER)
+}
+
+fn uploads_prefix() -> String {
+    format!("/{}/uploads/{}", DAV_PATH, USER)
+}
+
+// Path relative to the prefix, if the request path is below it
+fn strip_prefix(path: &str, prefix: &str) -> Option<String> {
+    if path == prefix {
+        Some("".to_string())
+    } else {
+        path.strip_prefix(&format!("{}/", prefix)).map(normalize)
+    }
+}
+
+fn respond(state: &Mutex<MockState>, origin: &str, request: Request) -> Option<Response> {
+    let mut state = state.lock().unwrap();
+    let path = request.path();
+    let files_path = strip_prefix(&path, &files_prefix());
+
+    state.requests.push(RecordedRequest {
+        method: request.method.clone(),
+        path: path.clone(),
+        headers: request.headers.clone(),
+        connection: request.connection,
+    });
+
+    if let Some(failure) = state
+        .failures
+        .iter_mut()
+        .find(|failure| failure.matches(&request.method, files_path.as_deref()))
+    {
+        failure.times -= 1;
+        if failure.disconnect {
+            return None;
+        }
+
+        let mut response = Response::new(failure.status);
+        for (name, value) in &failure.headers {
+            response = response.with_header(name, value);
+        }
+        return Some(response);
+    }
+
+    if request.header("Authorization") != Some(&basic_auth(USER, PASSWORD)) {
+        return Some(
+            Response::new(401).with_header("WWW-Authenticate", "Basic realm=\"Nextcloud\""),
+        );
+    }
+
+    if let Some(files_path) = files_path {
+        return Some(handle_files(&mut state, origin, &request, &files_path));
+    }
+    if let Some(upload_path) = strip_prefix(&path, &uploads_prefix()) {
+        return Some(handle_uploads(&mut state, origin, &request, &upload_path));
+    }
+    if let Some(trashbin_path) = strip_prefix(&path, &trashbin_prefix()) {
+        return Some(handle_trashbin(
+            &mut state,
+            origin,
+            &request,
+            &trashbin_path,
+        ));
+    }
+    if let Some(versions_path) = strip_prefix(&path, &versions_prefix()) {
+        return Some(handle_versions(
+            &mut state,
+            origin,
+            &request,
+            &versions_path,
+        ));
+    }
+    if path == "/ocs/v2.php/cloud/user" || path == "/ocs/v1.php/cloud/user" {
+        return Some(user_info(&state));
+    }
+    if path == "/ocs/v2.php/cloud/capabilities" {
+        return Some(capabilities(&state));
+    }
+
+    Some(Response::new(404))
+}
+
+fn handle_files(state: &mut MockState, origin: &str, request: &Request, path: &str) -> Response {
+    match request.method.as_str() {
+        "PROPFIND" => propfind(state, request, path),
+        "PROPPATCH" => {
+            if let Some(response) = check_lock(state, request, path) {
+                return response;
+            }
+            proppatch(state, request, path)
+        }
+        "GET" | "HEAD" => get(state, request, path),
+        "PUT" => {
+            if let Some(response) = check_lock(state, request, path) {
+                return response;
+            }
+            let last_modified = mtime_header(request);
+            put(state, request, path, request.body.clone(), last_modified)
+        }
+        "MKCOL" => match state.tree.mkdir(path) {
+            Ok(_) => Response::new(201),
+            Err(TreeError::AlreadyExists) => Response::new(405),
+            Err(_) => Response::new(409),
+        },
+        "DELETE" => {
+            if let Some(response) = check_lock(state, request, path) {
+                return response;
+            }
+            match move_to_trash(state, path) {
+                Ok(()) => {
+                    state.locks.remove(path);
+                    Response::new(204)
+                }
+                Err(TreeError::NotFound) => Response::new(404),
+                Err(_) => Response::new(403),
+            }
+        }
+        "MOVE" | "COPY" => transfer(state, origin, request, path),
+        "LOCK" => lock(state, request, path),
+        "UNLOCK" => {
+            let token = request
+                .header("Lock-Token")
+                .map(|token| token.trim_matches(|c| c == '<' || c == '>').to_string());
+            match state.locks.get(path) {
+                Some(held) if Some(held) == token.as_ref() => {
+                    state.locks.remove(path);
+                    Response::new(204)
+                }
+                _ => Response::new(409),
+            }
+        }
+        _ => Response::new(405),
+    }
+}
+
+fn basic_auth(user: &str, password: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let input = format!("{}:{}", user, password).into_bytes();
+    let mut encoded = String::new();
+
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    format!("Basic {}", encoded)
+}
+
+fn mtime_header(request: &Request) -> Option<SystemTime> {
+    request
+        .header("X-OC-Mtime")
+        .and_then(|mtime| mtime.trim().parse::<u64>().ok())
+        .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime))
+}
+
+fn quoted(etag: &str) -> String {
+    format!("\"{}\"", etag)
+}
+
+// 423 unless the request carries the token of a lock on path
+fn check_lock(state: &MockState, request: &Request, path: &str) -> Option<Response> {
+    match state.locks.get(path) {
+        Some(token) if !request.header("If").unwrap_or("").contains(token.as_str()) => {
+            Some(Response::new(423))
+        }
+        _ => None,
+    }
+}
+
+// If-Match and If-None-Match against the current etag of path
+fn check_preconditions(request: &Request, node: Option<&Node>) -> Option<Response> {
+    if let Some(if_match) = request.header("If-Match") {
+        let matches = match node {
+            Some(node) => {
+                if_match.trim() == "*"
+                    || if_match
+                        .split(',')
+                        .any(|etag| etag.trim() == quoted(&node.etag))
+            }
+            None => false,
+        };
+        if !matches {
+            return Some(Response::new(412));
+        }
+    }
+
+    if request.header("If-None-Match").map(|v| v.trim()) == Some("*") && node.is_some() {
+        return Some(Response::new(412));
+    }
+
+    None
+}
+
+fn propfind(state: &MockState, request: &Request, path: &str) -> Response {
+    if state.tree.get(path).is_none() {
+        return Response::new(404);
+    }
+
+    let entries = match request.header("Depth").unwrap_or("infinity") {
+        "0" => vec![(path.to_string(), state.tree.get(path).unwrap())],
+        "1" => {
+            let mut entries = vec![(path.to_string(), state.tree.get(path).unwrap())];
+            entries.extend(state.tree.children(path));
+            entries
+        }
+        _ => state.tree.descendants(path),
+    };
+
+    let responses: String = entries
+        .iter()
+        .map(|(entry_path, node)| propfind_response(state, entry_path, node))
+        .collect();
+
+    multistatus(responses)
+}
+
+// Properties set and removed by a PROPPATCH body, by namespace URI and name. Only props
+// directly inside d:set/d:prop and d:remove/d:prop count.
+fn parse_propertyupdate(body: &str) -> Option<Vec<(String, String, Option<String>)>> {
+    let mut reader = NsReader::from_str(body);
+    reader.trim_text(true);
+    let mut stack: Vec<(String, String)> = Vec::new();
+    let mut updates = Vec::new();
+
+    loop {
+        let (namespace, event) = reader.read_resolved_event().ok()?;
+        let namespace = match namespace {
+            ResolveResult::Bound(namespace) => {
+                String::from_utf8_lossy(namespace.into_inner()).to_string()
+            }
+            _ => String::new(),
+        };
+
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let name = String::from_utf8_lossy(e.local_name().into_inner()).to_string();
+                let in_prop =
+                    stack.len() == 3 && stack[2] == ("DAV:".to_string(), "prop".to_string());
+                if in_prop {
+                    let set = stack[1].1 == "set";
+                    updates.push((namespace.clone(), name.clone(), set.then(String::new)));
+                }
+                if matches!(event, Event::Start(_)) {
+                    stack.push((namespace, name));
+                }
+            }
+            Event::Text(e) if stack.len() == 4 => {
+                if let Some((_, _, Some(value))) = updates.last_mut() {
+                    *value = e.unescape().ok()?.to_string();
+                }
+            }
+            Event::End(_) => {
+                stack.pop();
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Some(updates)
+}
+
+// Dead properties are stored as they come, live DAV: properties cannot be changed. Like
+// Sabre, nothing is applied if one property fails.
+fn proppatch(state: &mut MockState, request: &Request, path: &str) -> Response {
+    let body = String::from_utf8_lossy(&request.body).to_string();
+    let updates = match parse_propertyupdate(&body) {
+        Some(updates) => updates,
+        None => return Response::new(400),
+    };
+    let node = match state.tree.get_mut(path) {
+        Some(node) => node,
+        None => return Response::new(404),
+    };
+
+    let failed = updates.iter().any(|(namespace, _, _)| namespace == "DAV:");
+    let mut props = String::new();
+    for (namespace, name, value) in updates {
+        props.push_str(&format!("<x:{} xmlns:x=\"{}\"/>", name, namespace));
+        if failed {
+            continue;
+        }
+        match value {
+            Some(value) => node.properties.insert((namespace, name), value),
+            None => node.properties.remove(&(namespace, name)),
+        };
+    }
+
+    let status = if failed { "403 Forbidden" } else { "200 OK" };
+    Response::xml(
+        207,
+        format!(
+            r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:"><d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 {}</d:status></d:propstat></d:response></d:multistatus>"#,
+            href(&files_prefix(), path, node.is_folder()),
+            props,
+            status
+        ),
+    )
+}
+
+fn href(prefix: &str, path: &str, is_folder: bool) -> String {
+    let encoded: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect();
+    let mut href = format!("{}/{}", prefix, encoded.join("/"));
+
+    if is_folder && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn propfind_response(state: &MockState, path: &str, node: &Node) -> String {
+    let mut props = format!(
+        "<d:getlastmodified>{}</d:getlastmodified><d:getetag>&quot;{}&quot;</d:getetag><oc:fileid>{}</oc:fileid><oc:permissions>{}</oc:permissions><oc:size>{}</oc:size>",
+        format_http_date(node.last_modified),
+        node.etag,
+        node.file_id,
+        node.permissions,
+        state.tree.size(path),
+    );
+
+    match node.content() {
+        Some(content) => props.push_str(&format!(
+            "<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength><d:getcontenttype>application/octet-stream</d:getcontenttype>",
+            content.len()
+        )),
+        None => {
+            let used = state.tree.size(path);
+            let available = match state.quota {
+                Some(quota) => quota.saturating_sub(state.tree.size("")) as i64,
+                None => -3,
+            };
+            props.push_str(&format!(
+                "<d:resourcetype><d:collection/></d:resourcetype><d:quota-used-bytes>{}</d:quota-used-bytes><d:quota-available-bytes>{}</d:quota-available-bytes>",
+                used, available
+            ));
+        }
+    }
+
+    match state.locks.get(path) {
+        Some(_) => props.push_str(&format!(
+            "<nc:lock>1</nc:lock><nc:lock-owner>{}</nc:lock-owner><nc:lock-owner-displayname>{}</nc:lock-owner-displayname><nc:lock-owner-type>0</nc:lock-owner-type><nc:lock-time>{}</nc:lock-time><nc:lock-timeout>1800</nc:lock-timeout>",
+            USER,
+            USER,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        )),
+        None => props.push_str("<nc:lock/>"),
+    }
+
+    props.push_str(&format!(
+        "<oc:owner-display-name>{}</oc:owner-display-name>",
+        USER
+    ));
+    for ((namespace, name), value) in &node.properties {
+        props.push_str(&format!(
+            "<x:{} xmlns:x=\"{}\">{}</x:{}>",
+            name,
+            namespace,
+            quick_xml::escape::escape(value),
+            name
+        ));
+    }
+
+    format!(
+        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        href(&files_prefix(), path, node.is_folder()),
+        props
+    )
+}
+
+// Parse a "bytes=start-end" range against a content length
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    // Multiple ranges are not supported, serve the first one
+    let range = range.split(',').next()?.trim();
+    let (start, end) = range.split_once('-')?;
+
+    let (start, end) = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        (length.saturating_sub(suffix), length.checked_sub(1)?)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => length.checked_sub(1)?,
+            end => end.parse::<u64>().ok()?.min(length.checked_sub(1)?),
+        };
+        (start, end)
+    };
+
+    if start > end || start >= length {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+fn get(state: &MockState, request: &Request, path: &str) -> Response {
+    match state.tree.get(path) {
+        Some(node) => get_node(request, node),
+        None => Response::new(404),
+    }
+}
+
+fn get_node(request: &Request, node: &Node) -> Response {
+    let content = match node.content() {
+        Some(content) => content,
+        None => return Response::new(405),
+    };
+
+    if let Some(response) = check_preconditions(request, Some(node)) {
+        return response;
+    }
+
+    let response = Response::new(200)
+        .with_header("ETag", &quoted(&node.etag))
+        .with_header("OC-ETag", &quoted(&node.etag))
+        .with_header("Last-Modified", &format_http_date(node.last_modified))
+        .with_header("Accept-Ranges", "bytes");
+
+    match request.header("Range") {
+        Some(range) => match parse_range(range, content.len() as u64) {
+            Some((start, end)) => {
+                let mut response = response
+                    .with_header(
+                        "Content-Range",
+                        &format!("bytes {}-{}/{}", start, end, content.len()),
+                    )
+                    .with_body(content[start as usize..=end as usize].to_vec());
+                response.status = 206;
+                response
+            }
+            None => Response::new(416)
+                .with_header("Content-Range", &format!("bytes */{}", content.len())),
+        },
+        None => response.with_body(content.clone()),
+    }
+}
+
+fn put(
+    state: &mut MockState,
+    request: &Request,
+    path: &str,
+    content: Vec<u8>,
+    last_modified: Option<SystemTime>,
+) -> Response {
+    if let Some(response) = check_preconditions(request, state.tree.get(path)) {
+        return response;
+    }
+
+    if let Some(quota) = state.quota {
+        let replaced = state
+            .tree
+            .get(path)
+            .and_then(|node| node.content())
+            .map_or(0, |content| content.len() as u64);
+        if state.tree.size("") - replaced + content.len() as u64 > quota {
+            return Response::new(507);
+        }
+    }
+
+    keep_version(state, path);
+    match state.tree.put(path, content, last_modified) {
+        Ok(created) => {
+            let node = state.tree.get(path).unwrap();
+            let mut response = Response::new(if created { 201 } else { 204 })
+                .with_header("ETag", &quoted(&node.etag))
+                .with_header("OC-ETag", &quoted(&node.etag))
+                .with_header("OC-FileId", &format!("{:08}ocmock", node.file_id));
+            if last_modified.is_some() {
+                response = response.with_header("X-OC-MTime", "accepted");
+            }
+            response
+        }
+        Err(TreeError::AlreadyExists) => Response::new(405),
+        Err(_) => Response::new(409),
+    }
+}
+
+// Files path of a Destination header, which holds an absolute URL
+fn destination(origin: &str, request: &Request) -> Option<String> {
+    strip_prefix(&destination_path(origin, request)?, &files_prefix())
+}
+
+// Decoded path of the Destination header
+fn destination_path(origin: &str, request: &Request) -> Option<String> {
+    let destination = request.header("Destination")?;
+    let url = url::Url::parse(origin).ok()?.join(destination).ok()?;
+
+    Some(
+        percent_encoding::percent_decode_str(url.path())
+            .decode_utf8_lossy()
+            .to_string(),
+    )
+}
+
+fn transfer(state: &mut MockState, origin: &str, request: &Request, path: &str) -> Response {
+    let keep_source = request.method == "COPY";
+    let destination = match destination(origin, request) {
+        Some(destination) => destination,
+        None => return Response::new(400),
+    };
+
+    if state.tree.get(path).is_none() {
+        return Response::new(404);
+    }
+    if !keep_source {
+        if let Some(response) = check_lock(state, request, path) {
+            return response;
+        }
+    }
+    if let Some(response) = check_lock(state, request, &destination) {
+        return response;
+    }
+    if let Some(response) = check_preconditions(request, state.tree.get(path)) {
+        return response;
+    }
+
+    let overwrite = !request
+        .header("Overwrite")
+        .is_some_and(|overwrite| overwrite.trim().eq_ignore_ascii_case("F"));
+    if !overwrite && state.tree.get(&destination).is_some() {
+        return Response::new(412);
+    }
+
+    match state.tree.transfer(path, &destination, keep_source) {
+        Ok(created) => {
+            if !keep_source {
+                if let Some(token) = state.locks.remove(path) {
+                    state.locks.insert(destination, token);
+                }
+            }
+            Response::new(if created { 201 } else { 204 })
+        }
+        Err(TreeError::NotFound) => Response::new(404),
+        Err(_) => Response::new(409),
+    }
+}
+
+fn lock(state: &mut MockState, request: &Request, path: &str) -> Response {
+    if let Some(token) = state.locks.get(path) {
+        // Refreshing a lock we hold
+        if request.header("If").unwrap_or("").contains(token.as_str()) {
+            let body = lock_discovery(token, request);
+            return Response::xml(200, body).with_header("Lock-Token", &format!("<{}>", token));
+        }
+        return Response::new(423);
+    }
+
+    let created = match state.tree.get(path) {
+        Some(_) => false,
+        // Locking an unmapped URL creates an empty file
+        None => match state.tree.put(path, Vec::new(), None) {
+            Ok(_) => true,
+            Err(_) => return Response::new(409),
+        },
+    };
+
+    state.next_lock += 1;
+    let token = format!("opaquelocktoken:mock-{}", state.next_lock);
+    state.locks.insert(path.to_string(), token.clone());
+
+    let body = lock_discovery(&token, request);
+    Response::xml(if created { 201 } else { 200 }, body)
+        .with_header("Lock-Token", &format!("<{}>", token))
+}
+
+fn lock_discovery(token: &str, request: &Request) -> String {
+    let timeout = request.header("Timeout").unwrap_or("Second-1800");
+    let owner = String::from_utf8_lossy(&request.body)
+        .split("<d:owner>")
+        .nth(1)
+        .and_then(|rest| rest.split("</d:owner>").next())
+        .unwrap_or("")
+        .to_string();
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<d:prop xmlns:d="DAV:"><d:lockdiscovery><d:activelock><d:lockscope><d:exclusive/></d:lockscope><d:locktype><d:write/></d:locktype><d:depth>0</d:depth><d:owner>{}</d:owner><d:timeout>{}</d:timeout><d:locktoken><d:href>{}</d:href></d:locktoken></d:activelock></d:lockdiscovery></d:prop>"#,
+        owner, timeout, token
+    )
+}
+
+// Nextcloud chunked upload v2: MKCOL uploads/<user>/<id>, PUT uploads/<user>/<id>/<n> for every
+// chunk, then MOVE uploads/<user>/<id>/.file to the destination
+fn handle_uploads(state: &mut MockState, origin: &str, request: &Request, path: &str) -> Response {
+    let (transfer_id, chunk) = match path.split_once('/') {
+        Some((transfer_id, chunk)) => (transfer_id.to_string(), Some(chunk.to_string())),
+        None => (path.to_string(), None),
+    };
+
+    match (request.method.as_str(), chunk) {
+        ("MKCOL", None) => {
+            if state.uploads.contains_key(&transfer_id) {
+                return Response::new(405);
+            }
+            state.uploads.insert(transfer_id, Upload::default());
+            Response::new(201)
+        }
+        ("PUT", Some(chunk)) => match state.uploads.get_mut(&transfer_id) {
+            Some(upload) => {
+                upload.chunks.insert(chunk, request.body.clone());
+                Response::new(201)
+            }
+            None => Response::new(404),
+        },
+        ("MOVE", Some(chunk)) if chunk == ".file" => {
+            let destination = match destination(origin, request) {
+                Some(destination) => destination,
+                None => return Response::new(400),
+            };
+            let upload = match state.uploads.remove(&transfer_id) {
+                Some(upload) => upload,
+                None => return Response::new(404),
+            };
+            if let Some(response) = check_lock(state, request, &destination) {
+                return response;
+            }
+
+            // Chunk names are numbers, assemble them in numeric order
+            let mut chunks: Vec<(String, Vec<u8>)> = upload.chunks.into_iter().collect();
+            chunks.sort_by_key(|(name, _)| name.parse::<u64>().unwrap_or(u64::MAX));
+            let content: Vec<u8> = chunks.into_iter().flat_map(|(_, chunk)| chunk).collect();
+
+            if let Some(total) = request.header("OC-Total-Length") {
+                if total.trim().parse::<usize>().ok() != Some(content.len()) {
+                    return Response::new(400);
+                }
+            }
+
+            put(state, request, &destination, content, mtime_header(request))
+        }
+        ("DELETE", None) => match state.uploads.remove(&transfer_id) {
+            Some(_) => Response::new(204),
+            None => Response::new(404),
+        },
+        _ => Response::new(405),
+    }
+}
+
+fn user_info(state: &MockState) -> Response {
+    let used = state.tree.size("");
+    let (free, total, relative, quota) = match state.quota {
+        Some(quota) => (
+            quota.saturating_sub(used) as i64,
+            quota as i64,
+            if quota == 0 {
+                0.0
+            } else {
+                used as f64 * 100.0 / quota as f64
+            },
+            quota as i64,
+        ),
+        None => (-3, -3, 0.0, -3),
+    };
+
+    Response::xml(
+        200,
+        format!(
+            r#"<?xml version="1.0"?>
+<ocs><meta><status>ok</status><statuscode>200</statuscode><message>OK</message></meta><data><enabled>1</enabled><id>{}</id><quota><free>{}</free><used>{}</used><total>{}</total><relative>{:.2}</relative><quota>{}</quota></quota><email>{}@example.com</email><displayname>Mock User</displayname></data></ocs>"#,
+            USER, free, used, total, relative, quota, USER
+        ),
+    )
+}
+
+// Deleted items go to the trash bin like in Nextcloud, named after the deletion time
+fn move_to_trash(state: &mut MockState, path: &str) -> Result<(), TreeError> {
+    let nodes = state.tree.take(path)?;
+    let deleted = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let base = path.rsplit('/').next().unwrap_or_default();
+
+    // Nextcloud only deletes a name once a second
+    let mut time = deleted;
+    while state
+        .trash
+        .iter()
+        .any(|trashed| trashed.name == format!("{}.d{}", base, time))
+    {
+        time += 1;
+    }
+    state.trash.push(Trashed {
+        name: format!("{}.d{}", base, time),
+        original_location: path.to_string(),
+        deleted,
+        nodes,
+    });
+
+    Ok(())
+}
+
+// trash/ lists the deleted items, trash/<name>/... what is inside them. Items are restored
+// by moving them to restore/<name>, and purged along with anything inside them by DELETE.
+fn handle_trashbin(state: &mut MockState, origin: &str, request: &Request, path: &str) -> Response {
+    let rest = match path.split_once('/') {
+        Some(("trash", rest)) => rest,
+        None if path == "trash" => "",
+        _ => return Response::new(404),
+    };
+    if rest.is_empty() {
+        return match request.method.as_str() {
+            "PROPFIND" => trash_listing(state),
+            _ => Response::new(405),
+        };
+    }
+
+    let (name, inner) = rest.split_once('/').unwrap_or((rest, ""));
+    let index = match state.trash.iter().position(|trashed| trashed.name == name) {
+        Some(index) => index,
+        None => return Response::new(404),
+    };
+    let trashed = &state.trash[index];
+    let node = match trashed.nodes.iter().find(|(key, _)| key == inner) {
+        Some((_, node)) => node,
+        None => return Response::new(404),
+    };
+
+    match request.method.as_str() {
+        "PROPFIND" => {
+            let mut responses = trash_response(trashed, inner, node);
+            if request.header("Depth") != Some("0") {
+                for (key, child) in &trashed.nodes {
+                    if !key.is_empty() && parent_of(key) == inner {
+                        responses.push_str(&trash_response(trashed, key, child));
+                    }
+                }
+            }
+            multistatus(responses)
+        }
+        "GET" | "HEAD" => get_node(request, node),
+        "DELETE" if inner.is_empty() => {
+            state.trash.remove(index);
+            Response::new(204)
+        }
+        "DELETE" => {
+            let below = format!("{}/", inner);
+            state.trash[index]
+                .nodes
+                .retain(|(key, _)| key != inner && !key.starts_with(&below));
+            Response::new(204)
+        }
+        // Only the items deleted themselves can be restored
+        _ if !inner.is_empty() => Response::new(403),
+        "MOVE" => {
+            let destination = destination_path(origin, request);
+            let restore = format!("{}/restore/{}", trashbin_prefix(), name);
+            if destination.as_deref() != Some(restore.as_str()) {
+                return Response::new(403);
+            }
+            // Nextcloud would pick another name, the client never restores over an item
+            let trashed = state.trash.remove(index);
+            let location = trashed.original_location.clone();
+            match state.tree.insert_tree(&location, trashed.nodes.clone()) {
+                Ok(()) => Response::new(201),
+                Err(error) => {
+                    state.trash.insert(index, trashed);
+                    match error {
+                        TreeError::AlreadyExists => Response::new(412),
+                        _ => Response::new(409),
+                    }
+                }
+            }
+        }
+        _ => Response::new(405),
+    }
+}
+
+// Every upload over a file keeps what it replaced
+fn keep_version(state: &mut MockState, path: &str) {
+    let node = match state.tree.get(path) {
+        Some(node) if !node.is_folder() => node.clone(),
+        _ => return,
+    };
+    let name = node
+        .last_modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let versions = state.versions.entry(node.file_id).or_default();
+    versions.retain(|(existing, _)| *existing != name);
+    versions.push((name, node));
+}
+
+// versions/<file id> lists the older versions of a file, versions/<file id>/<name> is one
+// of them. Moving one to restore/target makes it the current content.
+fn handle_versions(state: &mut MockState, origin: &str, request: &Request, path: &str) -> Response {
+    let mut segments = path.split('/');
+    let file_id: u64 = match (segments.next(), segments.next().map(str::parse)) {
+        (Some("versions"), Some(Ok(file_id))) => file_id,
+        _ => return Response::new(404),
+    };
+    let file_path = match state
+        .tree
+        .descendants("")
+        .into_iter()
+        .find(|(_, node)| node.file_id == file_id && !node.is_folder())
+    {
+        Some((file_path, _)) => file_path,
+        None => return Response::new(404),
+    };
+    let versions = state.versions.get(&file_id).cloned().unwrap_or_default();
+
+    let name = match segments.next() {
+        Some(name) => name,
+        None => {
+            return match request.method.as_str() {
+                "PROPFIND" => {
+                    let mut responses = format!(
+                        "<d:response><d:href>{}/versions/{}/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                        versions_prefix(),
+                        file_id
+                    );
+                    if request.header("Depth") != Some("0") {
+                        for (name, node) in &versions {
+                            responses.push_str(&version_response(file_id, *name, node));
+                        }
+                    }
+                    multistatus(responses)
+                }
+                _ => Response::new(405),
+            };
+        }
+    };
+    let (name, node) = match versions
+        .into_iter()
+        .find(|(version, _)| version.to_string() == name)
+    {
+        Some(version) => version,
+        None => return Response::new(404),
+    };
+
+    match request.method.as_str() {
+        "PROPFIND" => multistatus(version_response(file_id, name, &node)),
+        "GET" | "HEAD" => get_node(request, &node),
+        "MOVE" => {
+            let restore = format!("{}/restore/target", versions_prefix());
+            if destination_path(origin, request).as_deref() != Some(restore.as_str()) {
+                return Response::new(403);
+            }
+            let content = node.content().cloned().unwrap_or_default();
+            keep_version(state, &file_path);
+            if let Some(versions) = state.versions.get_mut(&file_id) {
+                versions.retain(|(version, _)| *version != name);
+            }
+            match state
+                .tree
+                .put(&file_path, content, Some(node.last_modified))
+            {
+                Ok(_) => Response::new(204),
+                Err(_) => Response::new(409),
+            }
+        }
+        _ => Response::new(405),
+    }
+}
+
+fn version_response(file_id: u64, name: u64, node: &Node) -> String {
+    format!(
+        "<d:response><d:href>{}/versions/{}/{}</d:href><d:propstat><d:prop><d:getlastmodified>{}</d:getlastmodified><d:getetag>&quot;{}&quot;</d:getetag><d:resourcetype/><d:getcontentlength>{}</d:getcontentlength></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        versions_prefix(),
+        file_id,
+        name,
+        format_http_date(node.last_modified),
+        node.etag,
+        node.content().map_or(0, Vec::len)
+    )
+}
+
+fn trash_listing(state: &MockState) -> Response {
+    let mut responses = format!(
+        "<d:response><d:href>{}/trash/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        trashbin_prefix()
+    );
+    for trashed in &state.trash {
+        if let Some((_, node)) = trashed.nodes.iter().find(|(key, _)| key.is_empty()) {
+            responses.push_str(&trash_response(trashed, "", node));
+        }
+    }
+    multistatus(responses)
+}
+
+fn trash_response(trashed: &Trashed, inner: &str, node: &Node) -> String {
+    let mut props = format!(
+        "<d:getlastmodified>{}</d:getlastmodified><d:getetag>&quot;{}&quot;</d:getetag><oc:fileid>{}</oc:fileid>",
+        format_http_date(node.last_modified),
+        node.etag,
+        node.file_id,
+    );
+    match node.content() {
+        Some(content) => props.push_str(&format!(
+            "<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>",
+            content.len()
+        )),
+        None => props.push_str("<d:resourcetype><d:collection/></d:resourcetype>"),
+    }
+    if inner.is_empty() {
+        props.push_str(&format!(
+            "<nc:trashbin-filename>{}</nc:trashbin-filename><nc:trashbin-original-location>{}</nc:trashbin-original-location><nc:trashbin-deletion-time>{}</nc:trashbin-deletion-time>",
+            quick_xml::escape::escape(trashed.original_location.rsplit('/').next().unwrap_or_default()),
+            quick_xml::escape::escape(&trashed.original_location),
+            trashed.deleted
+        ));
+    }
+
+    format!(
+        "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+        href(
+            &format!("{}/trash", trashbin_prefix()),
+            &format!("{}/{}", trashed.name, inner),
+            node.is_folder()
+        ),
+        props
+    )
+}
+
+fn multistatus(responses: String) -> Response {
+    Response::xml(
+        207,
+        format!(
+            r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns">{}</d:multistatus>"#,
+            responses
+        ),
+    )
+}
+
+fn capabilities(state: &MockState) -> Response {
+    let push = match &state.push_endpoint {
+        Some(endpoint) => format!(
+            "<notify_push><type><element>files</element></type><endpoints><websocket>{}</websocket></endpoints></notify_push>",
+            endpoint
+        ),
+        None => String::new(),
+    };
+
+    Response::xml(
+        200,
+        format!(
+            r#"<?xml version="1.0"?>
+<ocs><meta><status>ok</status><statuscode>200</statuscode><message>OK</message></meta><data><version><major>28</major></version><capabilities><dav><chunking>1.0</chunking></dav>{}</capabilities></data></ocs>"#,
+            push
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        assert_eq!(basic_auth("user", "password"), "Basic dXNlcjpwYXNzd29yZA==");
+        assert_eq!(basic_auth("a", "b"), "Basic YTpi");
+    }
+}
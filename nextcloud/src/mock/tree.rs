@@ -0,0 +1,344 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+#[derive(Debug, Clone)]
+pub enum NodeKind {
+    Folder,
+    File(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub file_id: u64,
+    pub etag: String,
+    pub last_modified: SystemTime,
+    pub permissions: String,
+    // Dead properties set with PROPPATCH, by namespace URI and name
+    pub properties: BTreeMap<(String, String), String>,
+}
+
+impl Node {
+    pub fn is_folder(&self) -> bool {
+        matches!(self.kind, NodeKind::Folder)
+    }
+
+    pub fn content(&self) -> Option<&Vec<u8>> {
+        match &self.kind {
+            NodeKind::File(content) => Some(content),
+            NodeKind::Folder => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeError {
+    NotFound,
+    // The parent folder does not exist or is a file
+    Conflict,
+    AlreadyExists,
+}
+
+// An in memory file tree, paths are relative to the user's files root without leading or
+// trailing slashes, the root itself is ""
+#[derive(Debug)]
+pub struct Tree {
+    nodes: BTreeMap<String, Node>,
+    next_file_id: u64,
+    next_etag: u64,
+}
+
+impl Default for Tree {
+    fn default() -> Self {
+        let mut tree = Self {
+            nodes: BTreeMap::new(),
+            next_file_id: 1,
+            next_etag: 1,
+        };
+        let root = tree.new_node(NodeKind::Folder);
+        tree.nodes.insert("".to_string(), root);
+        tree
+    }
+}
+
+pub fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+pub fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(index) => &path[..index],
+        None => "",
+    }
+}
+
+impl Tree {
+    fn new_node(&mut self, kind: NodeKind) -> Node {
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+
+        Node {
+            permissions: match kind {
+                NodeKind::Folder => "RGDNVCK".to_string(),
+                NodeKind::File(_) => "RGDNVW".to_string(),
+            },
+            kind,
+            file_id,
+            etag: self.new_etag(),
+            last_modified: SystemTime::now(),
+            properties: BTreeMap::new(),
+        }
+    }
+
+    fn new_etag(&mut self) -> String {
+        let etag = format!("{:013x}", self.next_etag * 0x9e3779b1);
+        self.next_etag += 1;
+        etag
+    }
+
+    // Nextcloud changes the etag of every ancestor when something inside a folder changes
+    fn touch_ancestors(&mut self, path: &str) {
+        let mut current = path.to_string();
+
+        while !current.is_empty() {
+            current = parent_of(&current).to_string();
+            let etag = self.new_etag();
+            if let Some(node) = self.nodes.get_mut(&current) {
+                node.etag = etag;
+                node.last_modified = SystemTime::now();
+            }
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        self.nodes.get(&normalize(path))
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Node> {
+        self.nodes.get_mut(&normalize(path))
+    }
+
+    fn check_parent(&self, path: &str) -> Result<(), TreeError> {
+        match self.nodes.get(parent_of(path)) {
+            Some(parent) if parent.is_folder() => Ok(()),
+            _ => Err(TreeError::Conflict),
+        }
+    }
+
+    // Direct children of a folder, sorted by path
+    pub fn children(&self, path: &str) -> Vec<(String, &Node)> {
+        let path = normalize(path);
+        let prefix = if path.is_empty() {
+            "".to_string()
+        } else {
+            format!("{}/", path)
+        };
+
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| !key.is_empty() && !key[prefix.len()..].contains('/'))
+            .map(|(key, node)| (key.clone(), node))
+            .collect()
+    }
+
+    // The node itself and everything below it
+    pub fn descendants(&self, path: &str) -> Vec<(String, &Node)> {
+        let path = normalize(path);
+        let prefix = format!("{}/", path);
+
+        self.nodes
+            .iter()
+            .filter(|(key, _)| **key == path || path.is_empty() || key.starts_with(&prefix))
+            .map(|(key, node)| (key.clone(), node))
+            .collect()
+    }
+
+    // Sum of the file sizes below path
+    pub fn size(&self, path: &str) -> u64 {
+        self.descendants(path)
+            .iter()
+            .filter_map(|(_, node)| node.content())
+            .map(|content| content.len() as u64)
+            .sum()
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<&Node, TreeError> {
+        let path = normalize(path);
+
+        if self.nodes.contains_key(&path) {
+            return Err(TreeError::AlreadyExists);
+        }
+        self.check_parent(&path)?;
+
+        let node = self.new_node(NodeKind::Folder);
+        self.nodes.insert(path.clone(), node);
+        self.touch_ancestors(&path);
+
+        Ok(&self.nodes[&path])
+    }
+
+    // Create or replace a file, returns whether it was created
+    pub fn put(
+        &mut self,
+        path: &str,
+        content: Vec<u8>,
+        last_modified: Option<SystemTime>,
+    ) -> Result<bool, TreeError> {
+        let path = normalize(path);
+        self.check_parent(&path)?;
+
+        let etag = self.new_etag();
+        let created = match self.nodes.get_mut(&path) {
+            Some(node) if node.is_folder() => return Err(TreeError::AlreadyExists),
+            Some(node) => {
+                node.kind = NodeKind::File(content);
+                node.etag = etag;
+                node.last_modified = last_modified.unwrap_or_else(SystemTime::now);
+                false
+            }
+            None => {
+                let mut node = self.new_node(NodeKind::File(content));
+                if let Some(last_modified) = last_modified {
+                    node.last_modified = last_modified;
+                }
+                self.nodes.insert(path.clone(), node);
+                true
+            }
+        };
+        self.touch_ancestors(&path);
+
+        Ok(created)
+    }
+
+    pub fn delete(&mut self, path: &str) -> Result<(), TreeError> {
+        let path = normalize(path);
+
+        if path.is_empty() {
+            return Err(TreeError::Conflict);
+        }
+        if !self.nodes.contains_key(&path) {
+            return Err(TreeError::NotFound);
+        }
+
+        let removed: Vec<String> = self
+            .descendants(&path)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        for key in removed {
+            self.nodes.remove(&key);
+        }
+        self.touch_ancestors(&path);
+
+        Ok(())
+    }
+
+    // Remove path and everything below it, returns them keyed relative to path
+    pub fn take(&mut self, path: &str) -> Result<Vec<(String, Node)>, TreeError> {
+        let path = normalize(path);
+        let nodes: Vec<(String, Node)> = self
+            .descendants(&path)
+            .into_iter()
+            .map(|(key, node)| {
+                (
+                    key[path.len()..].trim_start_matches('/').to_string(),
+                    node.clone(),
+                )
+            })
+            .collect();
+        self.delete(&path)?;
+
+        Ok(nodes)
+    }
+
+    // Put back what take returned, at path
+    pub fn insert_tree(&mut self, path: &str, nodes: Vec<(String, Node)>) -> Result<(), TreeError> {
+        let path = normalize(path);
+        if self.nodes.contains_key(&path) {
+            return Err(TreeError::AlreadyExists);
+        }
+        self.check_parent(&path)?;
+
+        for (key, node) in nodes {
+            self.nodes
+                .insert(normalize(&format!("{}/{}", path, key)), node);
+        }
+        self.touch_ancestors(&path);
+
+        Ok(())
+    }
+
+    // Copy or move source to destination, returns whether destination was created.
+    // Moves keep file ids, copies get new ones.
+    pub fn transfer(
+        &mut self,
+        source: &str,
+        destination: &str,
+        keep_source: bool,
+    ) -> Result<bool, TreeError> {
+        let source = normalize(source);
+        let destination = normalize(destination);
+
+        if !self.nodes.contains_key(&source) {
+            return Err(TreeError::NotFound);
+        }
+        if source == destination || destination.starts_with(&format!("{}/", source)) {
+            return Err(TreeError::Conflict);
+        }
+        self.check_parent(&destination)?;
+
+        let created = !self.nodes.contains_key(&destination);
+        if !created {
+            self.delete(&destination)?;
+        }
+
+        let moved: Vec<(String, Node)> = self
+            .descendants(&source)
+            .into_iter()
+            .map(|(key, node)| (key, node.clone()))
+            .collect();
+        if !keep_source {
+            self.delete(&source)?;
+        }
+
+        for (key, mut node) in moved {
+            let new_key = format!("{}{}", destination, &key[source.len()..]);
+            if keep_source {
+                node.file_id = self.next_file_id;
+                self.next_file_id += 1;
+                node.etag = self.new_etag();
+            }
+            self.nodes.insert(new_key, node);
+        }
+        self.touch_ancestors(&destination);
+
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree() {
+        let mut tree = Tree::default();
+        tree.mkdir("a").unwrap();
+        tree.put("a/b.txt", b"hello".to_vec(), None).unwrap();
+        let root_etag = tree.get("").unwrap().etag.clone();
+
+        assert!(matches!(tree.mkdir("a"), Err(TreeError::AlreadyExists)));
+        assert_eq!(tree.put("x/y", vec![], None), Err(TreeError::Conflict));
+        assert_eq!(tree.children("").len(), 1);
+        assert_eq!(tree.size("a"), 5);
+
+        let file_id = tree.get("a/b.txt").unwrap().file_id;
+        tree.transfer("a", "c", false).unwrap();
+        assert!(tree.get("a/b.txt").is_none());
+        assert_eq!(tree.get("c/b.txt").unwrap().file_id, file_id);
+        assert_ne!(tree.get("").unwrap().etag, root_etag);
+    }
+}